@@ -0,0 +1,57 @@
+/* Drives the emulator through the C API. Run ffi/smoke_test.sh to build
+ * and run it. */
+#include <stdio.h>
+#include <string.h>
+#include "chippy.h"
+
+#define CHECK(cond) do { \
+        if (!(cond)) { \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+            return 1; \
+        } \
+    } while (0)
+
+int main(void) {
+    /* LD V3, 0x2A; LD I, 0x300; LD [I], V3; LD ST, V3; DRW V0, V0, 5; then 0x0000 */
+    const uint8_t rom[] = {0x63, 0x2A, 0xA3, 0x00, 0xF3, 0x55, 0xF3, 0x18, 0xD0, 0x05};
+    uint8_t memory[4];
+    uint8_t framebuffer[CHIPPY_FRAMEBUFFER_SIZE];
+    uint8_t state[CHIPPY_STATE_SIZE];
+
+    ChippyCpu *cpu = chippy_new();
+    CHECK(cpu != NULL);
+    CHECK(chippy_load_rom(cpu, rom, sizeof(rom)));
+    CHECK(chippy_step(cpu, 100) == 5);
+    CHECK(chippy_get_pc(cpu) == 0x20A);
+    CHECK(chippy_get_reg(cpu, 3) == 0x2A);
+    CHECK(chippy_get_i(cpu) == 0x300);
+
+    CHECK(chippy_read_memory(cpu, 0x300, memory, sizeof(memory)));
+    CHECK(memory[3] == 0x2A);
+    CHECK(!chippy_read_memory(cpu, 0xFFE, memory, sizeof(memory)));
+    memory[0] = 0xAB;
+    CHECK(chippy_write_memory(cpu, 0x400, memory, 1));
+    CHECK(chippy_read_memory(cpu, 0x400, memory, 1) && memory[0] == 0xAB);
+
+    /* memory at I is 00 00 00 2A 00: only the last two bits of row 3 are lit */
+    chippy_framebuffer(cpu, framebuffer);
+    CHECK(framebuffer[3*CHIPPY_SCREEN_WIDTH + 2] == 1);
+    CHECK(framebuffer[3*CHIPPY_SCREEN_WIDTH + 1] == 0);
+
+    CHECK(chippy_sound_active(cpu));
+    CHECK(chippy_get_st(cpu) == 0x2A);
+    chippy_tick_timers(cpu);
+    CHECK(chippy_get_st(cpu) == 0x29);
+
+    chippy_set_key(cpu, 0xF, true);
+    chippy_save_state(cpu, state);
+    chippy_reset(cpu);
+    CHECK(chippy_get_reg(cpu, 3) == 0);
+    CHECK(chippy_load_state(cpu, state, sizeof(state)));
+    CHECK(chippy_get_reg(cpu, 3) == 0x2A && chippy_get_st(cpu) == 0x29);
+    CHECK(!chippy_load_state(cpu, state, sizeof(state) - 1));
+
+    chippy_free(cpu);
+    printf("ffi smoke test passed\n");
+    return 0;
+}
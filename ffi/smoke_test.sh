@@ -0,0 +1,8 @@
+#!/bin/sh
+# Builds the C API and runs ffi/smoke_test.c against it.
+set -e
+cd "$(dirname "$0")/.."
+cargo build --lib --no-default-features --features ffi
+out="${TMPDIR:-/tmp}/chippy_ffi_smoke_test"
+cc -std=c99 -Wall -Wextra -Werror -Iinclude ffi/smoke_test.c -Ltarget/debug -lchippy -o "$out"
+LD_LIBRARY_PATH=target/debug DYLD_LIBRARY_PATH=target/debug "$out"
@@ -0,0 +1,37 @@
+//! Runs arbitrary ROMs under each quirks profile. Errors are fine, panics
+//! aren't. The first byte picks the profile.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate chippy;
+
+use chippy::cpu::Cpu;
+use chippy::quirks::{Quirks, PROFILES};
+
+const MAX_INSTRUCTIONS: usize = 10000;
+
+fuzz_target!(|data: &[u8]| {
+    let (profile, rom) = match data.split_first() {
+        Some((&profile, rom)) => (profile, rom),
+        None => return,
+    };
+    let mut cpu = Cpu::new();
+    if cpu.memory.load_rom(rom).is_err() {
+        return;
+    }
+    cpu.set_quirks(Quirks::parse(PROFILES[profile as usize % PROFILES.len()]).unwrap());
+    cpu.set_seed(1);
+    for n in 0..MAX_INSTRUCTIONS {
+        // Press and release each key in turn so Fx0A and the key skips
+        // see both states
+        let key = (n / 32 % 16) as u8;
+        match n % 32 {
+            0 => cpu.keydown(key),
+            16 => cpu.keyup(key),
+            _ => (),
+        }
+        if cpu.exec_instruction().is_err() {
+            break;
+        }
+    }
+});
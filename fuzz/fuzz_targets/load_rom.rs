@@ -0,0 +1,15 @@
+//! Loads arbitrary data as a ROM, which should either fail or leave it at
+//! 0x200.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate chippy;
+
+use chippy::mem::Mem;
+
+fuzz_target!(|data: &[u8]| {
+    let mut mem = Mem::default();
+    if mem.load_rom(data).is_ok() {
+        assert_eq!(mem.get_vec(0x200, data.len()), data);
+    }
+});
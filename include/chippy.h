@@ -0,0 +1,241 @@
+#ifndef CHIPPY_H
+#define CHIPPY_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/* A Chip8 machine, created with chippy_new and freed with chippy_free. */
+typedef struct ChippyCpu ChippyCpu;
+
+#define CHIPPY_SCREEN_WIDTH 64
+
+#define CHIPPY_SCREEN_HEIGHT 32
+
+/**
+ * Size of the buffer filled by `chippy_framebuffer`.
+ */
+#define CHIPPY_FRAMEBUFFER_SIZE 2048
+
+/**
+ * Size of the buffers used by `chippy_save_state` and `chippy_load_state`.
+ */
+#define CHIPPY_STATE_SIZE 6199
+
+#define CHIPPY_MEMORY_SIZE 4096
+
+/**
+ * How often `chippy_tick_timers` should be called, and the frame rate
+ * the instructions per second are spread over.
+ */
+#define CHIPPY_FRAMES_PER_SECOND 60
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a new machine with nothing loaded.
+ */
+ChippyCpu *chippy_new(void);
+
+/**
+ * Frees a machine. Does nothing if `cpu` is null.
+ *
+ * # Safety
+ * `cpu` must be null or come from `chippy_new`, and not be used afterwards.
+ */
+void chippy_free(ChippyCpu *cpu);
+
+/**
+ * Resets the machine, clearing memory and registers.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+void chippy_reset(ChippyCpu *cpu);
+
+/**
+ * Resets the machine and loads `len` bytes of ROM at 0x200. Returns false
+ * if the ROM doesn't fit.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new` and `rom` must point to
+ * `len` readable bytes.
+ */
+bool chippy_load_rom(ChippyCpu *cpu, const uint8_t *rom, size_t len);
+
+/**
+ * Executes up to `count` instructions, stopping early if one fails.
+ * Returns how many were executed successfully. A panic in the emulator
+ * counts as a failure rather than unwinding into the caller.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint32_t chippy_step(ChippyCpu *cpu, uint32_t count);
+
+/**
+ * Counts the delay and sound timers down once, as happens at 60Hz.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+void chippy_tick_timers(ChippyCpu *cpu);
+
+/**
+ * How many instructions the machine runs a second.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint32_t chippy_get_ips(const ChippyCpu *cpu);
+
+/**
+ * Sets how many instructions the machine runs a second, clamped to the
+ * range the front ends allow.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+void chippy_set_ips(ChippyCpu *cpu, uint32_t ips);
+
+/**
+ * Presses or releases one of the 16 keys. Out of range keys are ignored.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+void chippy_set_key(ChippyCpu *cpu, uint8_t key, bool down);
+
+/**
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint16_t chippy_get_pc(const ChippyCpu *cpu);
+
+/**
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint16_t chippy_get_i(const ChippyCpu *cpu);
+
+/**
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint8_t chippy_get_sp(const ChippyCpu *cpu);
+
+/**
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint8_t chippy_get_dt(const ChippyCpu *cpu);
+
+/**
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint8_t chippy_get_st(const ChippyCpu *cpu);
+
+/**
+ * Reads register V0 to VF. Returns 0 for out of range registers.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+uint8_t chippy_get_reg(const ChippyCpu *cpu, uint8_t reg);
+
+/**
+ * Sets PC. Returns false, leaving it alone, if a whole instruction
+ * wouldn't fit at `pc`.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+bool chippy_set_pc(ChippyCpu *cpu, uint16_t pc);
+
+/**
+ * Sets I. Returns false, leaving it alone, if `i` is past the end of
+ * memory.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+bool chippy_set_i(ChippyCpu *cpu, uint16_t i);
+
+/**
+ * Sets register V0 to VF. Out of range registers are ignored.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+void chippy_set_reg(ChippyCpu *cpu, uint8_t reg, uint8_t val);
+
+/**
+ * Copies `len` bytes of memory starting at `addr` into `out`. Returns false,
+ * copying nothing, if the range runs past the end of memory.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new` and `out` must point to
+ * `len` writable bytes.
+ */
+bool chippy_read_memory(const ChippyCpu *cpu, uint16_t addr, uint8_t *out, size_t len);
+
+/**
+ * Copies `len` bytes from `data` into memory starting at `addr`. Returns
+ * false, writing nothing, if the range runs past the end of memory.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new` and `data` must point to
+ * `len` readable bytes.
+ */
+bool chippy_write_memory(ChippyCpu *cpu, uint16_t addr, const uint8_t *data, size_t len);
+
+/**
+ * Copies the screen into `out`, row by row with one byte per pixel (0 or
+ * 1). `out` must hold `CHIPPY_FRAMEBUFFER_SIZE` bytes.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new` and `out` must point to
+ * `CHIPPY_FRAMEBUFFER_SIZE` writable bytes.
+ */
+void chippy_framebuffer(const ChippyCpu *cpu, uint8_t *out);
+
+/**
+ * Whether the buzzer should be sounding.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new`.
+ */
+bool chippy_sound_active(const ChippyCpu *cpu);
+
+/**
+ * Writes the machine state to `out`, which must hold `CHIPPY_STATE_SIZE`
+ * bytes.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new` and `out` must point to
+ * `CHIPPY_STATE_SIZE` writable bytes.
+ */
+void chippy_save_state(const ChippyCpu *cpu, uint8_t *out);
+
+/**
+ * Restores a state written by `chippy_save_state`. Returns false, leaving
+ * the machine untouched, if the state is invalid.
+ *
+ * # Safety
+ * `cpu` must be a live pointer from `chippy_new` and `state` must point to
+ * `len` readable bytes.
+ */
+bool chippy_load_state(ChippyCpu *cpu, const uint8_t *state, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CHIPPY_H */
@@ -0,0 +1,296 @@
+"""Python bindings for the chippy Chip8 emulator core.
+
+These wrap the C API with ctypes, so there is nothing to compile beyond the
+library itself:
+
+    cargo build --release --lib --no-default-features --features ffi
+
+The library is looked for in $CHIPPY_LIB, then in the repository's
+target/release and target/debug directories.
+
+    import chippy
+    with chippy.Chip8() as c8:
+        c8.load_rom_file("pong.ch8")
+        c8.add_breakpoint(0x2a0)
+        c8.run(10000)
+        print(hex(c8.pc), c8.v)
+        print(chippy.render(c8.framebuffer()))
+"""
+
+import ctypes
+import os
+import sys
+
+SCREEN_WIDTH = 64
+SCREEN_HEIGHT = 32
+MEMORY_SIZE = 4096
+STATE_SIZE = 6199
+FRAMES_PER_SECOND = 60
+
+
+def _library_path():
+    if "CHIPPY_LIB" in os.environ:
+        return os.environ["CHIPPY_LIB"]
+    if sys.platform == "darwin":
+        name = "libchippy.dylib"
+    elif sys.platform == "win32":
+        name = "chippy.dll"
+    else:
+        name = "libchippy.so"
+    root = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "target")
+    for profile in ("release", "debug"):
+        path = os.path.join(root, profile, name)
+        if os.path.exists(path):
+            return path
+    raise OSError("Can't find %s, build it with `cargo build --lib --features ffi` "
+                  "or set CHIPPY_LIB" % name)
+
+
+def _load_library():
+    lib = ctypes.CDLL(_library_path())
+    cpu = ctypes.c_void_p
+    u8, u16, u32 = ctypes.c_uint8, ctypes.c_uint16, ctypes.c_uint32
+    buf = ctypes.POINTER(ctypes.c_uint8)
+    size, boolean = ctypes.c_size_t, ctypes.c_bool
+    signatures = {
+        "chippy_new": ([], cpu),
+        "chippy_free": ([cpu], None),
+        "chippy_reset": ([cpu], None),
+        "chippy_load_rom": ([cpu, buf, size], boolean),
+        "chippy_step": ([cpu, u32], u32),
+        "chippy_tick_timers": ([cpu], None),
+        "chippy_get_ips": ([cpu], u32),
+        "chippy_set_ips": ([cpu, u32], None),
+        "chippy_set_key": ([cpu, u8, boolean], None),
+        "chippy_get_pc": ([cpu], u16),
+        "chippy_get_i": ([cpu], u16),
+        "chippy_get_sp": ([cpu], u8),
+        "chippy_get_dt": ([cpu], u8),
+        "chippy_get_st": ([cpu], u8),
+        "chippy_get_reg": ([cpu, u8], u8),
+        "chippy_set_pc": ([cpu, u16], boolean),
+        "chippy_set_i": ([cpu, u16], boolean),
+        "chippy_set_reg": ([cpu, u8, u8], None),
+        "chippy_read_memory": ([cpu, u16, buf, size], boolean),
+        "chippy_write_memory": ([cpu, u16, buf, size], boolean),
+        "chippy_framebuffer": ([cpu, buf], None),
+        "chippy_sound_active": ([cpu], boolean),
+        "chippy_save_state": ([cpu, buf], None),
+        "chippy_load_state": ([cpu, buf, size], boolean),
+    }
+    for name, (args, ret) in signatures.items():
+        func = getattr(lib, name)
+        func.argtypes = args
+        func.restype = ret
+    return lib
+
+
+_lib = None
+
+
+def _library():
+    global _lib
+    if _lib is None:
+        _lib = _load_library()
+    return _lib
+
+
+def _buffer(data):
+    data = bytes(data)
+    return (ctypes.c_uint8 * len(data)).from_buffer_copy(data), len(data)
+
+
+class ChippyError(Exception):
+    pass
+
+
+class Registers(object):
+    """V0 to VF, readable and writable by index."""
+
+    def __init__(self, chip8):
+        self._chip8 = chip8
+
+    def __len__(self):
+        return 16
+
+    def __getitem__(self, reg):
+        if not 0 <= reg < 16:
+            raise IndexError("register V%d out of range" % reg)
+        return _library().chippy_get_reg(self._chip8._cpu, reg)
+
+    def __setitem__(self, reg, value):
+        if not 0 <= reg < 16:
+            raise IndexError("register V%d out of range" % reg)
+        _library().chippy_set_reg(self._chip8._cpu, reg, value)
+
+    def __iter__(self):
+        return (self[reg] for reg in range(16))
+
+    def __repr__(self):
+        return repr(list(self))
+
+
+class Chip8(object):
+    """A Chip8 machine.
+
+    Breakpoints are addresses; `run` stops before executing the instruction
+    at one of them.
+    """
+
+    def __init__(self):
+        self._lib = _library()
+        self._cpu = self._lib.chippy_new()
+        self.breakpoints = set()
+        self.v = Registers(self)
+        self._frames = 0
+
+    def close(self):
+        if self._cpu is not None:
+            self._lib.chippy_free(self._cpu)
+            self._cpu = None
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+    def reset(self):
+        self._lib.chippy_reset(self._cpu)
+
+    def load_rom(self, data):
+        """Resets the machine and loads ROM bytes at 0x200."""
+        rom, length = _buffer(data)
+        if not self._lib.chippy_load_rom(self._cpu, rom, length):
+            raise ChippyError("ROM is too large (%d bytes)" % length)
+
+    def load_rom_file(self, path):
+        with open(path, "rb") as f:
+            self.load_rom(f.read())
+
+    def step(self, count=1):
+        """Executes `count` instructions, ignoring breakpoints."""
+        done = self._lib.chippy_step(self._cpu, count)
+        if done != count:
+            raise ChippyError("Bad instruction at 0x%03x" % self.pc)
+
+    def add_breakpoint(self, addr):
+        self.breakpoints.add(addr)
+
+    def remove_breakpoint(self, addr):
+        self.breakpoints.discard(addr)
+
+    def run(self, max_steps):
+        """Executes up to `max_steps` instructions, stopping at a breakpoint.
+
+        Always executes at least one instruction, so that calling `run` again
+        continues past the breakpoint it stopped at. Returns the breakpoint
+        address, or None if the step limit was reached.
+        """
+        if not self.breakpoints:
+            self.step(max_steps)
+            return None
+        for i in range(max_steps):
+            if i > 0 and self.pc in self.breakpoints:
+                return self.pc
+            self.step()
+        return None
+
+    def run_frame(self):
+        """Runs one 60th of a second at the machine's speed, then ticks the
+        timers. The number of instructions is rounded differently from
+        frame to frame so that they add up to `ips` a second.
+        """
+        ips, frame = self.ips, self._frames % FRAMES_PER_SECOND
+        self.step((frame + 1) * ips // FRAMES_PER_SECOND - frame * ips // FRAMES_PER_SECOND)
+        self._frames += 1
+        self.tick_timers()
+
+    @property
+    def ips(self):
+        """Instructions run a second, 400 by default."""
+        return self._lib.chippy_get_ips(self._cpu)
+
+    @ips.setter
+    def ips(self, value):
+        self._lib.chippy_set_ips(self._cpu, value)
+
+    def tick_timers(self):
+        self._lib.chippy_tick_timers(self._cpu)
+
+    def set_key(self, key, down):
+        self._lib.chippy_set_key(self._cpu, key, down)
+
+    @property
+    def pc(self):
+        return self._lib.chippy_get_pc(self._cpu)
+
+    @pc.setter
+    def pc(self, value):
+        if not self._lib.chippy_set_pc(self._cpu, value):
+            raise ChippyError("PC 0x%03x is out of range" % value)
+
+    @property
+    def i(self):
+        return self._lib.chippy_get_i(self._cpu)
+
+    @i.setter
+    def i(self, value):
+        if not self._lib.chippy_set_i(self._cpu, value):
+            raise ChippyError("I 0x%03x is out of range" % value)
+
+    @property
+    def sp(self):
+        return self._lib.chippy_get_sp(self._cpu)
+
+    @property
+    def dt(self):
+        return self._lib.chippy_get_dt(self._cpu)
+
+    @property
+    def st(self):
+        return self._lib.chippy_get_st(self._cpu)
+
+    @property
+    def sound_active(self):
+        return self._lib.chippy_sound_active(self._cpu)
+
+    def peek(self, addr, length=1):
+        """Reads `length` bytes of memory starting at `addr`."""
+        out = (ctypes.c_uint8 * length)()
+        if not self._lib.chippy_read_memory(self._cpu, addr, out, length):
+            raise ChippyError("Read of %d bytes at 0x%03x is out of range" % (length, addr))
+        return bytes(out)
+
+    def poke(self, addr, data):
+        """Writes bytes (or a single int) to memory starting at `addr`."""
+        if isinstance(data, int):
+            data = [data]
+        buf, length = _buffer(data)
+        if not self._lib.chippy_write_memory(self._cpu, addr, buf, length):
+            raise ChippyError("Write of %d bytes at 0x%03x is out of range" % (length, addr))
+
+    def framebuffer(self):
+        """Returns the screen as SCREEN_HEIGHT rows of SCREEN_WIDTH 0/1 ints,
+        so `fb[y][x]` is a pixel. `numpy.array(fb)` turns it into an array."""
+        out = (ctypes.c_uint8 * (SCREEN_WIDTH*SCREEN_HEIGHT))()
+        self._lib.chippy_framebuffer(self._cpu, out)
+        return [list(out[y*SCREEN_WIDTH:(y+1)*SCREEN_WIDTH]) for y in range(SCREEN_HEIGHT)]
+
+    def save_state(self):
+        out = (ctypes.c_uint8 * STATE_SIZE)()
+        self._lib.chippy_save_state(self._cpu, out)
+        return bytes(out)
+
+    def load_state(self, state):
+        buf, length = _buffer(state)
+        if not self._lib.chippy_load_state(self._cpu, buf, length):
+            raise ChippyError("Invalid save state")
+
+
+def render(framebuffer):
+    """Draws a framebuffer as text, for quick looks from a notebook."""
+    return "\n".join("".join("#" if p else "." for p in row) for row in framebuffer)
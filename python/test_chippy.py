@@ -0,0 +1,88 @@
+"""Tests for the Python bindings. Build the library with the ffi feature,
+then run `python3 -m unittest discover python`."""
+
+import unittest
+
+import chippy
+
+# LD V3, 0x2A; LD I, 0x300; LD [I], V3; DRW V0, V0, 5; JP 0x208
+ROM = bytes([0x63, 0x2A, 0xA3, 0x00, 0xF3, 0x55, 0xD0, 0x05, 0x12, 0x08])
+
+
+class Chip8Test(unittest.TestCase):
+    def setUp(self):
+        self.c8 = chippy.Chip8()
+        self.c8.load_rom(ROM)
+
+    def tearDown(self):
+        self.c8.close()
+
+    def test_step_and_registers(self):
+        self.c8.step(3)
+        self.assertEqual(self.c8.pc, 0x206)
+        self.assertEqual(self.c8.i, 0x300)
+        self.assertEqual(self.c8.v[3], 0x2A)
+        self.c8.v[3] = 7
+        self.c8.i = 0x123
+        self.assertEqual(list(self.c8.v)[3], 7)
+        self.assertEqual(self.c8.i, 0x123)
+        with self.assertRaises(IndexError):
+            self.c8.v[16]
+        with self.assertRaises(chippy.ChippyError):
+            self.c8.i = 0x1000
+        with self.assertRaises(chippy.ChippyError):
+            self.c8.pc = 0xFFF
+
+    def test_memory(self):
+        self.c8.step(3)
+        self.assertEqual(self.c8.peek(0x300, 4), b"\x00\x00\x00\x2a")
+        self.c8.poke(0x400, b"\x01\x02")
+        self.c8.poke(0x402, 3)
+        self.assertEqual(self.c8.peek(0x400, 3), b"\x01\x02\x03")
+        with self.assertRaises(chippy.ChippyError):
+            self.c8.peek(0xFFF, 2)
+
+    def test_breakpoints(self):
+        self.c8.add_breakpoint(0x206)
+        self.assertEqual(self.c8.run(100), 0x206)
+        self.assertEqual(self.c8.pc, 0x206)
+        self.c8.add_breakpoint(0x208)
+        self.assertEqual(self.c8.run(100), 0x208)
+        self.c8.remove_breakpoint(0x208)
+        self.c8.remove_breakpoint(0x206)
+        self.assertIsNone(self.c8.run(10))
+
+    def test_framebuffer(self):
+        self.c8.step(4)
+        fb = self.c8.framebuffer()
+        self.assertEqual(len(fb), chippy.SCREEN_HEIGHT)
+        self.assertEqual(len(fb[0]), chippy.SCREEN_WIDTH)
+        self.assertEqual(fb[3][:8], [0, 0, 1, 0, 1, 0, 1, 0])
+        self.assertIn("..#.#.#.", chippy.render(fb))
+
+    def test_save_state(self):
+        self.c8.step(3)
+        state = self.c8.save_state()
+        self.c8.reset()
+        self.assertEqual(self.c8.v[3], 0)
+        self.c8.load_state(state)
+        self.assertEqual(self.c8.v[3], 0x2A)
+        with self.assertRaises(chippy.ChippyError):
+            self.c8.load_state(state[:-1])
+
+    def test_run_frame(self):
+        # ADD V0, 1 over and over counts the instructions run
+        self.c8.load_rom(bytes([0x70, 0x01]) * 100)
+        self.assertEqual(self.c8.ips, 400)
+        self.c8.ips = 150
+        self.c8.run_frame()
+        self.c8.run_frame()
+        self.assertEqual(self.c8.v[0], 5)
+
+    def test_bad_rom(self):
+        with self.assertRaises(chippy.ChippyError):
+            self.c8.load_rom(bytes(chippy.MEMORY_SIZE))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,52 @@
+pub const TONE_FREQUENCY: f32 = 440.0;
+const VOLUME: f32 = 0.25;
+
+/// Generates the square wave played while the sound timer is active.
+pub struct SquareWave {
+    phase: f32,
+    phase_inc: f32,
+    volume: f32,
+}
+
+impl SquareWave {
+    pub fn new(sample_rate: i32) -> SquareWave {
+        SquareWave {
+            phase: 0.0,
+            phase_inc: TONE_FREQUENCY/(sample_rate as f32),
+            volume: VOLUME,
+        }
+    }
+
+    /// Returns the next sample, from -1.0 to 1.0.
+    pub fn next_sample(&mut self) -> f32 {
+        let sample = if self.phase < 0.5 { self.volume } else { -self.volume };
+        self.phase = (self.phase + self.phase_inc) % 1.0;
+        sample
+    }
+
+    /// Fills `out` with the tone, or silence if it is off.
+    pub fn fill(&mut self, out: &mut [f32], on: bool) {
+        for sample in out.iter_mut() {
+            *sample = if on { self.next_sample() } else { 0.0 };
+        }
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_square_wave(){
+    let mut wave = SquareWave::new(1760);
+    let mut out = [0f32; 8];
+    wave.fill(&mut out, true);
+    // 1760Hz / 440Hz gives 4 samples per period, at phases 0, 1/4, 1/2 and 3/4
+    let high = out.iter().filter(|&&s| s > 0.0).count();
+    let low = out.iter().filter(|&&s| s < 0.0).count();
+    if high != 4 || low != 4 {
+        panic!("Unexpected square wave shape: {} high, {} low samples", high, low);
+    }
+    wave.fill(&mut out, false);
+    if out.iter().any(|&s| s != 0.0) {
+        panic!("Tone should be silent when off");
+    }
+}
@@ -0,0 +1,403 @@
+//! RAM search and freeze codes. Searches narrow down a set of candidate
+//! addresses by comparing memory against a value or against the previous
+//! snapshot; frozen addresses are written back every frame.
+//!
+//! Freezes are saved per ROM, keyed by its SHA-1, in
+//! `<config dir>/cheats/<sha1>.txt`. Each line is a hex address and value,
+//! optionally followed by a `#` comment:
+//!
+//! ```text
+//! 3f1 03  # infinite lives
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use config;
+use cpu::Cpu;
+use frontend::Hook;
+use mem::{Mem, MEM_SIZE};
+
+/// How to narrow down a search.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Equal(u8),
+    Greater(u8),
+    Less(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Condition {
+    /// Parses `eq N`, `gt N`, `lt N`, `changed`, `unchanged`, `inc` or `dec`.
+    /// Values are decimal, or hex with a `0x` prefix.
+    pub fn parse(words: &[&str]) -> Result<Condition, String> {
+        let value = || match words.get(1) {
+            Some(word) => parse_number(word, 0xff).map(|v| v as u8),
+            None => Err(format!("\"{}\" needs a value", words[0])),
+        };
+        match words.first() {
+            Some(&"eq") => Ok(Condition::Equal(value()?)),
+            Some(&"gt") => Ok(Condition::Greater(value()?)),
+            Some(&"lt") => Ok(Condition::Less(value()?)),
+            Some(&"changed") => Ok(Condition::Changed),
+            Some(&"unchanged") => Ok(Condition::Unchanged),
+            Some(&"inc") => Ok(Condition::Increased),
+            Some(&"dec") => Ok(Condition::Decreased),
+            _ => Err("Expected eq, gt, lt, changed, unchanged, inc or dec".to_string()),
+        }
+    }
+
+    fn matches(&self, old: u8, new: u8) -> bool {
+        match *self {
+            Condition::Equal(v) => new == v,
+            Condition::Greater(v) => new > v,
+            Condition::Less(v) => new < v,
+            Condition::Changed => new != old,
+            Condition::Unchanged => new == old,
+            Condition::Increased => new > old,
+            Condition::Decreased => new < old,
+        }
+    }
+}
+
+fn parse_number(word: &str, max: u32) -> Result<u32, String> {
+    let parsed = if let Some(hex) = word.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else {
+        word.parse()
+    };
+    match parsed {
+        Ok(v) if v <= max => Ok(v),
+        _ => Err(format!("Invalid value \"{}\", expected 0 to {}", word, max)),
+    }
+}
+
+/// A RAM search in progress.
+pub struct Search {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl Search {
+    /// Starts a search with every address as a candidate.
+    pub fn new(mem: &Mem) -> Search {
+        Search {
+            snapshot: mem.get_vec(0, MEM_SIZE),
+            candidates: (0..MEM_SIZE as u16).collect(),
+        }
+    }
+
+    /// Keeps the candidates matching `condition`, then takes a new snapshot
+    /// for the next comparison.
+    pub fn filter(&mut self, mem: &Mem, condition: Condition) {
+        let current = mem.get_vec(0, MEM_SIZE);
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| condition.matches(snapshot[addr as usize], current[addr as usize]));
+        self.snapshot = current;
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+/// Addresses held at fixed values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Freezes {
+    values: BTreeMap<u16, (u8, String)>,
+}
+
+impl Freezes {
+    pub fn parse(contents: &str) -> Result<Freezes, String> {
+        let mut freezes: Freezes = Default::default();
+        for (line_no, line) in contents.lines().enumerate() {
+            let mut parts = line.splitn(2, '#');
+            let code = parts.next().unwrap().trim();
+            let comment = parts.next().unwrap_or("").trim();
+            if code.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = code.split_whitespace().collect();
+            let parse_hex = |word: &str, max: u32| match u32::from_str_radix(word, 16) {
+                Ok(v) if v <= max => Ok(v),
+                _ => Err(format!("line {}: invalid hex number \"{}\"", line_no + 1, word)),
+            };
+            if words.len() != 2 {
+                return Err(format!("line {}: expected \"address value\"", line_no + 1));
+            }
+            let addr = parse_hex(words[0], MEM_SIZE as u32 - 1)?;
+            let value = parse_hex(words[1], 0xff)?;
+            freezes.set(addr as u16, value as u8, comment);
+        }
+        Ok(freezes)
+    }
+
+    pub fn set(&mut self, addr: u16, value: u8, comment: &str) {
+        self.values.insert(addr, (value, comment.to_string()));
+    }
+
+    pub fn remove(&mut self, addr: u16) -> bool {
+        self.values.remove(&addr).is_some()
+    }
+
+    /// Writes every frozen value that has changed back into memory. The
+    /// writes aren't the program's, so they stay out of the journal and
+    /// coverage, and pages are only marked dirty when something changed.
+    pub fn apply(&self, mem: &mut Mem) {
+        for (&addr, &(value, _)) in self.values.iter() {
+            if mem.read_u8(addr as usize) != value {
+                mem.poke(addr as usize, value);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl ::std::fmt::Display for Freezes {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        for (addr, &(value, ref comment)) in self.values.iter() {
+            if comment.is_empty() {
+                writeln!(f, "{:03x} {:02x}", addr, value)?;
+            } else {
+                writeln!(f, "{:03x} {:02x}  # {}", addr, value, comment)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the cheats for a ROM with this hash are kept.
+pub fn cheat_path(rom_hash: &str) -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("cheats").join(format!("{}.txt", rom_hash)))
+}
+
+/// Loads the saved cheats for a ROM, or none if there aren't any.
+pub fn load_freezes(rom_hash: &str) -> Result<Freezes, String> {
+    let path = match cheat_path(rom_hash) {
+        Some(path) => path,
+        None => return Ok(Default::default()),
+    };
+    let mut contents = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => Freezes::parse(&contents).map_err(|e| format!("Error in {}: {}", path.display(), e)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(e) => Err(format!("Error reading {}: {}", path.display(), e)),
+    }
+}
+
+pub fn save_freezes(rom_hash: &str, freezes: &Freezes) -> Result<PathBuf, String> {
+    let path = match cheat_path(rom_hash) {
+        Some(path) => path,
+        None => return Err("No config directory to save cheats in".to_string()),
+    };
+    fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| File::create(&path))
+        .and_then(|mut f| f.write_all(freezes.to_string().as_bytes()))
+        .map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Most candidates shown by `list`.
+const LIST_LIMIT: usize = 20;
+
+const HELP: &str = "\
+search eq|gt|lt N       keep addresses whose value is equal to/greater than/less than N
+search changed|unchanged|inc|dec
+                        keep addresses that changed/didn't change/went up/went down
+                        since the last search
+search reset            start again with every address
+list                    show the remaining candidates
+freeze ADDR VALUE [# comment]
+unfreeze ADDR
+freezes                 show frozen addresses
+save                    save frozen addresses for this rom";
+
+/// Runs the cheat console on stdin and applies freezes every frame.
+/// Commands are read on a background thread and run between frames.
+pub struct CheatHook {
+    rom_hash: String,
+    freezes: Freezes,
+    search: Option<Search>,
+    commands: Receiver<String>,
+}
+
+impl CheatHook {
+    pub fn new(rom_hash: &str) -> Result<CheatHook, String> {
+        let freezes = load_freezes(rom_hash)?;
+        if !freezes.is_empty() {
+            print!("Loaded cheats:\n{}", freezes);
+        }
+        let (sender, commands) = channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => if sender.send(line).is_err() { break },
+                    Err(_) => break,
+                }
+            }
+        });
+        println!("Cheat console ready, type \"help\" for commands");
+        Ok(CheatHook {
+            rom_hash: rom_hash.to_string(),
+            freezes,
+            search: None,
+            commands,
+        })
+    }
+
+    /// Runs one console command, returning what to print.
+    pub fn run_command(&mut self, cpu: &mut Cpu, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+        match words.first() {
+            None => Ok(String::new()),
+            Some(&"help") => Ok(HELP.to_string()),
+            Some(&"search") if words.get(1) == Some(&"reset") => {
+                self.search = None;
+                Ok("Search reset".to_string())
+            }
+            Some(&"search") => {
+                let condition = Condition::parse(&words[1..])?;
+                let search = self.search.get_or_insert_with(|| Search::new(&cpu.memory));
+                search.filter(&cpu.memory, condition);
+                Ok(format!("{} candidates", search.candidates().len()))
+            }
+            Some(&"list") => {
+                let search = match self.search {
+                    Some(ref search) => search,
+                    None => return Err("No search in progress".to_string()),
+                };
+                let values = cpu.memory.get_vec(0, MEM_SIZE);
+                let mut out: Vec<String> = search.candidates().iter().take(LIST_LIMIT)
+                    .map(|&addr| format!("{:03x}: {:02x} ({})", addr, values[addr as usize], values[addr as usize]))
+                    .collect();
+                if search.candidates().len() > LIST_LIMIT {
+                    out.push(format!("... and {} more", search.candidates().len() - LIST_LIMIT));
+                }
+                Ok(out.join("\n"))
+            }
+            Some(&"freeze") if words.len() >= 3 => {
+                let addr = parse_number(words[1], MEM_SIZE as u32 - 1)? as u16;
+                let value = parse_number(words[2], 0xff)? as u8;
+                let comment = line.split_once('#').map(|(_, comment)| comment.trim()).unwrap_or("");
+                self.freezes.set(addr, value, comment);
+                Ok(format!("Froze {:03x} at {:02x}", addr, value))
+            }
+            Some(&"unfreeze") if words.len() >= 2 => {
+                let addr = parse_number(words[1], MEM_SIZE as u32 - 1)? as u16;
+                if self.freezes.remove(addr) {
+                    Ok(format!("Unfroze {:03x}", addr))
+                } else {
+                    Err(format!("{:03x} isn't frozen", addr))
+                }
+            }
+            Some(&"freezes") => Ok(self.freezes.to_string().trim_end().to_string()),
+            Some(&"save") => save_freezes(&self.rom_hash, &self.freezes)
+                .map(|path| format!("Saved cheats to {}", path.display())),
+            Some(_) => Err(format!("Unknown command \"{}\", type \"help\" for commands", line.trim())),
+        }
+    }
+}
+
+impl Hook for CheatHook {
+    fn end_frame(&mut self, cpu: &mut Cpu) {
+        while let Ok(line) = self.commands.try_recv() {
+            match self.run_command(cpu, &line) {
+                Ok(ref out) if out.is_empty() => (),
+                Ok(out) => println!("{}", out),
+                Err(e) => println!("{}", e),
+            }
+        }
+        self.freezes.apply(&mut cpu.memory);
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_search(){
+    let mut mem: Mem = Default::default();
+    mem.write_u8(0x300, 5);
+    mem.write_u8(0x301, 5);
+    let mut search = Search::new(&mem);
+    search.filter(&mem, Condition::Equal(5));
+    if search.candidates() != [0x300, 0x301] {
+        panic!("Equal search found {:?}", search.candidates());
+    }
+    mem.write_u8(0x300, 4);
+    search.filter(&mem, Condition::Decreased);
+    if search.candidates() != [0x300] {
+        panic!("Decreased search found {:?}", search.candidates());
+    }
+    search.filter(&mem, Condition::Unchanged);
+    if search.candidates() != [0x300] {
+        panic!("Unchanged search found {:?}", search.candidates());
+    }
+    search.filter(&mem, Condition::Greater(4));
+    if !search.candidates().is_empty() {
+        panic!("Greater search found {:?}", search.candidates());
+    }
+}
+
+#[test]
+fn test_freezes(){
+    let freezes = Freezes::parse("# lives\n3f1 03  # infinite lives\n\n200 ff\n").unwrap();
+    if freezes.to_string() != "200 ff\n3f1 03  # infinite lives\n" {
+        panic!("Freezes didn't round trip: {:?}", freezes.to_string());
+    }
+    let mut mem: Mem = Default::default();
+    mem.set_coverage(true);
+    mem.take_dirty_pages();
+    freezes.apply(&mut mem);
+    if mem.read_u8(0x3f1) != 3 || mem.read_u8(0x200) != 0xff {
+        panic!("Freezes not applied");
+    }
+    if mem.coverage().unwrap().iter().any(|&flags| flags & ::mem::COVER_WRITE != 0) {
+        panic!("Freezes shouldn't count as program writes");
+    }
+    mem.take_dirty_pages();
+    freezes.apply(&mut mem);
+    if mem.take_dirty_pages() != 0 {
+        panic!("Reapplying unchanged freezes shouldn't dirty any pages");
+    }
+    for bad in ["1000 00", "3f1", "3f1 100", "zz 00"].iter() {
+        if Freezes::parse(bad).is_ok() {
+            panic!("\"{}\" should be rejected", bad);
+        }
+    }
+}
+
+#[test]
+fn test_cheat_commands(){
+    let (_sender, commands) = channel();
+    let mut hook = CheatHook {
+        rom_hash: String::new(),
+        freezes: Default::default(),
+        search: None,
+        commands,
+    };
+    let mut cpu = Cpu::new();
+    cpu.memory.write_u8(0x3f1, 9);
+    if hook.run_command(&mut cpu, "search eq 9").is_err() || hook.run_command(&mut cpu, "list").unwrap().find("3f1: 09").is_none() {
+        panic!("Search should find 0x3f1");
+    }
+    hook.run_command(&mut cpu, "freeze 0x3f1 3 # lives").unwrap();
+    hook.end_frame(&mut cpu);
+    if cpu.memory.read_u8(0x3f1) != 3 {
+        panic!("Freeze not applied at the end of the frame");
+    }
+    if hook.run_command(&mut cpu, "freeze 0x1000 3").is_ok() || hook.run_command(&mut cpu, "bogus").is_ok() {
+        panic!("Bad commands should be rejected");
+    }
+}
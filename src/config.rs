@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Settings read from the user's config file.
+///
+/// The file is a list of `key = value` lines; blank lines and lines starting
+/// with `#` are ignored. Command line options take priority over anything
+/// set here.
+#[derive(Debug, Default)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    /// Location of the config file, `$XDG_CONFIG_HOME/chippy/chippy.conf`
+    /// falling back to `~/.config/chippy/chippy.conf`.
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("chippy.conf"))
+    }
+
+    /// Loads the config file, returning an empty config if it doesn't exist.
+    pub fn load() -> Config {
+        let path = match Config::default_path() {
+            Some(path) => path,
+            None => return Default::default(),
+        };
+        let mut contents = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Default::default(),
+            Err(e) => {
+                println!("Error reading config file {}: {}", path.display(), e);
+                return Default::default();
+            }
+        }
+        match Config::parse(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Error in config file {}: {}", path.display(), e);
+                Default::default()
+            }
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let mut values = HashMap::new();
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(format!("line {}: expected \"key = value\"", line_no + 1)),
+            };
+            values.insert(key.to_string(), value.to_string());
+        }
+        Ok(Config { values })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+}
+
+/// Directory that chippy keeps its per user files in.
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(dir).join("chippy"));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("chippy"))
+}
+
+//TESTS
+
+#[test]
+fn test_parse_config(){
+    let config = Config::parse("# comment\n\npalette = #000000,#ffffff\nfreq=500\n").unwrap();
+    if config.get("palette") != Some("#000000,#ffffff") || config.get("freq") != Some("500") {
+        panic!("Config parsed incorrectly: {:?}", config);
+    }
+    if config.get("missing").is_some() {
+        panic!("Unset key should not have a value");
+    }
+    if Config::parse("palette amber").is_ok() {
+        panic!("Line without '=' should be rejected");
+    }
+}
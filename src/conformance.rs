@@ -0,0 +1,227 @@
+//! Runs well known test ROMs headless and checks the screen each one ends
+//! on against a golden image, once for every quirks profile.
+//!
+//! The ROMs are looked for by file name in a directory; only chippy's own
+//! is distributed with it. Golden images are the text framebuffers written
+//! by `headless::framebuffer_text`, stored as `<test>-<profile>.txt` so
+//! that differences show up clearly in a diff.
+
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::Path;
+use cpu::Cpu;
+use engine::Interpreter;
+use frontend;
+use frontend::{Frontend, NullAudio};
+use headless;
+use mem::Mem;
+use quirks::Quirks;
+
+const FRAMES: u64 = 300;
+const INSTRUCTIONS_PER_SECOND: u64 = 1000;
+/// The Timendus test ROMs read this byte to pick a test without showing
+/// their menu.
+const SELECT_ADDRESS: usize = 0x1FF;
+const SEED: u32 = 1;
+
+/// A test ROM and how to run it.
+pub struct TestRom {
+    pub name: &'static str,
+    pub file: &'static str,
+    /// Value put at `SELECT_ADDRESS` before running, by profile, with `*`
+    /// for any profile not listed.
+    pub select: &'static [(&'static str, u8)],
+    /// Keys held down while running.
+    pub keys: &'static [u8],
+    /// Whether the ROM is committed to `test-suite/roms`, so that it being
+    /// missing is a failure rather than a skip.
+    pub bundled: bool,
+}
+
+impl TestRom {
+    /// The menu entry to pick when running under `profile`.
+    pub fn select_for(&self, profile: &str) -> Option<u8> {
+        self.select.iter().find(|&&(name, _)| name == profile || name == "*").map(|&(_, select)| select)
+    }
+}
+
+// The Timendus ROMs are GPL-3.0, which can't be distributed under chippy's
+// GPL-2.0, and BC_test's licence is unknown. corax89's is MIT but hasn't
+// been added yet.
+pub const TEST_ROMS: [TestRom; 7] = [
+    TestRom { name: "opcode", file: "test_opcode.ch8", select: &[], keys: &[], bundled: false },
+    TestRom { name: "bc", file: "BC_test.ch8", select: &[], keys: &[], bundled: false },
+    TestRom { name: "corax", file: "3-corax+.ch8", select: &[], keys: &[], bundled: false },
+    TestRom { name: "flags", file: "4-flags.ch8", select: &[], keys: &[], bundled: false },
+    // The menu offers CHIP-8, SUPER-CHIP and XO-CHIP; chippy's own quirks
+    // are compared against CHIP-8's
+    TestRom { name: "quirks", file: "5-quirks.ch8", select: &[("schip", 2), ("xochip", 3), ("*", 1)],
+              keys: &[], bundled: false },
+    TestRom { name: "keypad", file: "6-keypad.ch8", select: &[("*", 1)], keys: &[5], bundled: false },
+    TestRom { name: "chippy", file: "chippy_test.ch8", select: &[], keys: &[], bundled: true },
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    /// The screen differed from the golden image, with the differences.
+    Fail(String),
+    /// The golden image was written.
+    Blessed,
+    /// There's no golden image to check against, which counts as a failure.
+    NoGolden,
+    /// The ROM wasn't found, which counts as a failure if it's bundled.
+    NoRom,
+}
+
+/// Runs a ROM headless under a quirks profile, returning the screen it ends
+/// on.
+pub fn run_rom(rom: &[u8], test: &TestRom, profile: &str) -> Result<String, String> {
+    Mem::check_rom_size(rom)?;
+    let mut cpu = Cpu::new();
+    cpu.set_ips(INSTRUCTIONS_PER_SECOND);
+    cpu.set_quirks(Quirks::parse(profile)?);
+    cpu.set_seed(SEED);
+    let mut frontend = Frontend {
+        video: headless::HeadlessVideo::new(),
+        audio: NullAudio,
+        input: headless::HeadlessInput::new(Some(FRAMES)),
+        clock: headless::VirtualClock::new(),
+    };
+    let load = |cpu: &mut Cpu| {
+        cpu.memory.load_rom(rom).unwrap();
+        if let Some(select) = test.select_for(profile) {
+            cpu.memory.raw_mut()[SELECT_ADDRESS] = select;
+        }
+        for &key in test.keys {
+            cpu.keydown(key);
+        }
+    };
+    frontend::run(&mut frontend, &mut cpu, load, &mut [], &mut Interpreter);
+    Ok(headless::framebuffer_text(&cpu))
+}
+
+/// The rows that differ between two screens.
+fn diff(expected: &str, actual: &str) -> String {
+    let mut out = String::new();
+    for (row, (e, a)) in expected.lines().zip(actual.lines()).enumerate() {
+        if e != a {
+            let _ = writeln!(out, "  row {:2} expected {}\n         got      {}", row, e, a);
+        }
+    }
+    if expected.lines().count() != actual.lines().count() {
+        let _ = writeln!(out, "  expected {} rows, got {}", expected.lines().count(), actual.lines().count());
+    }
+    out
+}
+
+/// Runs one test with one profile, writing the golden image instead of
+/// checking against it if `bless` is set.
+pub fn check(test: &TestRom, profile: &str, roms: &Path, golden: &Path, bless: bool) -> Result<Outcome, String> {
+    Quirks::parse(profile)?;
+    let rom = match fs::read(roms.join(test.file)) {
+        Ok(rom) => rom,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Outcome::NoRom),
+        Err(e) => return Err(format!("Error reading {}: {}", test.file, e)),
+    };
+    let screen = run_rom(&rom, test, profile).map_err(|e| format!("Error loading {}: {}", test.file, e))?;
+    let golden_file = golden.join(format!("{}-{}.txt", test.name, profile));
+    if bless {
+        return fs::create_dir_all(golden)
+            .and_then(|_| fs::write(&golden_file, screen))
+            .map(|_| Outcome::Blessed)
+            .map_err(|e| format!("Error writing {}: {}", golden_file.display(), e));
+    }
+    match fs::read_to_string(&golden_file) {
+        Ok(ref expected) if *expected == screen => Ok(Outcome::Pass),
+        Ok(expected) => Ok(Outcome::Fail(diff(&expected, &screen))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Outcome::NoGolden),
+        Err(e) => Err(format!("Error reading {}: {}", golden_file.display(), e)),
+    }
+}
+
+/// Runs every test with each profile, returning a report and whether
+/// anything failed. Missing ROMs are skipped unless they're bundled, missing
+/// golden images fail.
+pub fn run_suite(roms: &Path, golden: &Path, profiles: &[&str], bless: bool) -> Result<(String, bool), String> {
+    let mut report = String::new();
+    let mut failed = false;
+    for test in TEST_ROMS.iter() {
+        for &profile in profiles {
+            let outcome = check(test, profile, roms, golden, bless)?;
+            let status = match outcome {
+                Outcome::Pass => "ok",
+                Outcome::Fail(_) => "FAILED",
+                Outcome::Blessed => "blessed",
+                Outcome::NoGolden => "no golden image",
+                Outcome::NoRom if test.bundled => "FAILED, bundled ROM not found",
+                Outcome::NoRom => "skipped, ROM not found",
+            };
+            let _ = writeln!(report, "{:<8} {:<8} {}", test.name, profile, status);
+            match outcome {
+                Outcome::Fail(ref differences) => {
+                    report.push_str(differences);
+                    failed = true;
+                }
+                Outcome::NoGolden => failed = true,
+                Outcome::NoRom if test.bundled => failed = true,
+                _ => (),
+            }
+        }
+    }
+    Ok((report, failed))
+}
+
+//TESTS
+
+#[test]
+fn test_conformance(){
+    // LD V0, 0xF0; LD I, 0x300; LD [I], V0; LD V1, 0; DRW V1, V1, 1; JP 0x20A
+    // draws from 0x300 by default, or the empty 0x301 when I is incremented
+    let rom = [0x60, 0xF0, 0xA3, 0x00, 0xF0, 0x55, 0x61, 0x00, 0xD1, 0x11, 0x12, 0x0A];
+    let dir = ::std::env::temp_dir().join("chippy_test_conformance");
+    let golden = dir.join("golden");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("test_opcode.ch8"), &rom[..]).unwrap();
+    let test = &TEST_ROMS[0];
+    let run = |profile: &str, bless: bool| check(test, profile, &dir, &golden, bless).unwrap();
+
+    if run("chippy", false) != Outcome::NoGolden || run("chippy", true) != Outcome::Blessed ||
+        run("chippy", false) != Outcome::Pass {
+        panic!("Blessing a golden image didn't make the test pass");
+    }
+    let screen = fs::read_to_string(golden.join("opcode-chippy.txt")).unwrap();
+    if !screen.starts_with("####....") {
+        panic!("Unexpected screen:\n{}", screen);
+    }
+    fs::copy(golden.join("opcode-chippy.txt"), golden.join("opcode-cosmac.txt")).unwrap();
+    match run("cosmac", false) {
+        Outcome::Fail(ref differences) if differences.starts_with("  row  0 expected ####....") => (),
+        outcome => panic!("Expected the cosmac profile to differ on row 0, got {:?}", outcome),
+    }
+    if check(&TEST_ROMS[1], "chippy", &dir, &golden, false).unwrap() != Outcome::NoRom {
+        panic!("Expected a missing ROM to be skipped");
+    }
+    let (report, failed) = run_suite(&dir.join("missing"), &golden, &["chippy"], false).unwrap();
+    if !failed || !report.contains("chippy   chippy   FAILED, bundled ROM not found") {
+        panic!("Expected a missing bundled ROM to fail:\n{}", report);
+    }
+    let quirks = &TEST_ROMS[4];
+    if quirks.select_for("schip") != Some(2) || quirks.select_for("cosmac") != Some(1) ||
+        TEST_ROMS[5].select_for("xochip") != Some(1) || TEST_ROMS[0].select_for("chippy").is_some() {
+        panic!("Unexpected menu entries");
+    }
+}
+
+#[test]
+fn test_conformance_roms(){
+    // Checks the bundled ROMs, which must be present, and any others put
+    // in test-suite/roms
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-suite");
+    let (report, failed) = run_suite(&dir.join("roms"), &dir.join("golden"), &::quirks::PROFILES, false).unwrap();
+    if failed {
+        panic!("Conformance tests failed:\n{}", report);
+    }
+}
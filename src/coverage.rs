@@ -0,0 +1,266 @@
+//! Reports on which bytes of memory a ROM used, from the coverage tracked
+//! by `Mem`: executed as code, read as sprite or register data, written, or
+//! never touched. Useful for finding dead code and for telling code from
+//! data when disassembling.
+//!
+//! Each byte is shown as one of
+//!
+//! ```text
+//! .  untouched       C  code            R  data read
+//! W  written         B  data read and written
+//! X  code that was also written (self modifying)
+//! ```
+
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Write;
+use cpu::Cpu;
+use frontend::Hook;
+use mem::{COVER_FETCH, COVER_READ, COVER_WRITE, MEM_SIZE};
+use palette::Rgb;
+use postfx;
+use postfx::Image;
+
+/// Bytes per row of the memory map.
+const ROW_LENGTH: usize = 64;
+const ROM_START: usize = 0x200;
+const IMAGE_SCALE: usize = 8;
+
+/// How a byte was used, in order of precedence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Usage {
+    Unused,
+    Code,
+    Read,
+    Written,
+    ReadWritten,
+    ModifiedCode,
+}
+
+impl Usage {
+    pub fn from_flags(flags: u8) -> Usage {
+        let written = flags & COVER_WRITE != 0;
+        if flags & COVER_FETCH != 0 {
+            if written { Usage::ModifiedCode } else { Usage::Code }
+        } else if flags & COVER_READ != 0 {
+            if written { Usage::ReadWritten } else { Usage::Read }
+        } else if written {
+            Usage::Written
+        } else {
+            Usage::Unused
+        }
+    }
+
+    pub fn symbol(&self) -> char {
+        match *self {
+            Usage::Unused       => '.',
+            Usage::Code         => 'C',
+            Usage::Read         => 'R',
+            Usage::Written      => 'W',
+            Usage::ReadWritten  => 'B',
+            Usage::ModifiedCode => 'X',
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Usage::Unused       => "unused",
+            Usage::Code         => "code",
+            Usage::Read         => "data",
+            Usage::Written      => "written",
+            Usage::ReadWritten  => "data, written",
+            Usage::ModifiedCode => "code, written",
+        }
+    }
+
+    fn colour(&self) -> Rgb {
+        match *self {
+            Usage::Unused       => Rgb(0x20, 0x20, 0x20),
+            Usage::Code         => Rgb(0x40, 0xc0, 0x40),
+            Usage::Read         => Rgb(0x40, 0x80, 0xe0),
+            Usage::Written      => Rgb(0xe0, 0xc0, 0x40),
+            Usage::ReadWritten  => Rgb(0xc0, 0x60, 0xe0),
+            Usage::ModifiedCode => Rgb(0xe0, 0x40, 0x40),
+        }
+    }
+
+    /// ANSI escape for showing the symbol in a terminal.
+    fn ansi(&self) -> &'static str {
+        match *self {
+            Usage::Unused       => "\x1b[90m",
+            Usage::Code         => "\x1b[32m",
+            Usage::Read         => "\x1b[34m",
+            Usage::Written      => "\x1b[33m",
+            Usage::ReadWritten  => "\x1b[35m",
+            Usage::ModifiedCode => "\x1b[31m",
+        }
+    }
+}
+
+/// Runs of addresses with the same usage, as (first, last, usage).
+pub fn ranges(coverage: &[u8; MEM_SIZE], start: usize, end: usize) -> Vec<(u16, u16, Usage)> {
+    let mut ranges: Vec<(u16, u16, Usage)> = Vec::new();
+    for (addr, &flags) in coverage.iter().enumerate().take(end).skip(start) {
+        let usage = Usage::from_flags(flags);
+        match ranges.last_mut() {
+            Some(&mut (_, ref mut last, u)) if u == usage => *last = addr as u16,
+            _ => ranges.push((addr as u16, addr as u16, usage)),
+        }
+    }
+    ranges
+}
+
+/// Draws memory as rows of `ROW_LENGTH` symbols, skipping untouched rows
+/// outside the ROM. Symbols are coloured with ANSI escapes if `colour` is
+/// set.
+pub fn memory_map(coverage: &[u8; MEM_SIZE], rom_end: usize, colour: bool) -> String {
+    let mut out = String::new();
+    for (row, cells) in coverage.chunks(ROW_LENGTH).enumerate() {
+        let addr = row*ROW_LENGTH;
+        let in_rom = addr + ROW_LENGTH > ROM_START && addr < rom_end;
+        if !in_rom && cells.iter().all(|&flags| flags == 0) {
+            continue;
+        }
+        let _ = write!(out, "{:03X} ", addr);
+        let mut last = None;
+        for &flags in cells {
+            let usage = Usage::from_flags(flags);
+            if colour && last != Some(usage) {
+                out.push_str(usage.ansi());
+            }
+            out.push(usage.symbol());
+            last = Some(usage);
+        }
+        if colour {
+            out.push_str("\x1b[0m");
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// A full report: totals, the ranges within the ROM, then the map.
+pub fn report(coverage: &[u8; MEM_SIZE], rom_len: usize) -> String {
+    let rom_end = (ROM_START + rom_len).min(MEM_SIZE);
+    let mut out = String::new();
+    let count = |usage: Usage| coverage[ROM_START..rom_end].iter()
+        .filter(|&&flags| Usage::from_flags(flags) == usage).count();
+    let _ = writeln!(out, "ROM bytes: {}", rom_end - ROM_START);
+    for &usage in [Usage::Code, Usage::Read, Usage::ModifiedCode, Usage::Unused].iter() {
+        let _ = writeln!(out, "  {:<14} {:>5}", usage.name(), count(usage));
+    }
+    let _ = writeln!(out, "\nROM ranges:");
+    for (first, last, usage) in ranges(coverage, ROM_START, rom_end) {
+        let _ = writeln!(out, "  {:03X}-{:03X}  {:>4} bytes  {}", first, last, last - first + 1, usage.name());
+    }
+    let _ = writeln!(out, "\nMemory map (. unused, C code, R data, W written, B data written, X code written):");
+    out.push_str(&memory_map(coverage, rom_end, false));
+    out
+}
+
+/// Draws the coverage as an image, one `IMAGE_SCALE` square per byte.
+pub fn image(coverage: &[u8; MEM_SIZE]) -> Image {
+    let mut image = Image::new(ROW_LENGTH, MEM_SIZE/ROW_LENGTH);
+    for (addr, &flags) in coverage.iter().enumerate() {
+        image.set(addr % ROW_LENGTH, addr / ROW_LENGTH, Usage::from_flags(flags).colour());
+    }
+    postfx::scale(&image, IMAGE_SCALE)
+}
+
+/// Tracks coverage while running and writes it out when the emulator
+/// stops, as a PPM image if the file name ends in `.ppm` or as a text
+/// report otherwise. A summary is shown alongside the screen.
+pub struct CoverageHook {
+    file_name: String,
+    rom_len: usize,
+    overlay: Vec<String>,
+}
+
+impl CoverageHook {
+    pub fn new(file_name: &str, rom_len: usize) -> CoverageHook {
+        CoverageHook {
+            file_name: file_name.to_string(),
+            rom_len,
+            overlay: Vec::new(),
+        }
+    }
+}
+
+impl Hook for CoverageHook {
+    fn before_instruction(&mut self, cpu: &mut Cpu) {
+        if cpu.memory.coverage().is_none() {
+            cpu.memory.set_coverage(true);
+        }
+    }
+
+    fn end_frame(&mut self, cpu: &mut Cpu) {
+        if let Some(coverage) = cpu.memory.coverage() {
+            let rom_end = (ROM_START + self.rom_len).min(MEM_SIZE);
+            let rom = &coverage[ROM_START..rom_end];
+            let used = rom.iter().filter(|&&flags| flags & (COVER_FETCH | COVER_READ) != 0).count();
+            let code = rom.iter().filter(|&&flags| flags & COVER_FETCH != 0).count();
+            self.overlay = vec![
+                format!("ROM used {}/{}", used, rom.len()),
+                format!("code {} data {}", code, used - code),
+            ];
+        }
+    }
+
+    fn overlay(&self) -> Vec<String> {
+        self.overlay.clone()
+    }
+
+    fn finish(&mut self, cpu: &mut Cpu) {
+        let coverage = match cpu.memory.coverage() {
+            Some(coverage) => coverage,
+            None => return,
+        };
+        let result = File::create(&self.file_name).and_then(|mut f| {
+            if self.file_name.ends_with(".ppm") {
+                image(coverage).write_ppm(&mut f)
+            } else {
+                f.write_all(report(coverage, self.rom_len).as_bytes())
+            }
+        });
+        match result {
+            Ok(()) => println!("Wrote coverage to {}", self.file_name),
+            Err(e) => println!("Error writing coverage to {}: {}", self.file_name, e),
+        }
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_coverage(){
+    // LD I, 0x20C; DRW V0, V0, 2; LD [I], V0; LD V1, [I]; JP 0x208; then
+    // a 2 byte sprite and 2 unused bytes
+    let rom = vec![0xA2, 0x0C, 0xD0, 0x02, 0xF0, 0x55, 0xF1, 0x65, 0x12, 0x08, 0x00, 0x00,
+                   0xFF, 0x81, 0x00, 0x00];
+    let mut cpu = Cpu::new();
+    cpu.memory.load_rom(&rom).unwrap();
+    let mut hook = CoverageHook::new("", rom.len());
+    for _ in 0..6 {
+        hook.before_instruction(&mut cpu);
+        cpu.exec_instruction().unwrap();
+    }
+    hook.end_frame(&mut cpu);
+    let coverage = cpu.memory.coverage().unwrap();
+    let expected = vec![
+        (0x200, 0x209, Usage::Code),
+        (0x20A, 0x20B, Usage::Unused),
+        (0x20C, 0x20C, Usage::ReadWritten),
+        (0x20D, 0x20D, Usage::Read),
+        (0x20E, 0x20F, Usage::Unused),
+    ];
+    if ranges(coverage, 0x200, 0x210) != expected {
+        panic!("Unexpected ranges: {:?}", ranges(coverage, 0x200, 0x210));
+    }
+    if hook.overlay() != vec!["ROM used 12/16".to_string(), "code 10 data 2".to_string()] {
+        panic!("Unexpected overlay: {:?}", hook.overlay());
+    }
+    let text = report(coverage, rom.len());
+    if !text.contains("\n200 CCCCCCCCCC..BR..............") || text.contains("\n240 ") {
+        panic!("Unexpected memory map:\n{}", text);
+    }
+}
@@ -1,6 +1,8 @@
-extern crate rand;
+use disasm::Instruction;
+use mem;
 use mem::Mem;
-use rand::Rng;
+use quirks::Quirks;
+use rng::XorShift;
 
 const MIN_INS_PER_SECOND: u64 = 60u64;
 const MAX_INS_PER_SECOND: u64 = 4000u64;
@@ -17,11 +19,17 @@ pub struct Cpu {
     pub memory : Mem,
 
     keys:  [bool; 16],
-    key_counters: [u16; 16],
+    /// Instruction count at which each key's trail fades out.
+    key_trail_end: [u64; 16],
+    instruction_count: u64,
     instructions_per_second: u64,
+    rng: XorShift,
+    quirks: Quirks,
 }
 
 const KEY_TRAIL_LENGTH: u16 = 150;
+/// Registers, timers and stack, followed by the memory state.
+pub const STATE_SIZE: usize = 2 + 1 + 1 + 1 + 16 + 16*2 + 2 + mem::STATE_SIZE;
 
 impl Cpu { 
     pub fn new() -> Cpu {
@@ -35,8 +43,11 @@ impl Cpu {
             reg_i: 0,
             memory : Default::default(),
             keys:  [false; 16],
-            key_counters: [0u16; 16],
+            key_trail_end: [0u64; 16],
+            instruction_count: 0,
             instructions_per_second: DEFAULT_INS_PER_SECOND,
+            rng: XorShift::from_time(),
+            quirks: Quirks::default(),
         };
         ret_val
     }
@@ -45,269 +56,290 @@ impl Cpu {
         println!("[0x{:03x}]: {:04X}", self.pc, self.get_next_instruction());
     }
     pub fn exec_instruction(&mut self) -> Result<(), String>{
+        self.instruction_count += 1;
 
-        for i in self.key_counters.iter_mut() {
-            *i=i.saturating_sub(1u16);
-        }
-
-        if self.pc >= 0x1000 {
+        if self.pc as usize + 1 >= mem::MEM_SIZE {
             return Err(format!("PC at illegal address: {}", self.pc));
         }
         let ins = self.get_next_instruction();
-        //instructions are decoded as so
-        // abcd
-        // _nnn
-        // jj__
-        // __kk
-        let a   = ((ins>>12)   )  as u8;
-        let b   = ((ins>>8)&0xf)  as u8;
-        let c   = ((ins>>4)&0xf)  as u8;
-        let d   = ((ins   )&0xf)  as u8;
-        //let jj  = (ins>> 8)  as u8;
-        let kk  = (ins&0xff) as u8;
-        let nnn = ins&0xfff;
-
-        //err message for unknown opcode
-        let err_unknown_opcode = Err(format!("Unknown instruction encountered at address[0x{:03x}]: 0x{:04x}", self.pc, ins)); 
-
-        match a {
-            0x0 => match kk {
-                0xe0 => {
-                    //00E0 - CLS
-                    //Clear the display.
-                    self.memory.clear_screen()
-                },
-                0xee => {
-                    //00EE - RET
-                    //Return from a subroutine.
-                    self.pc = try!(self.pop_stack());
-                    return Ok(());
-                },
-                _   => return err_unknown_opcode,
+        self.memory.mark(self.pc as usize, 2, mem::COVER_FETCH);
+        match Instruction::decode(ins) {
+            Some(decoded) => self.execute(decoded),
+            //err message for unknown opcode
+            None => Err(format!("Unknown instruction encountered at address[0x{:03x}]: 0x{:04x}", self.pc, ins)),
+        }
+    }
+    /// Executes an instruction already decoded from the one at `pc`, for
+    /// execution engines that cache decoded instructions. Has the same
+    /// effect as `exec_instruction` would.
+    pub fn exec_decoded(&mut self, ins: Instruction) -> Result<(), String> {
+        self.instruction_count += 1;
+        self.memory.mark(self.pc as usize, 2, mem::COVER_FETCH);
+        self.execute(ins)
+    }
+    /// Accounts for `count` instructions run by an execution engine without
+    /// going through `exec_instruction` or `exec_decoded`.
+    pub fn add_executed(&mut self, count: u64) {
+        self.instruction_count += count;
+    }
+    fn execute(&mut self, ins: Instruction) -> Result<(), String> {
+        use disasm::Instruction::*;
+        match ins {
+            Cls => {
+                //00E0 - CLS
+                //Clear the display.
+                self.memory.clear_screen()
+            },
+            Ret => {
+                //00EE - RET
+                //Return from a subroutine.
+                self.pc = self.pop_stack()?;
+                return Ok(());
             },
-            0x1 => {
+            Jp { nnn } => {
                 //1nnn - JP addr
                 //Jump to location nnn.
                 self.pc=nnn;
                 return Ok(());
             },
-            0x2 => {
+            Call { nnn } => {
                 //2nnn - CALL addr
                 //Call subroutine at nnn.
                 let pc = self.pc;
-                try!(self.push_stack(pc+2u16));
+                self.push_stack(pc+2u16)?;
                 self.pc=nnn;
                 return Ok(());
             },
-            0x3 => {
+            SeByte { x, kk } => {
                 //SE Vx, byte
                 //Skip next instruction if Vx = kk.
-                if self.reg[b as usize] == kk {
+                if self.reg[x as usize] == kk {
                     self.pc += 2;
                 }
             },
-            0x4 => {
+            SneByte { x, kk } => {
                 //SNE Vx, byte
                 //Skip next instruction if Vx != kk.
-                if self.reg[b as usize] != kk {
+                if self.reg[x as usize] != kk {
                     self.pc += 2;
                 }
             },
-            0x5 => { 
+            SeReg { x, y } => {
                 //5xy0 - SE Vx, Vy
                 //Skip next instruction if Vx = Vy.
-                if self.reg[b as usize] == self.reg[c as usize] {
+                if self.reg[x as usize] == self.reg[y as usize] {
                     self.pc += 2;
                 }
             },
-            0x6 => { 
+            LdByte { x, kk } => {
                 //6xkk - LD Vx, byte
                 //Set Vx = kk.
-                self.reg[b as usize] = kk;
+                self.reg[x as usize] = kk;
             },
-            0x7 => { 
+            AddByte { x, kk } => {
                 //7xkk - ADD Vx, byte
                 //Set Vx = Vx + kk.
-                self.reg[b as usize] = self.reg[b as usize].wrapping_add(kk);
-            },
-            0x8 => match d { 
-                0x0 => {
-                    //8xy0 - LD Vx, Vy
-                    //Set Vx = Vy.
-                    self.reg[b as usize] = self.reg[c as usize];
-                },
-                0x1 => {
-                    //8xy1 - OR Vx, Vy
-                    //Set Vx = Vx OR Vy.
-                    self.reg[b as usize] |= self.reg[c as usize];
-                },
-                0x2 => {
-                    //8xy2 - AND Vx, Vy
-                    //Set Vx = Vx AND Vy.
-                    self.reg[b as usize] &= self.reg[c as usize];
-                },
-                0x3 => {
-                    //8xy3 - XOR Vx, Vy
-                    //Set Vx = Vx XOR Vy.
-                    self.reg[b as usize] ^= self.reg[c as usize];
-                },
-                0x4 => {
-                    //8xy4 - ADD Vx, Vy
-                    //Set Vx = Vx + Vy, set VF = carry.
-                    let sum : u16 = 
-                        self.reg[b as usize] as u16 + self.reg[c as usize] as u16;
-                    self.reg[0xf] = (sum > 255) as u8;
-                    self.reg[b as usize] = sum as u8;
-                },
-                0x5 => {
-                    //8xy5 - SUB Vx, Vy
-                    //Set Vx = Vx - Vy, set VF = (Vx > Vy).
-                    let sum : i16 = 
-                        self.reg[b as usize] as i16 - self.reg[c as usize] as i16;
-                    self.reg[0xf] = (sum > 0) as u8;
-                    self.reg[b as usize] = sum as u8;
-                },
-                0x6 => {
-                    //8xy6 - SHR Vx {, Vy}
-                    //Set Vx = Vx SHR 1, set VF = Vx[LSB]
-                    self.reg[0xf] = self.reg[b as usize] & 0x01;
-                    self.reg[b as usize]>>=1;
-                },
-                0x7 => {
-                    //8xy7 - SUBN Vx, Vy
-                    //Set Vx = Vy - Vx, set VF = (Vy > Vx).
-                    let sum : i16 = 
-                        self.reg[c as usize] as i16 - self.reg[b as usize] as i16;
-                    self.reg[0xf] = (sum > 0) as u8;
-                    self.reg[b as usize] = sum as u8;
-                },
-                0xE => {
-                    //8xyE - SHL Vx {, Vy}
-                    //Set Vx = Vx SHL 1, set VF = Vx[MSB]
-                    self.reg[0xf] = (self.reg[b as usize] & 0x80u8 != 0) as u8;
-                    self.reg[b as usize]<<=1;
-                },
-                _   => return err_unknown_opcode,
-            },
-            0x9 => { 
+                self.reg[x as usize] = self.reg[x as usize].wrapping_add(kk);
+            },
+            LdReg { x, y } => {
+                //8xy0 - LD Vx, Vy
+                //Set Vx = Vy.
+                self.reg[x as usize] = self.reg[y as usize];
+            },
+            Or { x, y } => {
+                //8xy1 - OR Vx, Vy
+                //Set Vx = Vx OR Vy.
+                self.reg[x as usize] |= self.reg[y as usize];
+                if self.quirks.vf_reset {
+                    self.reg[0xf] = 0;
+                }
+            },
+            And { x, y } => {
+                //8xy2 - AND Vx, Vy
+                //Set Vx = Vx AND Vy.
+                self.reg[x as usize] &= self.reg[y as usize];
+                if self.quirks.vf_reset {
+                    self.reg[0xf] = 0;
+                }
+            },
+            Xor { x, y } => {
+                //8xy3 - XOR Vx, Vy
+                //Set Vx = Vx XOR Vy.
+                self.reg[x as usize] ^= self.reg[y as usize];
+                if self.quirks.vf_reset {
+                    self.reg[0xf] = 0;
+                }
+            },
+            AddReg { x, y } => {
+                //8xy4 - ADD Vx, Vy
+                //Set Vx = Vx + Vy, set VF = carry.
+                let sum : u16 =
+                    self.reg[x as usize] as u16 + self.reg[y as usize] as u16;
+                self.reg[0xf] = (sum > 255) as u8;
+                self.reg[x as usize] = sum as u8;
+            },
+            Sub { x, y } => {
+                //8xy5 - SUB Vx, Vy
+                //Set Vx = Vx - Vy, set VF = (Vx > Vy).
+                let sum : i16 =
+                    self.reg[x as usize] as i16 - self.reg[y as usize] as i16;
+                self.reg[0xf] = (sum > 0) as u8;
+                self.reg[x as usize] = sum as u8;
+            },
+            Shr { x, y } => {
+                //8xy6 - SHR Vx {, Vy}
+                //Set Vx = Vx SHR 1, set VF = Vx[LSB]
+                if self.quirks.shift_vy {
+                    let value = self.reg[y as usize];
+                    self.reg[x as usize] = value >> 1;
+                    self.reg[0xf] = value & 0x01;
+                } else {
+                    self.reg[0xf] = self.reg[x as usize] & 0x01;
+                    self.reg[x as usize]>>=1;
+                }
+            },
+            Subn { x, y } => {
+                //8xy7 - SUBN Vx, Vy
+                //Set Vx = Vy - Vx, set VF = (Vy > Vx).
+                let sum : i16 =
+                    self.reg[y as usize] as i16 - self.reg[x as usize] as i16;
+                self.reg[0xf] = (sum > 0) as u8;
+                self.reg[x as usize] = sum as u8;
+            },
+            Shl { x, y } => {
+                //8xyE - SHL Vx {, Vy}
+                //Set Vx = Vx SHL 1, set VF = Vx[MSB]
+                if self.quirks.shift_vy {
+                    let value = self.reg[y as usize];
+                    self.reg[x as usize] = value << 1;
+                    self.reg[0xf] = value >> 7;
+                } else {
+                    self.reg[0xf] = (self.reg[x as usize] & 0x80u8 != 0) as u8;
+                    self.reg[x as usize]<<=1;
+                }
+            },
+            SneReg { x, y } => {
                 //9xy0 - SNE Vx, Vy
                 //Skip next instruction if Vx != Vy.
-                if self.reg[b as usize] != self.reg[c as usize] {
+                if self.reg[x as usize] != self.reg[y as usize] {
                     self.pc += 2;
                 }
             },
-            0xA => {
+            LdI { nnn } => {
                 //Annn - LD I, addr
                 //Set I = nnn.
                 self.reg_i=nnn;
             },
-            0xB => {
+            JpV0 { nnn } => {
                 //Bnnn - JP V0, addr
                 //Jump to location nnn + V0.
-                self.pc=nnn+(self.reg[0] as u16);
+                let x = if self.quirks.jump_vx { (nnn >> 8) & 0xf } else { 0 };
+                self.pc=nnn+(self.reg[x as usize] as u16);
                 return Ok(());
             },
-            0xC => {
+            Rnd { x, kk } => {
                 //Cxkk - RND Vx, byte
                 //Set Vx = random byte AND kk.
-                self.reg[b as usize]=kk & (rand::thread_rng().gen_range(0,256) as u8);
+                self.reg[x as usize]=kk & self.rng.next_u8();
             },
-            0xD => {
+            Drw { x, y, n } => {
                 //Dxyn - DRW Vx, Vy, nibble
                 //Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
-                let x = self.reg[b as usize];
-                let y = self.reg[c as usize];
+                let x = self.reg[x as usize];
+                let y = self.reg[y as usize];
+                self.memory.mark(self.reg_i as usize, n as usize, mem::COVER_READ);
                 self.reg[0xf]=self.memory
-                    .draw_sprite(self.reg_i, x, y, d) as u8;
-            }, 
-            0xE => match kk {
-                0x9E => {
-                    //Ex9E - SKP Vx
-                    //Skip next instruction if key with the value of Vx is pressed.
-                    let key = self.reg[b as usize]&0xfu8;
-                    self.key_counters[key as usize]=KEY_TRAIL_LENGTH;
-                    if self.keys[key as usize] {
-                        self.pc+=2;
-                    }
-                },
-                0xA1 => {
-                    //ExA1 - SKNP Vx
-                    //Skip next instruction if key with the value of Vx is not pressed.
-                    let key = self.reg[b as usize]&0xfu8;
-                    self.key_counters[key as usize]=KEY_TRAIL_LENGTH;
-                    if !self.keys[key as usize] {
-                        self.pc+=2;
-                    }
-                },
-                _ => return err_unknown_opcode,
-            },
-            0xF => match kk {
-                0x07 => {
-                    //Fx07 - LD Vx, DT
-                    //Set Vx = delay timer value.
-                    self.reg[b as usize]=self.dt;
-                }, 
-                0x0A => {
-                    //Fx0A - LD Vx, K
-                    //Wait for a key press, store the value of the key in Vx.
-                    self.reg[b as usize]=0xff;
-                    for i in 0..16 {
-                        if self.keys[i] {
-                            self.reg[b as usize] = i as u8;
-                            break;
-                        }
-                    }
-                    if self.reg[b as usize] == 0xff {
-                        return Ok(());
+                    .draw_sprite(self.reg_i, x, y, n, self.quirks.wrap_sprites) as u8;
+            },
+            Skp { x } => {
+                //Ex9E - SKP Vx
+                //Skip next instruction if key with the value of Vx is pressed.
+                let key = self.reg[x as usize]&0xfu8;
+                self.touch_key(key);
+                if self.keys[key as usize] {
+                    self.pc+=2;
+                }
+            },
+            Sknp { x } => {
+                //ExA1 - SKNP Vx
+                //Skip next instruction if key with the value of Vx is not pressed.
+                let key = self.reg[x as usize]&0xfu8;
+                self.touch_key(key);
+                if !self.keys[key as usize] {
+                    self.pc+=2;
+                }
+            },
+            LdVxDt { x } => {
+                //Fx07 - LD Vx, DT
+                //Set Vx = delay timer value.
+                self.reg[x as usize]=self.dt;
+            },
+            LdKey { x } => {
+                //Fx0A - LD Vx, K
+                //Wait for a key press, store the value of the key in Vx.
+                self.reg[x as usize]=0xff;
+                for i in 0..16 {
+                    if self.keys[i] {
+                        self.reg[x as usize] = i as u8;
+                        break;
                     }
-                },
-                0x15 => {
-                    //Fx15 - LD DT, Vx
-                    //Set delay timer = Vx.
-                    self.dt=self.reg[b as usize];
-                }, 
-                0x18 => {
-                    //Fx18 - LD ST, Vx
-                    //Set sound timer = Vx.
-                    self.st=self.reg[b as usize];
-                }, 
-                0x1E => {
-                    //Fx1E - ADD I, Vx
-                    //Set I = I + Vx.
-                    self.reg_i+=self.reg[b as usize] as u16;
-                }, 
-                0x29 => {
-                    //Fx29 - LD F, Vx
-                    //Set I = location of sprite for digit Vx.
-                    self.reg_i=Mem::get_sprite_addr(self.reg[b as usize]&0xfu8);
-                },
-                0x33 => {
-                    //Fx33 - LD B, Vx
-                    //Store BCD representation of Vx in memory locations I, I+1, and I+2.
-                    let value = self.reg[b as usize];
-                    let addr = self.reg_i as usize;
-                    self.memory.write_u8(  addr, value/100);
-                    self.memory.write_u8(addr+1, (value/10)%10);
-                    self.memory.write_u8(addr+2, value%10);
-                }, 
-                0x55 => {
-                    //Fx55 - LD [I], Vx
-                    //Store registers V0 through Vx in memory starting at location I.
-                    let addr = self.reg_i as usize;
-                    let vec = &self.reg[0..((b+1) as usize)].to_vec();
-                    self.memory.memset(addr, vec);
-                }, 
-                0x65 => {
-                    //Fx65 - LD Vx, [I] 
-                    //Read registers V0 through Vx from memory starting at location I.
-                    let addr = self.reg_i as usize;
-                    let b = b as usize;
-                    let mem_vec = self.memory.get_vec(addr, b+1);
-                    self.reg[0..b+1].clone_from_slice(mem_vec.as_slice());
-                }, 
-                _ => return err_unknown_opcode,
-            },
-            _ => unreachable!()
+                }
+                if self.reg[x as usize] == 0xff {
+                    return Ok(());
+                }
+            },
+            LdDtVx { x } => {
+                //Fx15 - LD DT, Vx
+                //Set delay timer = Vx.
+                self.dt=self.reg[x as usize];
+            },
+            LdStVx { x } => {
+                //Fx18 - LD ST, Vx
+                //Set sound timer = Vx.
+                self.st=self.reg[x as usize];
+            },
+            AddI { x } => {
+                //Fx1E - ADD I, Vx
+                //Set I = I + Vx.
+                self.reg_i=self.reg_i.wrapping_add(self.reg[x as usize] as u16);
+            },
+            LdFont { x } => {
+                //Fx29 - LD F, Vx
+                //Set I = location of sprite for digit Vx.
+                self.reg_i=Mem::get_sprite_addr(self.reg[x as usize]&0xfu8);
+            },
+            LdBcd { x } => {
+                //Fx33 - LD B, Vx
+                //Store BCD representation of Vx in memory locations I, I+1, and I+2.
+                let value = self.reg[x as usize];
+                let addr = self.reg_i as usize;
+                self.memory.write_u8(  addr    % mem::MEM_SIZE, value/100);
+                self.memory.write_u8((addr+1) % mem::MEM_SIZE, (value/10)%10);
+                self.memory.write_u8((addr+2) % mem::MEM_SIZE, value%10);
+            },
+            Store { x } => {
+                //Fx55 - LD [I], Vx
+                //Store registers V0 through Vx in memory starting at location I.
+                let addr = self.reg_i as usize;
+                for (offset, &value) in self.reg[0..((x+1) as usize)].iter().enumerate() {
+                    self.memory.write_u8((addr + offset) % mem::MEM_SIZE, value);
+                }
+                if self.quirks.memory_increment {
+                    self.reg_i = self.reg_i.wrapping_add(x as u16 + 1);
+                }
+            },
+            Load { x } => {
+                //Fx65 - LD Vx, [I]
+                //Read registers V0 through Vx from memory starting at location I.
+                let addr = self.reg_i as usize;
+                let x = x as usize;
+                let mem_vec = self.memory.get_vec_wrapping(addr, x+1);
+                self.memory.mark(addr, x+1, mem::COVER_READ);
+                self.reg[0..x+1].clone_from_slice(mem_vec.as_slice());
+                if self.quirks.memory_increment {
+                    self.reg_i = self.reg_i.wrapping_add(x as u16 + 1);
+                }
+            },
         };
 
         self.pc += 2;
@@ -358,8 +390,13 @@ impl Cpu {
     pub fn get_key(&self, keycode: u8) -> bool {
         self.keys[keycode as usize]
     }
+    /// Starts the trail shown when the program checks a key.
+    fn touch_key(&mut self, keycode: u8) {
+        self.key_trail_end[keycode as usize] = self.instruction_count + KEY_TRAIL_LENGTH as u64;
+    }
     pub fn get_key_trail(&self, keycode: u8) -> u8 {
-        let prod: u32 = (self.key_counters[keycode as usize] as u32)*256;
+        let counter = self.key_trail_end[keycode as usize].saturating_sub(self.instruction_count);
+        let prod: u32 = (counter as u32)*256;
         (prod/(KEY_TRAIL_LENGTH as u32)) as u8
     }
     pub fn increase_ips(&mut self) {
@@ -383,9 +420,97 @@ impl Cpu {
         self.instructions_per_second = 
             clamp(MIN_INS_PER_SECOND, MAX_INS_PER_SECOND, new_ips);
     }
+    /// The interpreter quirks being followed.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+    /// Changes which interpreter quirks are followed.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+    /// Reseeds the random number generator, for reproducible runs.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.rng = XorShift::new(seed);
+    }
     pub fn get_st_active(&self) -> bool {
         self.st != 0
     }
+    pub fn get_pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn get_i(&self) -> u16 {
+        self.reg_i
+    }
+    pub fn get_sp(&self) -> usize {
+        self.sp
+    }
+    pub fn get_dt(&self) -> u8 {
+        self.dt
+    }
+    pub fn get_st(&self) -> u8 {
+        self.st
+    }
+    pub fn get_reg(&self, reg: u8) -> u8 {
+        self.reg[reg as usize]
+    }
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+    pub fn set_i(&mut self, i: u16) {
+        self.reg_i = i;
+    }
+    pub fn set_reg(&mut self, reg: u8, val: u8) {
+        self.reg[reg as usize] = val;
+    }
+    /// Sets the stack pointer, which can't be more than 16.
+    pub fn set_sp(&mut self, sp: usize) -> Result<(), String> {
+        if sp > 16 {
+            return Err(format!("Stack pointer {} out of range", sp));
+        }
+        self.sp = sp;
+        Ok(())
+    }
+    pub fn set_dt(&mut self, dt: u8) {
+        self.dt = dt;
+    }
+    pub fn set_st(&mut self, st: u8) {
+        self.st = st;
+    }
+    /// Serializes the machine state, not including keys or speed.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(STATE_SIZE);
+        out.extend_from_slice(&[(self.pc >> 8) as u8, self.pc as u8]);
+        out.extend_from_slice(&[self.sp as u8, self.dt, self.st]);
+        out.extend_from_slice(&self.reg);
+        for v in self.stack.iter() {
+            out.extend_from_slice(&[(v >> 8) as u8, *v as u8]);
+        }
+        out.extend_from_slice(&[(self.reg_i >> 8) as u8, self.reg_i as u8]);
+        self.memory.save_state(&mut out);
+        out
+    }
+    /// Restores a state written by `save_state`. The machine is left
+    /// untouched if the state is invalid.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != STATE_SIZE {
+            return Err(format!("Save state is {} bytes, expected {}", data.len(), STATE_SIZE));
+        }
+        let read_u16 = |offset: usize| ((data[offset] as u16) << 8) | data[offset+1] as u16;
+        if data[2] as usize > 16 {
+            return Err(format!("Invalid stack pointer {} in save state", data[2]));
+        }
+        self.memory.load_state(&data[STATE_SIZE-mem::STATE_SIZE..])?;
+        self.pc = read_u16(0);
+        self.sp = data[2] as usize;
+        self.dt = data[3];
+        self.st = data[4];
+        self.reg.copy_from_slice(&data[5..21]);
+        for i in 0..16 {
+            self.stack[i] = read_u16(21 + i*2);
+        }
+        self.reg_i = read_u16(53);
+        Ok(())
+    }
 }
 
 #[allow(dead_code)]
@@ -855,3 +980,212 @@ fn test_exec_instruction(){
         }
     }
 }
+
+#[test]
+fn test_save_state(){
+    let mut cpu = Cpu::new();
+    cpu.memory.memset(0x200, &vec![0x6A, 0x3C, 0xFA, 0x18, 0xA2, 0x34, 0x22, 0x00, 0xD0, 0x05]);
+    for _ in 0..5 {
+        attempt(cpu.exec_instruction());
+    }
+    let state = cpu.save_state();
+    if state.len() != STATE_SIZE {
+        panic!("Save state has wrong size {}", state.len());
+    }
+    let mut restored = Cpu::new();
+    attempt(restored.load_state(&state));
+    if restored.pc != cpu.pc || restored.sp != cpu.sp || restored.st != cpu.st ||
+       restored.reg != cpu.reg || restored.stack != cpu.stack || restored.reg_i != cpu.reg_i {
+        panic!("Registers not restored");
+    }
+    if restored.memory.framebuffer() != cpu.memory.framebuffer() ||
+       restored.memory.get_vec(0, mem::MEM_SIZE) != cpu.memory.get_vec(0, mem::MEM_SIZE) {
+        panic!("Memory not restored");
+    }
+    if restored.load_state(&state[1..]).is_ok() {
+        panic!("Truncated save state should be rejected");
+    }
+}
+
+/// A machine in a random state with `opcode` at PC. I is often near or
+/// past the end of memory, where Fx33, Fx55, Fx65 and Dxyn wrap around.
+#[cfg(test)]
+fn random_cpu(rng: &mut XorShift, opcode: u16) -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.set_seed(rng.next_u32());
+    for byte in cpu.memory.raw_mut()[0x200..].iter_mut() {
+        *byte = rng.next_u8();
+    }
+    for reg in cpu.reg.iter_mut() {
+        *reg = rng.next_u8();
+    }
+    for addr in cpu.stack.iter_mut() {
+        *addr = 0x200 + (rng.next_u32() % 0x6ff) as u16 * 2;
+    }
+    for key in cpu.keys.iter_mut() {
+        *key = rng.next_u32() & 3 == 0;
+    }
+    cpu.sp = (rng.next_u32() % 17) as usize;
+    cpu.reg_i = match rng.next_u32() & 3 {
+        0 => 0xff0 + (rng.next_u32() % 0x10) as u16,
+        1 => rng.next_u32() as u16,
+        _ => (rng.next_u32() % 0x1000) as u16,
+    };
+    cpu.dt = rng.next_u8();
+    cpu.st = rng.next_u8();
+    cpu.pc = 0x200 + (rng.next_u32() % 0x700) as u16 * 2;
+    let pc = cpu.pc as usize;
+    cpu.memory.write_u16(pc, opcode);
+    for _ in 0..8 {
+        let (x, y) = (rng.next_u8(), rng.next_u8());
+        cpu.memory.draw_sprite(0x200 + rng.next_u8() as u16, x % 64, y % 32, 8, false);
+    }
+    cpu
+}
+
+#[test]
+fn test_instruction_properties(){
+    let mut rng = XorShift::new(47);
+    for _ in 0..500 {
+        let r = rng.next_u32();
+        let (x, y, n) = ((r & 0xf) as u16, (r >> 4 & 0xf) as u16, (r >> 8 & 0xf) as u16);
+        let kk = (r >> 12 & 0xff) as u16;
+        let nnn = (r >> 20 & 0xfff) as u16;
+        let xy = x << 8 | y << 4;
+        let mut opcodes = vec![0x00E0, 0x00EE, 0x1000 | nnn, 0x2000 | nnn, 0x3000 | x << 8 | kk,
+                               0x4000 | x << 8 | kk, 0x5000 | xy, 0x6000 | x << 8 | kk, 0x7000 | x << 8 | kk,
+                               0x9000 | xy, 0xA000 | nnn, 0xB000 | nnn, 0xC000 | x << 8 | kk,
+                               0xD000 | xy | n, 0xE09E | x << 8, 0xE0A1 | x << 8, nnn, 0xF0FF];
+        opcodes.extend([0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE, 0x8].iter().map(|op| 0x8000 | xy | op));
+        opcodes.extend([0x07, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x33, 0x55, 0x65].iter().map(|op| 0xF000 | x << 8 | op));
+        for &opcode in opcodes.iter() {
+            let mut cpu = random_cpu(&mut rng, opcode);
+            check_instruction(&mut cpu, opcode);
+        }
+    }
+    // The last byte of memory is only half an instruction
+    let mut cpu = random_cpu(&mut rng, 0x6000);
+    cpu.pc = 0xfff;
+    if cpu.exec_instruction().is_ok() {
+        panic!("Expected an instruction at 0xFFF to fail");
+    }
+}
+
+/// Runs `opcode` and checks everything it did against a model of the
+/// instruction set.
+#[cfg(test)]
+fn check_instruction(cpu: &mut Cpu, opcode: u16) {
+    let x = (opcode >> 8 & 0xf) as usize;
+    let y = (opcode >> 4 & 0xf) as usize;
+    let n = (opcode & 0xf) as usize;
+    let kk = opcode as u8;
+    let nnn = opcode & 0xfff;
+    let (vx, vy) = (cpu.reg[x], cpu.reg[y]);
+    let before = format!("{:04X} at {:03X} with V={:02X?} I={:03X} SP={} keys={:?}",
+                         opcode, cpu.pc, cpu.reg, cpu.reg_i, cpu.sp, cpu.keys);
+
+    let mut reg = cpu.reg;
+    let mut stack = cpu.stack;
+    let (mut pc, mut i, mut sp, mut dt, mut st) = (cpu.pc + 2, cpu.reg_i, cpu.sp, cpu.dt, cpu.st);
+    let mut memory = cpu.memory.get_vec(0, mem::MEM_SIZE);
+    let mut screen = cpu.memory.framebuffer();
+    let mut fails = Instruction::decode(opcode).is_none();
+    let skip = |condition: bool| if condition { cpu.pc + 4 } else { cpu.pc + 2 };
+    match opcode >> 12 {
+        0x0 if kk == 0xE0 => screen = vec![0; screen.len()],
+        0x0 if kk == 0xEE => if sp == 0 { fails = true } else { sp -= 1; pc = stack[sp] },
+        0x1 => pc = nnn,
+        0x2 => if sp == 16 { fails = true } else { stack[sp] = cpu.pc + 2; sp += 1; pc = nnn },
+        0x3 => pc = skip(vx == kk),
+        0x4 => pc = skip(vx != kk),
+        0x5 => pc = skip(vx == vy),
+        0x6 => reg[x] = kk,
+        0x7 => reg[x] = ((vx as u16 + kk as u16) & 0xff) as u8,
+        // Flags are set before the result is stored, so with x = F the
+        // result (or the shifted flag) is what's left in VF
+        0x8 => match n {
+            0x0 => reg[x] = vy,
+            0x1 => reg[x] = vx | vy,
+            0x2 => reg[x] = vx & vy,
+            0x3 => reg[x] = vx ^ vy,
+            0x4 => { reg[0xf] = (vx as u16 + vy as u16 > 0xff) as u8; reg[x] = ((vx as u16 + vy as u16) & 0xff) as u8 },
+            0x5 => { reg[0xf] = (vx > vy) as u8; reg[x] = ((vx as u16 + 0x100 - vy as u16) & 0xff) as u8 },
+            0x7 => { reg[0xf] = (vy > vx) as u8; reg[x] = ((vy as u16 + 0x100 - vx as u16) & 0xff) as u8 },
+            0x6 => { reg[0xf] = vx & 1; reg[x] /= 2 },
+            0xE => { reg[0xf] = vx >> 7; reg[x] = ((reg[x] as u16 * 2) & 0xff) as u8 },
+            _ => (),
+        },
+        0x9 => pc = skip(vx != vy),
+        0xA => i = nnn,
+        0xB => pc = nnn + reg[0] as u16,
+        0xD => {
+            let mut collision = false;
+            for row in 0..n {
+                for bit in 0..8 {
+                    let (px, py) = (vx as usize + bit, vy as usize + row);
+                    if memory[(i as usize + row) % mem::MEM_SIZE] & (0x80 >> bit) != 0 && px < mem::SCREEN_WIDTH && py < mem::SCREEN_HEIGHT {
+                        let pixel = &mut screen[py*mem::SCREEN_WIDTH + px];
+                        collision |= *pixel != 0;
+                        *pixel ^= 1;
+                    }
+                }
+            }
+            reg[0xf] = collision as u8;
+        }
+        0xE if kk == 0x9E => pc = skip(cpu.keys[(vx & 0xf) as usize]),
+        0xE if kk == 0xA1 => pc = skip(!cpu.keys[(vx & 0xf) as usize]),
+        0xF => match kk {
+            0x07 => reg[x] = dt,
+            0x0A => match cpu.keys.iter().position(|&down| down) {
+                Some(key) => reg[x] = key as u8,
+                None => { reg[x] = 0xff; pc = cpu.pc },
+            },
+            0x15 => dt = vx,
+            0x18 => st = vx,
+            0x1E => i = i.wrapping_add(vx as u16),
+            0x29 => i = 5 * (vx & 0xf) as u16,
+            0x33 => for (k, &digit) in [vx / 100, vx / 10 % 10, vx % 10].iter().enumerate() {
+                memory[(i as usize + k) % mem::MEM_SIZE] = digit;
+            },
+            0x55 => for k in 0..x + 1 { memory[(i as usize + k) % mem::MEM_SIZE] = reg[k] },
+            0x65 => for k in 0..x + 1 { reg[k] = memory[(i as usize + k) % mem::MEM_SIZE] },
+            _ => (),
+        },
+        _ => (),
+    }
+
+    let result = cpu.exec_instruction();
+    if fails {
+        if result.is_ok() {
+            panic!("{} should have failed", before);
+        }
+        return;
+    }
+    if let Err(e) = result {
+        panic!("{} failed: {}", before, e);
+    }
+    if opcode >> 12 == 0xC {
+        // Anything random, as long as only kk's bits are set
+        if cpu.reg[x] & !kk != 0 {
+            panic!("{} set V{:X}={:02X}", before, x, cpu.reg[x]);
+        }
+        reg[x] = cpu.reg[x];
+    }
+    if opcode & 0xf0ff == 0xf033 {
+        let digits = cpu.memory.get_vec_wrapping(i as usize, 3);
+        if digits.iter().any(|&d| d > 9) || digits[0] as u16 * 100 + digits[1] as u16 * 10 + digits[2] as u16 != vx as u16 {
+            panic!("{} wrote BCD {:?}", before, digits);
+        }
+    }
+    let actual = (cpu.reg, cpu.pc, cpu.reg_i, cpu.sp, &cpu.stack[..cpu.sp], cpu.dt, cpu.st);
+    if actual != (reg, pc, i, sp, &stack[..sp], dt, st) {
+        panic!("{}\n  expected V={:02X?} PC={:03X} I={:03X} SP={} stack={:03X?} DT={} ST={}\n  got      {:02X?}",
+               before, reg, pc, i, sp, &stack[..sp], dt, st, actual);
+    }
+    if cpu.memory.get_vec(0, mem::MEM_SIZE) != memory {
+        panic!("{} changed memory unexpectedly", before);
+    }
+    if cpu.memory.framebuffer() != screen {
+        panic!("{} drew the screen incorrectly", before);
+    }
+}
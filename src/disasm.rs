@@ -0,0 +1,220 @@
+//! Decoding and disassembly of Chip8 instructions, using the mnemonics from
+//! Cowgod's Chip-8 technical reference.
+
+use std::fmt;
+
+/// A decoded instruction. `x` and `y` are register numbers, `kk` a byte
+/// immediate, `n` a nibble and `nnn` an address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    Cls,
+    Ret,
+    Jp { nnn: u16 },
+    Call { nnn: u16 },
+    SeByte { x: u8, kk: u8 },
+    SneByte { x: u8, kk: u8 },
+    SeReg { x: u8, y: u8 },
+    LdByte { x: u8, kk: u8 },
+    AddByte { x: u8, kk: u8 },
+    LdReg { x: u8, y: u8 },
+    Or { x: u8, y: u8 },
+    And { x: u8, y: u8 },
+    Xor { x: u8, y: u8 },
+    AddReg { x: u8, y: u8 },
+    Sub { x: u8, y: u8 },
+    Shr { x: u8, y: u8 },
+    Subn { x: u8, y: u8 },
+    Shl { x: u8, y: u8 },
+    SneReg { x: u8, y: u8 },
+    LdI { nnn: u16 },
+    JpV0 { nnn: u16 },
+    Rnd { x: u8, kk: u8 },
+    Drw { x: u8, y: u8, n: u8 },
+    Skp { x: u8 },
+    Sknp { x: u8 },
+    LdVxDt { x: u8 },
+    LdKey { x: u8 },
+    LdDtVx { x: u8 },
+    LdStVx { x: u8 },
+    AddI { x: u8 },
+    LdFont { x: u8 },
+    LdBcd { x: u8 },
+    Store { x: u8 },
+    Load { x: u8 },
+}
+
+impl Instruction {
+    /// Decodes an opcode, returning None if it isn't a valid instruction.
+    /// Like the interpreter, unused nibbles are ignored, so 0x5121 is still
+    /// `SE V1, V2`.
+    pub fn decode(ins: u16) -> Option<Instruction> {
+        use self::Instruction::*;
+        let x   = ((ins>>8)&0xf) as u8;
+        let y   = ((ins>>4)&0xf) as u8;
+        let n   = (ins&0xf) as u8;
+        let kk  = (ins&0xff) as u8;
+        let nnn = ins&0xfff;
+        Some(match ins>>12 {
+            0x0 => match kk {
+                0xE0 => Cls,
+                0xEE => Ret,
+                _ => return None,
+            },
+            0x1 => Jp { nnn },
+            0x2 => Call { nnn },
+            0x3 => SeByte { x, kk },
+            0x4 => SneByte { x, kk },
+            0x5 => SeReg { x, y },
+            0x6 => LdByte { x, kk },
+            0x7 => AddByte { x, kk },
+            0x8 => match n {
+                0x0 => LdReg { x, y },
+                0x1 => Or { x, y },
+                0x2 => And { x, y },
+                0x3 => Xor { x, y },
+                0x4 => AddReg { x, y },
+                0x5 => Sub { x, y },
+                0x6 => Shr { x, y },
+                0x7 => Subn { x, y },
+                0xE => Shl { x, y },
+                _ => return None,
+            },
+            0x9 => SneReg { x, y },
+            0xA => LdI { nnn },
+            0xB => JpV0 { nnn },
+            0xC => Rnd { x, kk },
+            0xD => Drw { x, y, n },
+            0xE => match kk {
+                0x9E => Skp { x },
+                0xA1 => Sknp { x },
+                _ => return None,
+            },
+            0xF => match kk {
+                0x07 => LdVxDt { x },
+                0x0A => LdKey { x },
+                0x15 => LdDtVx { x },
+                0x18 => LdStVx { x },
+                0x1E => AddI { x },
+                0x29 => LdFont { x },
+                0x33 => LdBcd { x },
+                0x55 => Store { x },
+                0x65 => Load { x },
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+
+    /// The opcode pattern this instruction was decoded from, e.g. "8xy4".
+    pub fn pattern(&self) -> &'static str {
+        use self::Instruction::*;
+        match *self {
+            Cls            => "00E0",
+            Ret            => "00EE",
+            Jp { .. }      => "1nnn",
+            Call { .. }    => "2nnn",
+            SeByte { .. }  => "3xkk",
+            SneByte { .. } => "4xkk",
+            SeReg { .. }   => "5xy0",
+            LdByte { .. }  => "6xkk",
+            AddByte { .. } => "7xkk",
+            LdReg { .. }   => "8xy0",
+            Or { .. }      => "8xy1",
+            And { .. }     => "8xy2",
+            Xor { .. }     => "8xy3",
+            AddReg { .. }  => "8xy4",
+            Sub { .. }     => "8xy5",
+            Shr { .. }     => "8xy6",
+            Subn { .. }    => "8xy7",
+            Shl { .. }     => "8xyE",
+            SneReg { .. }  => "9xy0",
+            LdI { .. }     => "Annn",
+            JpV0 { .. }    => "Bnnn",
+            Rnd { .. }     => "Cxkk",
+            Drw { .. }     => "Dxyn",
+            Skp { .. }     => "Ex9E",
+            Sknp { .. }    => "ExA1",
+            LdVxDt { .. }  => "Fx07",
+            LdKey { .. }   => "Fx0A",
+            LdDtVx { .. }  => "Fx15",
+            LdStVx { .. }  => "Fx18",
+            AddI { .. }    => "Fx1E",
+            LdFont { .. }  => "Fx29",
+            LdBcd { .. }   => "Fx33",
+            Store { .. }   => "Fx55",
+            Load { .. }    => "Fx65",
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+        match *self {
+            Cls                => write!(f, "CLS"),
+            Ret                => write!(f, "RET"),
+            Jp { nnn }         => write!(f, "JP 0x{:03X}", nnn),
+            Call { nnn }       => write!(f, "CALL 0x{:03X}", nnn),
+            SeByte { x, kk }   => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            SneByte { x, kk }  => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            SeReg { x, y }     => write!(f, "SE V{:X}, V{:X}", x, y),
+            LdByte { x, kk }   => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            AddByte { x, kk }  => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            LdReg { x, y }     => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or { x, y }        => write!(f, "OR V{:X}, V{:X}", x, y),
+            And { x, y }       => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor { x, y }       => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddReg { x, y }    => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub { x, y }       => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr { x, y }       => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn { x, y }      => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl { x, y }       => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneReg { x, y }    => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI { nnn }        => write!(f, "LD I, 0x{:03X}", nnn),
+            JpV0 { nnn }       => write!(f, "JP V0, 0x{:03X}", nnn),
+            Rnd { x, kk }      => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Drw { x, y, n }    => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp { x }          => write!(f, "SKP V{:X}", x),
+            Sknp { x }         => write!(f, "SKNP V{:X}", x),
+            LdVxDt { x }       => write!(f, "LD V{:X}, DT", x),
+            LdKey { x }        => write!(f, "LD V{:X}, K", x),
+            LdDtVx { x }       => write!(f, "LD DT, V{:X}", x),
+            LdStVx { x }       => write!(f, "LD ST, V{:X}", x),
+            AddI { x }         => write!(f, "ADD I, V{:X}", x),
+            LdFont { x }       => write!(f, "LD F, V{:X}", x),
+            LdBcd { x }        => write!(f, "LD B, V{:X}", x),
+            Store { x }        => write!(f, "LD [I], V{:X}", x),
+            Load { x }         => write!(f, "LD V{:X}, [I]", x),
+        }
+    }
+}
+
+/// Disassembles an opcode, showing invalid ones as data.
+pub fn disassemble(ins: u16) -> String {
+    match Instruction::decode(ins) {
+        Some(instruction) => instruction.to_string(),
+        None => format!("DW 0x{:04X}", ins),
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_disassemble(){
+    let cases = [
+        (0x00E0, "CLS"),
+        (0x2ABC, "CALL 0xABC"),
+        (0x6A02, "LD VA, 0x02"),
+        (0x8126, "SHR V1, V2"),
+        (0xD015, "DRW V0, V1, 5"),
+        (0xF355, "LD [I], V3"),
+        (0x8128, "DW 0x8128"),
+        (0x5121, "SE V1, V2"),
+        (0x0123, "DW 0x0123"),
+    ];
+    for &(ins, text) in cases.iter() {
+        if disassemble(ins) != text {
+            panic!("Expected {:04X} to disassemble to {}, got {}", ins, text, disassemble(ins));
+        }
+    }
+}
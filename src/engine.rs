@@ -0,0 +1,208 @@
+//! Ways of running many instructions at once, for fuzzing and batch runs
+//! where nothing needs to watch each instruction. `Interpreter` just calls
+//! `Cpu::exec_instruction`. `BlockEngine` decodes straight line runs of
+//! instructions (basic blocks) once and caches them, throwing them away
+//! when memory they were decoded from is written. Both give exactly the
+//! same results.
+
+use std::rc::Rc;
+use cpu::Cpu;
+use disasm::Instruction;
+use mem::{MEM_SIZE, PAGE_SIZE};
+
+/// Longest block decoded at once.
+const MAX_BLOCK_LENGTH: usize = 64;
+const NUM_PAGES: usize = MEM_SIZE/PAGE_SIZE;
+
+pub trait Engine {
+    /// Runs up to `count` instructions, stopping after the first one that
+    /// fails. Returns how many ran, including a failed one, and its error.
+    fn run(&mut self, cpu: &mut Cpu, count: u64) -> (u64, Result<(), String>);
+}
+
+/// Looks up an engine by name: interpreter, cached, or jit when built with
+/// the jit feature on x86-64.
+pub fn by_name(name: &str) -> Result<Box<dyn Engine>, String> {
+    match name {
+        "interpreter" => Ok(Box::new(Interpreter)),
+        "cached" => Ok(Box::new(BlockEngine::new())),
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        "jit" => Ok(Box::new(::jit::JitEngine::new()?)),
+        _ => Err(format!("Unknown engine \"{}\", expected interpreter, cached or jit", name)),
+    }
+}
+
+/// Runs instructions one at a time with `Cpu::exec_instruction`.
+pub struct Interpreter;
+
+impl Engine for Interpreter {
+    fn run(&mut self, cpu: &mut Cpu, count: u64) -> (u64, Result<(), String>) {
+        for ran in 1..count+1 {
+            if let Err(e) = cpu.exec_instruction() {
+                return (ran, Err(e));
+            }
+        }
+        (count, Ok(()))
+    }
+}
+
+/// Whether an instruction can change the flow of control, or write memory
+/// that might hold the rest of the block.
+pub fn ends_block(ins: &Instruction) -> bool {
+    use disasm::Instruction::*;
+    matches!(*ins, Ret | Jp { .. } | Call { .. } | JpV0 { .. } |
+             SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } |
+             Skp { .. } | Sknp { .. } | LdKey { .. } | LdBcd { .. } | Store { .. })
+}
+
+/// Runs cached, pre-decoded basic blocks.
+pub struct BlockEngine {
+    /// Blocks by start address.
+    blocks: Vec<Option<Rc<[Instruction]>>>,
+    /// Start addresses of the blocks decoded from each page.
+    page_blocks: Vec<Vec<u16>>,
+}
+
+impl BlockEngine {
+    pub fn new() -> BlockEngine {
+        BlockEngine {
+            blocks: vec![None; MEM_SIZE],
+            page_blocks: vec![Vec::new(); NUM_PAGES],
+        }
+    }
+
+    /// Throws away blocks decoded from the pages set in `dirty`.
+    fn invalidate(&mut self, dirty: u64) {
+        if dirty == 0 {
+            return;
+        }
+        for page in (0..NUM_PAGES).filter(|page| dirty & (1 << page) != 0) {
+            for start in self.page_blocks[page].drain(..) {
+                self.blocks[start as usize] = None;
+            }
+        }
+    }
+
+    /// The block starting at `pc`, or None if the instruction there can't
+    /// be decoded.
+    fn block_at(&mut self, cpu: &Cpu, pc: u16) -> Option<Rc<[Instruction]>> {
+        let start = pc as usize;
+        if start + 1 >= MEM_SIZE {
+            return None;
+        }
+        if let Some(ref block) = self.blocks[start] {
+            return Some(block.clone());
+        }
+        let mut block = Vec::new();
+        let mut addr = start;
+        while addr + 1 < MEM_SIZE && block.len() < MAX_BLOCK_LENGTH {
+            let ins = match Instruction::decode(cpu.memory.read_u16(addr)) {
+                Some(ins) => ins,
+                None => break,
+            };
+            block.push(ins);
+            if ends_block(&ins) {
+                break;
+            }
+            addr += 2;
+        }
+        if block.is_empty() {
+            return None;
+        }
+        let end = start + block.len()*2 - 1;
+        for page in start/PAGE_SIZE..end/PAGE_SIZE+1 {
+            self.page_blocks[page].push(pc);
+        }
+        let block: Rc<[Instruction]> = block.into();
+        self.blocks[start] = Some(block.clone());
+        Some(block)
+    }
+}
+
+impl Default for BlockEngine {
+    fn default() -> BlockEngine {
+        BlockEngine::new()
+    }
+}
+
+impl Engine for BlockEngine {
+    fn run(&mut self, cpu: &mut Cpu, count: u64) -> (u64, Result<(), String>) {
+        let mut ran = 0;
+        while ran < count {
+            let dirty = cpu.memory.take_dirty_pages();
+            self.invalidate(dirty);
+            let pc = cpu.get_pc();
+            let block = match self.block_at(cpu, pc) {
+                Some(block) => block,
+                None => {
+                    // Let the interpreter report the error
+                    ran += 1;
+                    if let Err(e) = cpu.exec_instruction() {
+                        return (ran, Err(e));
+                    }
+                    continue;
+                }
+            };
+            for &ins in block.iter().take((count - ran) as usize) {
+                ran += 1;
+                if let Err(e) = cpu.exec_decoded(ins) {
+                    return (ran, Err(e));
+                }
+            }
+        }
+        (ran, Ok(()))
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+pub fn random_program(rng: &mut ::rng::XorShift) -> Vec<u8> {
+    //! A random program. Most jumps stay within the program so that it
+    //! runs for a while, the rest go anywhere.
+    let mut program = Vec::new();
+    for _ in 0..128 {
+        let ins = (rng.next_u32() & 0xffff) as u16;
+        let ins = match ins >> 12 {
+            0x0 => if ins & 1 == 0 { 0x00E0 } else { 0x00EE },
+            0x1 | 0x2 if ins & 0x800 == 0 => (ins & 0xf000) | 0x200 | (ins & 0xfe),
+            _ => ins,
+        };
+        program.push((ins >> 8) as u8);
+        program.push(ins as u8);
+    }
+    program
+}
+
+#[test]
+fn test_engines_match(){
+    use rng::XorShift;
+    let mut rng = XorShift::new(1234);
+    let mut engine = BlockEngine::new();
+    for _ in 0..200 {
+        let program = random_program(&mut rng);
+        let seed = rng.next_u32();
+        let keys = rng.next_u32();
+        let mut reference = Cpu::new();
+        let mut cached = Cpu::new();
+        for cpu in [&mut reference, &mut cached].iter_mut() {
+            cpu.memory.load_rom(&program).unwrap();
+            cpu.set_seed(seed);
+            for key in 0..16 {
+                if keys & (1 << key) != 0 {
+                    cpu.keydown(key);
+                }
+            }
+        }
+        // Run in uneven chunks so blocks get split, and carry on past errors
+        for chunk in 0..40 {
+            let (ran_reference, reference_result) = Interpreter.run(&mut reference, chunk % 7 + 1);
+            let (ran_cached, cached_result) = engine.run(&mut cached, chunk % 7 + 1);
+            if ran_reference != ran_cached || reference_result != cached_result ||
+                reference.save_state() != cached.save_state() || reference.get_key_trail(3) != cached.get_key_trail(3) {
+                panic!("Engines differ after chunk {} of {:02x?}: {:?} vs {:?}",
+                       chunk, program, reference_result, cached_result);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! A C API over `Cpu`, for driving the emulator from other languages. The
+//! matching header is `include/chippy.h`, regenerate it with
+//! `cbindgen --config cbindgen.toml --output include/chippy.h src/ffi.rs`
+//! after changing anything here. cbindgen only sees this file, so the sizes
+//! below are spelled out and checked against the real ones at compile time.
+//!
+//! Every function taking a `ChippyCpu` pointer expects one returned by
+//! `chippy_new` that hasn't been passed to `chippy_free` yet.
+
+use std::panic;
+use std::ptr;
+use std::slice;
+use cpu;
+use cpu::Cpu;
+use frontend;
+use mem;
+
+pub const CHIPPY_SCREEN_WIDTH: usize = 64;
+pub const CHIPPY_SCREEN_HEIGHT: usize = 32;
+/// Size of the buffer filled by `chippy_framebuffer`.
+pub const CHIPPY_FRAMEBUFFER_SIZE: usize = 2048;
+/// Size of the buffers used by `chippy_save_state` and `chippy_load_state`.
+pub const CHIPPY_STATE_SIZE: usize = 6199;
+pub const CHIPPY_MEMORY_SIZE: usize = 4096;
+/// How often `chippy_tick_timers` should be called, and the frame rate
+/// the instructions per second are spread over.
+pub const CHIPPY_FRAMES_PER_SECOND: u32 = 60;
+
+const _: () = assert!(CHIPPY_SCREEN_WIDTH == mem::SCREEN_WIDTH && CHIPPY_SCREEN_HEIGHT == mem::SCREEN_HEIGHT);
+const _: () = assert!(CHIPPY_FRAMEBUFFER_SIZE == mem::SCREEN_WIDTH*mem::SCREEN_HEIGHT);
+const _: () = assert!(CHIPPY_STATE_SIZE == cpu::STATE_SIZE);
+const _: () = assert!(CHIPPY_MEMORY_SIZE == mem::MEM_SIZE);
+const _: () = assert!(CHIPPY_FRAMES_PER_SECOND as i64 == frontend::FRAMES_PER_SECOND);
+
+/// Creates a new machine with nothing loaded.
+#[no_mangle]
+pub extern "C" fn chippy_new() -> *mut Cpu {
+    Box::into_raw(Box::new(Cpu::new()))
+}
+
+/// Frees a machine. Does nothing if `cpu` is null.
+///
+/// # Safety
+/// `cpu` must be null or come from `chippy_new`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_free(cpu: *mut Cpu) {
+    if !cpu.is_null() {
+        drop(Box::from_raw(cpu));
+    }
+}
+
+/// Resets the machine, clearing memory and registers.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_reset(cpu: *mut Cpu) {
+    (*cpu).reset();
+}
+
+/// Resets the machine and loads `len` bytes of ROM at 0x200. Returns false
+/// if the ROM doesn't fit.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new` and `rom` must point to
+/// `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_load_rom(cpu: *mut Cpu, rom: *const u8, len: usize) -> bool {
+    (*cpu).reset();
+    (*cpu).memory.load_rom(slice::from_raw_parts(rom, len)).is_ok()
+}
+
+/// Executes up to `count` instructions, stopping early if one fails.
+/// Returns how many were executed successfully. A panic in the emulator
+/// counts as a failure rather than unwinding into the caller.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_step(cpu: *mut Cpu, count: u32) -> u32 {
+    let mut done = 0;
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        while done < count && (*cpu).exec_instruction().is_ok() {
+            done += 1;
+        }
+    }));
+    done
+}
+
+/// Counts the delay and sound timers down once, as happens at 60Hz.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_tick_timers(cpu: *mut Cpu) {
+    (*cpu).decr_dt();
+    (*cpu).decr_st();
+}
+
+/// How many instructions the machine runs a second.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_get_ips(cpu: *const Cpu) -> u32 {
+    (*cpu).get_ips() as u32
+}
+
+/// Sets how many instructions the machine runs a second, clamped to the
+/// range the front ends allow.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_set_ips(cpu: *mut Cpu, ips: u32) {
+    (*cpu).set_ips(ips as u64);
+}
+
+/// Presses or releases one of the 16 keys. Out of range keys are ignored.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_set_key(cpu: *mut Cpu, key: u8, down: bool) {
+    if key < 16 {
+        if down {
+            (*cpu).keydown(key);
+        } else {
+            (*cpu).keyup(key);
+        }
+    }
+}
+
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_get_pc(cpu: *const Cpu) -> u16 {
+    (*cpu).get_pc()
+}
+
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_get_i(cpu: *const Cpu) -> u16 {
+    (*cpu).get_i()
+}
+
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_get_sp(cpu: *const Cpu) -> u8 {
+    (*cpu).get_sp() as u8
+}
+
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_get_dt(cpu: *const Cpu) -> u8 {
+    (*cpu).get_dt()
+}
+
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_get_st(cpu: *const Cpu) -> u8 {
+    (*cpu).get_st()
+}
+
+/// Reads register V0 to VF. Returns 0 for out of range registers.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_get_reg(cpu: *const Cpu, reg: u8) -> u8 {
+    if reg < 16 { (*cpu).get_reg(reg) } else { 0 }
+}
+
+/// Sets PC. Returns false, leaving it alone, if a whole instruction
+/// wouldn't fit at `pc`.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_set_pc(cpu: *mut Cpu, pc: u16) -> bool {
+    if pc as usize + 1 >= mem::MEM_SIZE {
+        return false;
+    }
+    (*cpu).set_pc(pc);
+    true
+}
+
+/// Sets I. Returns false, leaving it alone, if `i` is past the end of
+/// memory.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_set_i(cpu: *mut Cpu, i: u16) -> bool {
+    if i as usize >= mem::MEM_SIZE {
+        return false;
+    }
+    (*cpu).set_i(i);
+    true
+}
+
+/// Sets register V0 to VF. Out of range registers are ignored.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_set_reg(cpu: *mut Cpu, reg: u8, val: u8) {
+    if reg < 16 {
+        (*cpu).set_reg(reg, val);
+    }
+}
+
+/// Copies `len` bytes of memory starting at `addr` into `out`. Returns false,
+/// copying nothing, if the range runs past the end of memory.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new` and `out` must point to
+/// `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_read_memory(cpu: *const Cpu, addr: u16, out: *mut u8, len: usize) -> bool {
+    if addr as usize + len > mem::MEM_SIZE {
+        return false;
+    }
+    let data = (*cpu).memory.get_vec(addr as usize, len);
+    ptr::copy_nonoverlapping(data.as_ptr(), out, len);
+    true
+}
+
+/// Copies `len` bytes from `data` into memory starting at `addr`. Returns
+/// false, writing nothing, if the range runs past the end of memory.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new` and `data` must point to
+/// `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_write_memory(cpu: *mut Cpu, addr: u16, data: *const u8, len: usize) -> bool {
+    if addr as usize + len > mem::MEM_SIZE {
+        return false;
+    }
+    (*cpu).memory.memset(addr as usize, &slice::from_raw_parts(data, len).to_vec());
+    true
+}
+
+/// Copies the screen into `out`, row by row with one byte per pixel (0 or
+/// 1). `out` must hold `CHIPPY_FRAMEBUFFER_SIZE` bytes.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new` and `out` must point to
+/// `CHIPPY_FRAMEBUFFER_SIZE` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_framebuffer(cpu: *const Cpu, out: *mut u8) {
+    let frame = (*cpu).memory.framebuffer();
+    ptr::copy_nonoverlapping(frame.as_ptr(), out, frame.len());
+}
+
+/// Whether the buzzer should be sounding.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_sound_active(cpu: *const Cpu) -> bool {
+    (*cpu).get_st_active()
+}
+
+/// Writes the machine state to `out`, which must hold `CHIPPY_STATE_SIZE`
+/// bytes.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new` and `out` must point to
+/// `CHIPPY_STATE_SIZE` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_save_state(cpu: *const Cpu, out: *mut u8) {
+    let state = (*cpu).save_state();
+    ptr::copy_nonoverlapping(state.as_ptr(), out, state.len());
+}
+
+/// Restores a state written by `chippy_save_state`. Returns false, leaving
+/// the machine untouched, if the state is invalid.
+///
+/// # Safety
+/// `cpu` must be a live pointer from `chippy_new` and `state` must point to
+/// `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chippy_load_state(cpu: *mut Cpu, state: *const u8, len: usize) -> bool {
+    (*cpu).load_state(slice::from_raw_parts(state, len)).is_ok()
+}
+
+//TESTS
+
+#[test]
+fn test_ffi(){
+    // LD V3, 0x2A; LD I, 0x300; LD [I], V3 (stores V0..V3); then an invalid 0x0000
+    let rom = [0x63u8, 0x2A, 0xA3, 0x00, 0xF3, 0x55];
+    unsafe {
+        let cpu = chippy_new();
+        if !chippy_load_rom(cpu, rom.as_ptr(), rom.len()) {
+            panic!("Rom should load");
+        }
+        if chippy_step(cpu, 10) != 3 {
+            panic!("Step should stop at the invalid instruction");
+        }
+        if chippy_get_reg(cpu, 3) != 0x2A || chippy_get_i(cpu) != 0x300 || chippy_get_pc(cpu) != 0x206 {
+            panic!("Registers not updated");
+        }
+        let mut out = [0u8; 4];
+        if !chippy_read_memory(cpu, 0x300, out.as_mut_ptr(), 4) || out != [0, 0, 0, 0x2A] {
+            panic!("Memory not readable: {:?}", out);
+        }
+        if chippy_read_memory(cpu, 0xFFF, out.as_mut_ptr(), 4) {
+            panic!("Reads past the end of memory should fail");
+        }
+        if chippy_set_pc(cpu, 0xFFF) || chippy_set_i(cpu, 0x1000) || !chippy_set_i(cpu, 0xFFF) {
+            panic!("PC and I should be limited to memory");
+        }
+        let mut state = vec![0u8; CHIPPY_STATE_SIZE];
+        chippy_save_state(cpu, state.as_mut_ptr());
+        chippy_reset(cpu);
+        if !chippy_load_state(cpu, state.as_ptr(), state.len()) || chippy_get_reg(cpu, 3) != 0x2A {
+            panic!("State not restored");
+        }
+        chippy_free(cpu);
+    }
+}
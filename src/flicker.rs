@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+const DEFAULT_DECAY_PERCENT: u8 = 60;
+const DEFAULT_BLEND_FRAMES: usize = 3;
+const MAX_BLEND_FRAMES: usize = 16;
+
+/// Ways of hiding the flicker caused by games erasing and redrawing sprites.
+///
+/// Every mode turns a sequence of framebuffers (one byte per pixel, non-zero
+/// meaning lit) into per-pixel intensities from 0 to 255.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlickerMode {
+    /// Show each frame exactly as it is.
+    #[default]
+    Off,
+    /// Lit pixels fade out like a phosphor, keeping `percent` of their
+    /// brightness every frame.
+    Decay { percent: u8 },
+    /// Average the last `frames` frames.
+    Blend { frames: usize },
+    /// Show pixels that were lit at the end of this frame or the previous one,
+    /// so sprites that are erased just before a frame ends don't disappear.
+    Hold,
+}
+
+impl FlickerMode {
+    /// Parses `off`, `decay[:percent]`, `blend[:frames]` or `hold`.
+    pub fn parse(spec: &str) -> Result<FlickerMode, String> {
+        let spec = spec.trim();
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap();
+        let arg = parts.next();
+        let parse_arg = |default: usize, max: usize| -> Result<usize, String> {
+            match arg {
+                None => Ok(default),
+                Some(v) => match v.parse::<usize>() {
+                    Ok(v) if v >= 1 && v <= max => Ok(v),
+                    _ => Err(format!("Invalid argument in flicker mode \"{}\", expected 1 to {}", spec, max)),
+                },
+            }
+        };
+        match name {
+            "off"   if arg.is_none() => Ok(FlickerMode::Off),
+            "hold"  if arg.is_none() => Ok(FlickerMode::Hold),
+            "decay" => Ok(FlickerMode::Decay {
+                percent: parse_arg(DEFAULT_DECAY_PERCENT as usize, 99)? as u8
+            }),
+            "blend" => Ok(FlickerMode::Blend {
+                frames: parse_arg(DEFAULT_BLEND_FRAMES, MAX_BLEND_FRAMES)?
+            }),
+            _ => Err(format!("Unknown flicker mode \"{}\", expected off, decay[:percent], blend[:frames] or hold", spec)),
+        }
+    }
+
+    /// The mode selected after this one by the runtime hotkey.
+    pub fn next(&self) -> FlickerMode {
+        match *self {
+            FlickerMode::Off => FlickerMode::Decay { percent: DEFAULT_DECAY_PERCENT },
+            FlickerMode::Decay {..} => FlickerMode::Blend { frames: DEFAULT_BLEND_FRAMES },
+            FlickerMode::Blend {..} => FlickerMode::Hold,
+            FlickerMode::Hold => FlickerMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for FlickerMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FlickerMode::Off => write!(f, "off"),
+            FlickerMode::Decay { percent } => write!(f, "decay:{}", percent),
+            FlickerMode::Blend { frames } => write!(f, "blend:{}", frames),
+            FlickerMode::Hold => write!(f, "hold"),
+        }
+    }
+}
+
+pub struct FlickerFilter {
+    mode: FlickerMode,
+    history: VecDeque<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl FlickerFilter {
+    pub fn new(mode: FlickerMode) -> FlickerFilter {
+        FlickerFilter {
+            mode,
+            history: VecDeque::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> FlickerMode {
+        self.mode
+    }
+
+    /// Switches mode, forgetting any previous frames.
+    pub fn set_mode(&mut self, mode: FlickerMode) {
+        self.mode = mode;
+        self.history.clear();
+        self.output.clear();
+    }
+
+    /// Feeds the framebuffer at the end of a frame through the filter and
+    /// returns the intensity of each pixel.
+    pub fn apply(&mut self, frame: &[u8]) -> &[u8] {
+        if self.output.len() != frame.len() {
+            self.history.clear();
+            self.output = vec![0u8; frame.len()];
+        }
+        let lit = |v: u8| if v != 0 { 255u8 } else { 0u8 };
+        match self.mode {
+            FlickerMode::Off => {
+                for (out, &v) in self.output.iter_mut().zip(frame) {
+                    *out = lit(v);
+                }
+            },
+            FlickerMode::Decay { percent } => {
+                for (out, &v) in self.output.iter_mut().zip(frame) {
+                    let faded = ((*out as u32)*(percent as u32)/100) as u8;
+                    *out = faded.max(lit(v));
+                }
+            },
+            FlickerMode::Blend { frames } => {
+                self.push_history(frame, frames);
+                let count = self.history.len() as u32;
+                for (i, out) in self.output.iter_mut().enumerate() {
+                    let total: u32 = self.history.iter().map(|f| lit(f[i]) as u32).sum();
+                    *out = (total/count) as u8;
+                }
+            },
+            FlickerMode::Hold => {
+                self.push_history(frame, 2);
+                for (i, out) in self.output.iter_mut().enumerate() {
+                    *out = self.history.iter().map(|f| lit(f[i])).max().unwrap_or(0);
+                }
+            },
+        }
+        &self.output
+    }
+
+    fn push_history(&mut self, frame: &[u8], frames: usize) {
+        while self.history.len() >= frames {
+            self.history.pop_front();
+        }
+        self.history.push_back(frame.to_vec());
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_parse_flicker_mode(){
+    let cases = [
+        ("off", FlickerMode::Off),
+        ("hold", FlickerMode::Hold),
+        ("decay", FlickerMode::Decay { percent: DEFAULT_DECAY_PERCENT }),
+        ("decay:25", FlickerMode::Decay { percent: 25 }),
+        ("blend", FlickerMode::Blend { frames: DEFAULT_BLEND_FRAMES }),
+        ("blend:4", FlickerMode::Blend { frames: 4 }),
+    ];
+    for &(spec, mode) in cases.iter() {
+        match FlickerMode::parse(spec) {
+            Ok(ref parsed) if *parsed == mode => (),
+            other => panic!("Parsing \"{}\" gave {:?}, expected {:?}", spec, other, mode),
+        }
+        if FlickerMode::parse(&mode.to_string()) != Ok(mode) {
+            panic!("{:?} did not round trip through Display", mode);
+        }
+    }
+    for bad in ["sparkle", "off:2", "decay:100", "blend:0", "blend:x"].iter() {
+        if FlickerMode::parse(bad).is_ok() {
+            panic!("Flicker mode \"{}\" should not have parsed", bad);
+        }
+    }
+}
+
+#[test]
+fn test_flicker_off(){
+    let mut filter = FlickerFilter::new(FlickerMode::Off);
+    if filter.apply(&[0, 1, 0, 1]) != [0, 255, 0, 255] {
+        panic!("Off mode should pass frames through unchanged");
+    }
+    if filter.apply(&[1, 0, 0, 0]) != [255, 0, 0, 0] {
+        panic!("Off mode should not remember earlier frames");
+    }
+}
+
+#[test]
+fn test_flicker_decay(){
+    let mut filter = FlickerFilter::new(FlickerMode::Decay { percent: 50 });
+    filter.apply(&[1, 0]);
+    if filter.apply(&[0, 1]) != [127, 255] {
+        panic!("Pixel should have faded to half brightness");
+    }
+    if filter.apply(&[0, 0]) != [63, 127] {
+        panic!("Pixels should keep fading");
+    }
+    if filter.apply(&[1, 0]) != [255, 63] {
+        panic!("Relit pixel should return to full brightness");
+    }
+}
+
+#[test]
+fn test_flicker_blend(){
+    let mut filter = FlickerFilter::new(FlickerMode::Blend { frames: 2 });
+    if filter.apply(&[1, 0]) != [255, 0] {
+        panic!("First frame should be shown as is");
+    }
+    if filter.apply(&[0, 0]) != [127, 0] {
+        panic!("Two frames should have been averaged");
+    }
+    if filter.apply(&[0, 1]) != [0, 127] {
+        panic!("Frames older than the blend window should be dropped");
+    }
+}
+
+#[test]
+fn test_flicker_hold(){
+    let mut filter = FlickerFilter::new(FlickerMode::Hold);
+    filter.apply(&[1, 0, 0]);
+    //sprite erased at the end of this frame, redrawn in the next
+    if filter.apply(&[0, 0, 1]) != [255, 0, 255] {
+        panic!("Pixel lit in the previous frame should be held");
+    }
+    if filter.apply(&[0, 1, 1]) != [0, 255, 255] {
+        panic!("Pixel should only be held for a single frame");
+    }
+}
+
+#[test]
+fn test_flicker_set_mode_resets(){
+    let mut filter = FlickerFilter::new(FlickerMode::Decay { percent: 90 });
+    filter.apply(&[1]);
+    filter.set_mode(FlickerMode::Decay { percent: 90 });
+    if filter.apply(&[0]) != [0] {
+        panic!("Changing mode should forget earlier frames");
+    }
+}
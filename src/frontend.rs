@@ -0,0 +1,211 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use cpu::Cpu;
+use engine::{Engine, Interpreter};
+use io;
+
+pub const FRAMES_PER_SECOND: i64 = 60;
+
+/// Actions that an input source can ask for. Emulator level actions are
+/// handled by the main loop, the rest are passed on to the video sink.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Quit,
+    IncreaseSpeed,
+    DecreaseSpeed,
+    Reset,
+    CyclePalette,
+    CycleFlicker,
+    Screenshot,
+    /// Moves through a list, such as the launcher's.
+    Up,
+    Down,
+    /// Picks the selected item in a list.
+    Select,
+}
+
+/// Somewhere to show the Chip8 display, e.g. an SDL window or a terminal.
+pub trait VideoSink {
+    /// Draws the current state of the machine, once per frame.
+    fn draw(&mut self, cpu: &Cpu);
+    /// Handles display related commands such as changing palette.
+    fn handle_command(&mut self, _command: Command) {}
+    /// Shows extra lines of text from hooks, e.g. a script's memory display.
+    fn set_overlay(&mut self, _lines: &[String]) {}
+}
+
+/// Somewhere to play the Chip8 buzzer.
+pub trait AudioSink {
+    /// Turns the tone on or off, called once per frame.
+    fn set_tone(&mut self, on: bool);
+}
+
+/// Where key presses and other user input come from.
+pub trait InputSource {
+    /// Handles pending input. Chip8 key presses are passed straight to
+    /// `cpu`, everything else is returned as a `Command`.
+    fn poll_events(&mut self, cpu: &mut Cpu) -> Vec<Command>;
+}
+
+/// A source of time for pacing frames.
+pub trait Clock {
+    /// Milliseconds since some fixed point in time.
+    fn ticks(&mut self) -> u32;
+    /// Waits for `ms` milliseconds.
+    fn delay(&mut self, ms: u32);
+}
+
+/// Something that watches or changes the machine as it runs, such as a
+/// script. Memory writes made by the last instruction can be read from
+/// `cpu.memory.writes()` once the hook has turned the journal on.
+pub trait Hook {
+    fn before_instruction(&mut self, _cpu: &mut Cpu) {}
+    fn after_instruction(&mut self, _cpu: &mut Cpu) {}
+    /// Called once per frame, after the instructions for the frame have run
+    /// and before it is drawn.
+    fn end_frame(&mut self, _cpu: &mut Cpu) {}
+    /// Lines of text to show alongside the screen.
+    fn overlay(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Whether the emulator should stop, checked once per frame.
+    fn quit_requested(&self) -> bool {
+        false
+    }
+    /// Called when the emulator stops, e.g. to write out a report.
+    fn finish(&mut self, _cpu: &mut Cpu) {}
+}
+
+/// The set of back ends that the emulator runs on.
+pub struct Frontend<V, A, I, C> {
+    pub video: V,
+    pub audio: A,
+    pub input: I,
+    pub clock: C,
+}
+
+/// An audio sink that ignores the buzzer.
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn set_tone(&mut self, _on: bool) {}
+}
+
+/// A clock following the system's monotonic time.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn ticks(&mut self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+    fn delay(&mut self, ms: u32) {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
+
+pub fn main_loop<V, A, I, C>(frontend: &mut Frontend<V, A, I, C>,
+                             cpu: &mut Cpu,
+                             file_name: &str,
+                             hooks: &mut [Box<dyn Hook>])
+    where V: VideoSink, A: AudioSink, I: InputSource, C: Clock
+{
+    run(frontend, cpu, |cpu| io::load_rom(cpu, file_name), hooks, &mut Interpreter);
+}
+
+/// Runs the emulator with the given execution engine. `load` puts the ROM
+/// in memory, at the start and again on reset. Hooks see every instruction,
+/// so with any hooks the engine is only asked for one at a time.
+pub fn run<V, A, I, C, L>(frontend: &mut Frontend<V, A, I, C>,
+                          cpu: &mut Cpu,
+                          load: L,
+                          hooks: &mut [Box<dyn Hook>],
+                          engine: &mut dyn Engine)
+    where V: VideoSink, A: AudioSink, I: InputSource, C: Clock, L: Fn(&mut Cpu)
+{
+    let num_frames = 0i64;
+
+    load(cpu);
+
+    'main: loop {
+        let instructions_per_second = cpu.get_ips() as i64;
+        //timing stuff
+        //Note: This will probably break pretty badly if the program starts lagging heavily
+        let start_frame_time = frontend.clock.ticks() as i64;
+        let frame_expected_time =
+            ((num_frames+1)*1000)/FRAMES_PER_SECOND-(num_frames*1000)/FRAMES_PER_SECOND;
+        let end_frame_time = frame_expected_time + start_frame_time;
+        let instructions_by_frame_end = (frame_expected_time*instructions_per_second)/1000;
+
+        for command in frontend.input.poll_events(cpu) {
+            match command {
+                Command::Quit          => break 'main,
+                Command::IncreaseSpeed => cpu.increase_ips(),
+                Command::DecreaseSpeed => cpu.decrease_ips(),
+                Command::Reset         => {
+                    cpu.reset();
+                    load(cpu);
+                }
+                command => frontend.video.handle_command(command),
+            }
+        }
+        if hooks.is_empty() {
+            let mut remaining = instructions_by_frame_end as u64;
+            while remaining > 0 {
+                let (ran, result) = engine.run(cpu, remaining);
+                remaining -= ran;
+                if let Err(v) = result {
+                    println!("Error in cpu.exec_instruction(): {}", v);
+                }
+            }
+        } else {
+            for _ in 0..instructions_by_frame_end {
+                cpu.memory.clear_writes();
+                for hook in hooks.iter_mut() {
+                    hook.before_instruction(cpu);
+                }
+                if let Err(v) = engine.run(cpu, 1).1 {
+                    println!("Error in cpu.exec_instruction(): {}", v);
+                }
+                for hook in hooks.iter_mut() {
+                    hook.after_instruction(cpu);
+                }
+            }
+        }
+        if !hooks.is_empty() {
+            let mut overlay = Vec::new();
+            for hook in hooks.iter_mut() {
+                hook.end_frame(cpu);
+                overlay.extend(hook.overlay());
+            }
+            frontend.video.set_overlay(&overlay);
+            if hooks.iter().any(|hook| hook.quit_requested()) {
+                break 'main;
+            }
+        }
+        frontend.video.draw(cpu);
+
+        //timing stuff
+        let current_time = frontend.clock.ticks();
+        frontend.clock.delay((end_frame_time as u32).saturating_sub(current_time));
+        cpu.decr_dt();
+        cpu.decr_st();
+        frontend.audio.set_tone(cpu.get_st_active());
+    }
+    for hook in hooks.iter_mut() {
+        hook.finish(cpu);
+    }
+}
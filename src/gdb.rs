@@ -0,0 +1,571 @@
+//! A GDB remote serial protocol stub, so ROMs can be debugged from GDB or
+//! any other front end that speaks RSP. Start chippy with `--gdb 127.0.0.1:1234`
+//! (or `--gdb unix:/path/to/socket`) and it waits for a debugger to connect
+//! before running the ROM, stopped at the first instruction.
+//!
+//! Registers are numbered V0-VF (0-15, 8 bits), I (16, 16 bits), PC (17, 16
+//! bits), SP (18), DT (19) and ST (20), all 8 bits. 16 bit registers are sent
+//! little endian, as GDB expects. The layout is also described by the
+//! `target.xml` served through `qXfer:features:read`. Memory is the 4K of
+//! Chip8 RAM.
+//!
+//! `monitor coverage` shows a colour coded map of the memory used so far,
+//! see `coverage`, and `monitor coverage reset` starts it again.
+
+use std::collections::HashSet;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use coverage;
+use cpu::Cpu;
+use frontend::Hook;
+use mem::MEM_SIZE;
+
+const NUM_REGISTERS: usize = 21;
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const REG_DT: usize = 19;
+const REG_ST: usize = 20;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chippy.chip8">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// A socket the debugger is connected through.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl<C: Connection + ?Sized> Connection for Box<C> {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Waits for a debugger to connect to `address`, which is either
+/// `host:port` or `unix:<path>`.
+pub fn listen(address: &str) -> io::Result<Box<dyn Connection>> {
+    println!("Waiting for a debugger on {} ...", address);
+    #[cfg(unix)]
+    {
+        if let Some(path) = address.strip_prefix("unix:") {
+            let listener = UnixListener::bind(path)?;
+            let (stream, _) = listener.accept()?;
+            println!("Debugger connected");
+            return Ok(Box::new(stream));
+        }
+    }
+    let listener = TcpListener::bind(address)?;
+    let (stream, peer) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    println!("Debugger connected from {}", peer);
+    Ok(Box::new(stream))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RunState {
+    Stopped,
+    Stepping,
+    Running,
+    Detached,
+    Killed,
+}
+
+/// Hands control to the debugger whenever the machine stops.
+pub struct GdbStub<C: Connection> {
+    connection: C,
+    state: RunState,
+    breakpoints: HashSet<u16>,
+    /// Don't stop at a breakpoint on this address, as we are continuing
+    /// from it.
+    resume_from: Option<u16>,
+    no_ack: bool,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i+2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+/// Parses `addr,len`.
+fn parse_range(spec: &str) -> Option<(usize, usize)> {
+    let (addr, len) = spec.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn read_register(cpu: &Cpu, reg: usize) -> Vec<u8> {
+    match reg {
+        0..=15 => vec![cpu.get_reg(reg as u8)],
+        REG_I => cpu.get_i().to_le_bytes().to_vec(),
+        REG_PC => cpu.get_pc().to_le_bytes().to_vec(),
+        REG_SP => vec![cpu.get_sp() as u8],
+        REG_DT => vec![cpu.get_dt()],
+        REG_ST => vec![cpu.get_st()],
+        _ => Vec::new(),
+    }
+}
+
+fn valid_i(i: u16) -> bool {
+    (i as usize) < MEM_SIZE
+}
+
+/// Whether a whole instruction fits at `pc`.
+fn valid_pc(pc: u16) -> bool {
+    pc as usize + 1 < MEM_SIZE
+}
+
+/// Whether I and PC are in range in a `G` packet's data, where they follow
+/// the one byte V registers.
+fn registers_valid(data: &[u8]) -> bool {
+    let wide = |offset: usize| data.get(offset..offset + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    wide(REG_I).is_none_or(valid_i) && wide(REG_I + 2).is_none_or(valid_pc)
+}
+
+/// Writes a register from the start of `data`, returning how many bytes it
+/// used, or None if there isn't enough data or the value is out of range.
+fn write_register(cpu: &mut Cpu, reg: usize, data: &[u8]) -> Option<usize> {
+    let wide = || if data.len() >= 2 { Some(u16::from_le_bytes([data[0], data[1]])) } else { None };
+    let narrow = data.first().cloned();
+    match reg {
+        0..=15 => cpu.set_reg(reg as u8, narrow?),
+        REG_I => { cpu.set_i(wide().filter(|&i| valid_i(i))?); return Some(2) }
+        REG_PC => { cpu.set_pc(wide().filter(|&pc| valid_pc(pc))?); return Some(2) }
+        REG_SP => cpu.set_sp(narrow? as usize).ok()?,
+        REG_DT => cpu.set_dt(narrow?),
+        REG_ST => cpu.set_st(narrow?),
+        _ => return None,
+    }
+    Some(1)
+}
+
+/// Runs a `monitor` command, returning its output.
+fn monitor_command(cpu: &mut Cpu, command: &str) -> String {
+    match command {
+        "coverage" => match cpu.memory.coverage() {
+            Some(coverage) => coverage::memory_map(coverage, 0, true),
+            None => "Coverage isn't being tracked\n".to_string(),
+        },
+        "coverage reset" => {
+            cpu.memory.set_coverage(true);
+            "Coverage cleared\n".to_string()
+        }
+        _ => "Commands: coverage, coverage reset\n".to_string(),
+    }
+}
+
+impl<C: Connection> GdbStub<C> {
+    /// Creates a stub that stops before the first instruction.
+    pub fn new(connection: C) -> GdbStub<C> {
+        GdbStub {
+            connection,
+            state: RunState::Stopped,
+            breakpoints: HashSet::new(),
+            resume_from: None,
+            no_ack: false,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8];
+        self.connection.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Reads the next packet, acknowledging it unless in no-ack mode.
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            // Skip acks and stray interrupts until the start of a packet
+            while self.read_byte()? != b'$' {}
+            let mut data = Vec::new();
+            let mut checksum = 0u8;
+            loop {
+                let byte = self.read_byte()?;
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                data.push(byte);
+            }
+            let sent = [self.read_byte()?, self.read_byte()?];
+            let valid = ::std::str::from_utf8(&sent).ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok()) == Some(checksum);
+            if self.no_ack {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            if valid {
+                self.connection.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        loop {
+            write!(self.connection, "${}#{:02x}", data, checksum)?;
+            self.connection.flush()?;
+            if self.no_ack || self.read_byte()? == b'+' {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Tells the debugger the machine stopped and serves its requests until
+    /// it resumes. The initial stop isn't announced, the debugger asks with
+    /// `?` once connected.
+    fn stop(&mut self, cpu: &mut Cpu, signal: Option<u8>) {
+        self.state = RunState::Stopped;
+        let announced = match signal {
+            Some(signal) => self.send_packet(&format!("S{:02x}", signal)),
+            None => Ok(()),
+        };
+        let result = announced.and_then(|_| self.serve(cpu));
+        if let Err(e) = result {
+            println!("Lost connection to the debugger: {}", e);
+            self.state = RunState::Detached;
+        }
+    }
+
+    fn serve(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        while self.state == RunState::Stopped {
+            let packet = self.read_packet()?;
+            let reply = self.handle_packet(cpu, &packet);
+            if let Some(reply) = reply {
+                self.send_packet(&reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one packet, returning the reply, or None if the reply comes
+    /// later (when resuming).
+    fn handle_packet(&mut self, cpu: &mut Cpu, packet: &str) -> Option<String> {
+        let error = "E01".to_string();
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => to_hex(&(0..NUM_REGISTERS).flat_map(|reg| read_register(cpu, reg)).collect::<Vec<u8>>()),
+            "G" => match from_hex(args) {
+                Some(data) if registers_valid(&data) => {
+                    let mut offset = 0;
+                    for reg in 0..NUM_REGISTERS {
+                        match write_register(cpu, reg, &data[offset.min(data.len())..]) {
+                            Some(used) => offset += used,
+                            None => break,
+                        }
+                    }
+                    "OK".to_string()
+                }
+                _ => error,
+            },
+            "p" => match parse_hex(args) {
+                Some(reg) if reg < NUM_REGISTERS => to_hex(&read_register(cpu, reg)),
+                _ => error,
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(reg, value)| Some((parse_hex(reg)?, from_hex(value)?)));
+                match parsed.and_then(|(reg, value)| write_register(cpu, reg, &value)) {
+                    Some(_) => "OK".to_string(),
+                    None => error,
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) if addr.checked_add(len).is_some_and(|end| end <= MEM_SIZE) => to_hex(&cpu.memory.get_vec(addr, len)),
+                _ => error,
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, from_hex(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len && addr.checked_add(len).is_some_and(|end| end <= MEM_SIZE) => {
+                        // Written like a script's pokes, so that the program isn't
+                        // seen making these writes
+                        for (offset, &byte) in data.iter().enumerate() {
+                            cpu.memory.poke(addr + offset, byte);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => error,
+                }
+            }
+            "Z" | "z" => {
+                // Software and hardware breakpoints are the same thing here
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                match (kind, parts.next().and_then(parse_hex)) {
+                    (Some("0"), Some(addr)) | (Some("1"), Some(addr)) if addr < MEM_SIZE => {
+                        if command == "Z" {
+                            self.breakpoints.insert(addr as u16);
+                        } else {
+                            self.breakpoints.remove(&(addr as u16));
+                        }
+                        "OK".to_string()
+                    }
+                    (Some("0"), _) | (Some("1"), _) => error,
+                    // Watchpoints aren't supported
+                    _ => String::new(),
+                }
+            }
+            "c" | "s" => {
+                match parse_hex(args) {
+                    Some(addr) if addr < MEM_SIZE - 1 => cpu.set_pc(addr as u16),
+                    Some(_) => return Some(error),
+                    None => (),
+                }
+                self.resume_from = Some(cpu.get_pc());
+                self.state = if command == "c" { RunState::Running } else { RunState::Stepping };
+                return None;
+            }
+            "D" => {
+                self.breakpoints.clear();
+                self.state = RunState::Detached;
+                "OK".to_string()
+            }
+            "k" => {
+                self.state = RunState::Killed;
+                return None;
+            }
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => {
+                "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+".to_string()
+            }
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "q" if args.starts_with("Rcmd,") => {
+                match from_hex(&args["Rcmd,".len()..]).map(|c| String::from_utf8_lossy(&c).into_owned()) {
+                    Some(command) => to_hex(monitor_command(cpu, command.trim()).as_bytes()),
+                    None => error,
+                }
+            }
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                match parse_range(&args["Xfer:features:read:target.xml:".len()..]) {
+                    Some((offset, len)) => {
+                        let xml = TARGET_XML.as_bytes();
+                        let start = offset.min(xml.len());
+                        let end = (offset + len).min(xml.len());
+                        let chunk = String::from_utf8_lossy(&xml[start..end]);
+                        format!("{}{}", if end == xml.len() { "l" } else { "m" }, chunk)
+                    }
+                    None => error,
+                }
+            }
+            "Q" if args == "StartNoAckMode" => {
+                // The OK is still acknowledged, no-ack starts after it
+                let sent = self.send_packet("OK");
+                self.no_ack = true;
+                return if sent.is_ok() { None } else { Some(error) };
+            }
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    /// Checks for a Ctrl-C from the debugger without blocking.
+    fn interrupted(&mut self) -> bool {
+        if self.connection.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut byte = [0u8];
+        let result = self.connection.read(&mut byte);
+        let _ = self.connection.set_nonblocking(false);
+        matches!(result, Ok(1) if byte[0] == 0x03)
+    }
+}
+
+impl<C: Connection> Hook for GdbStub<C> {
+    fn before_instruction(&mut self, cpu: &mut Cpu) {
+        if cpu.memory.coverage().is_none() {
+            cpu.memory.set_coverage(true);
+        }
+        let pc = cpu.get_pc();
+        let resuming = self.resume_from.take() == Some(pc);
+        match self.state {
+            RunState::Stopped => self.stop(cpu, None),
+            RunState::Stepping if !resuming => self.stop(cpu, Some(SIGTRAP)),
+            RunState::Running if !resuming && self.breakpoints.contains(&pc) => self.stop(cpu, Some(SIGTRAP)),
+            _ => (),
+        }
+    }
+
+    fn end_frame(&mut self, cpu: &mut Cpu) {
+        if self.state == RunState::Running && self.interrupted() {
+            self.stop(cpu, Some(SIGINT));
+        }
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.state == RunState::Killed
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+fn rsp_client(port: u16) -> Vec<String> {
+    //! Connects to the stub and runs a scripted session, returning the replies.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut replies = Vec::new();
+    let mut no_ack = false;
+    let mut exchange = |stream: &mut TcpStream, packet: &str, expect_reply: bool, no_ack: bool| {
+        let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+        let mut byte = [0u8];
+        if !no_ack {
+            stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+        }
+        if !expect_reply {
+            return;
+        }
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        if !no_ack {
+            stream.write_all(b"+").unwrap();
+        }
+        replies.push(String::from_utf8(reply).unwrap());
+    };
+    for packet in ["qSupported:swbreak+", "?", "QStartNoAckMode"].iter() {
+        exchange(&mut stream, packet, true, no_ack);
+    }
+    no_ack = true;
+    for packet in ["qXfer:features:read:target.xml:0,14", "g", "m200,4", "M300,2:abcd", "m300,2",
+                   "Z0,206,2", "c", "p11", "qRcmd,636f766572616765", "z0,206,2", "s", "p11", "P3=2a", "p3", "m1000,1",
+                   "mffffffffffffffff,2", "P11=ff0f", "P10=0010", "cfff"].iter() {
+        exchange(&mut stream, packet, true, no_ack);
+    }
+    exchange(&mut stream, &format!("G{}0010", "00".repeat(16)), true, no_ack);
+    exchange(&mut stream, "k", false, no_ack);
+    replies
+}
+
+#[test]
+fn test_gdb_stub(){
+    use std::thread;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let client = thread::spawn(move || rsp_client(port));
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut stub = GdbStub::new(stream);
+
+    let mut cpu = Cpu::new();
+    // LD V0, 1; LD V1, 2; LD V2, 3; JP 0x206
+    cpu.memory.memset(0x200, &vec![0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0x12, 0x06]);
+    cpu.memory.set_journal(true);
+    for _ in 0..100 {
+        stub.before_instruction(&mut cpu);
+        if stub.quit_requested() {
+            break;
+        }
+        cpu.exec_instruction().unwrap();
+    }
+    if !stub.quit_requested() {
+        panic!("Kill packet should stop the emulator");
+    }
+    if !cpu.memory.writes().is_empty() {
+        panic!("M packets shouldn't be journalled: {:?}", cpu.memory.writes());
+    }
+
+    let replies = client.join().unwrap();
+    let expected = [
+        "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+",
+        "S05",
+        "OK",
+        "m<?xml version=\"1.0\"?",
+        &format!("{}00000002000000", "00".repeat(16)),
+        "60016102",
+        "OK",
+        "abcd",
+        "OK",
+        "S05",
+        "0602",
+        // Only the fetches show; the M packet's writes to 0x300 don't
+        &to_hex(format!("200 \x1b[32mCCCCCC\x1b[90m{}\x1b[0m\n", ".".repeat(58)).as_bytes()),
+        "OK",
+        "S05",
+        "0602",
+        "OK",
+        "2a",
+        "E01",
+        "E01",
+        "E01",
+        "E01",
+        "E01",
+        "E01",
+    ];
+    if replies.len() != expected.len() {
+        panic!("Expected {} replies, got {:?}", expected.len(), replies);
+    }
+    for (reply, expected) in replies.iter().zip(expected.iter()) {
+        if reply != expected {
+            panic!("Expected reply {:?}, got {:?}", expected, reply);
+        }
+    }
+}
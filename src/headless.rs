@@ -0,0 +1,121 @@
+use cpu::Cpu;
+use frontend::{Clock, Command, InputSource, VideoSink};
+use mem;
+
+/// A video sink that only counts frames, for running without a display.
+pub struct HeadlessVideo {
+    pub frames: u64,
+}
+
+impl HeadlessVideo {
+    pub fn new() -> HeadlessVideo {
+        HeadlessVideo { frames: 0 }
+    }
+}
+
+impl Default for HeadlessVideo {
+    fn default() -> HeadlessVideo {
+        HeadlessVideo::new()
+    }
+}
+
+impl VideoSink for HeadlessVideo {
+    fn draw(&mut self, _cpu: &Cpu) {
+        self.frames += 1;
+    }
+}
+
+/// An input source with no keys that quits after a fixed number of frames.
+pub struct HeadlessInput {
+    frames_left: Option<u64>,
+}
+
+impl HeadlessInput {
+    /// Quits after `frames` frames, or never if `frames` is `None`.
+    pub fn new(frames: Option<u64>) -> HeadlessInput {
+        HeadlessInput { frames_left: frames }
+    }
+}
+
+impl InputSource for HeadlessInput {
+    fn poll_events(&mut self, _cpu: &mut Cpu) -> Vec<Command> {
+        match self.frames_left {
+            Some(0) => vec![Command::Quit],
+            Some(ref mut frames) => {
+                *frames -= 1;
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A clock that jumps forward instead of sleeping, so frames run as fast as
+/// the host allows.
+pub struct VirtualClock {
+    now: u32,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock { now: 0 }
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> VirtualClock {
+        VirtualClock::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn ticks(&mut self) -> u32 {
+        self.now
+    }
+    fn delay(&mut self, ms: u32) {
+        self.now = self.now.wrapping_add(ms);
+    }
+}
+
+/// Draws the screen as text, `#` for lit pixels and `.` for unlit ones.
+pub fn framebuffer_text(cpu: &Cpu) -> String {
+    let frame = cpu.memory.framebuffer();
+    let mut text = String::new();
+    for row in frame.chunks(mem::SCREEN_WIDTH) {
+        text.extend(row.iter().map(|&p| if p != 0 { '#' } else { '.' }));
+        text.push('\n');
+    }
+    text
+}
+
+//TESTS
+
+#[test]
+fn test_headless_frontend(){
+    use frontend::{Frontend, NullAudio, main_loop};
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    // Draw the "0" sprite at (1, 2), then spin
+    let rom_path = env::temp_dir().join("chippy_test_headless.ch8");
+    File::create(&rom_path).unwrap()
+        .write_all(&[0x60, 0x01, 0x61, 0x02, 0xD0, 0x15, 0x12, 0x06]).unwrap();
+
+    let mut cpu = Cpu::new();
+    let mut frontend = Frontend {
+        video: HeadlessVideo::new(),
+        audio: NullAudio,
+        input: HeadlessInput::new(Some(5)),
+        clock: VirtualClock::new(),
+    };
+    main_loop(&mut frontend, &mut cpu, rom_path.to_str().unwrap(), &mut []);
+    if frontend.video.frames != 5 {
+        panic!("Expected 5 frames to run, got {}", frontend.video.frames);
+    }
+    let text = framebuffer_text(&cpu);
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() != mem::SCREEN_HEIGHT || !lines[2].starts_with(".####.") || !lines[3].starts_with(".#..#.") {
+        panic!("Sprite drawn incorrectly:\n{}", text);
+    }
+}
@@ -0,0 +1,466 @@
+//! A JIT that translates basic blocks to x86-64 machine code, for batch
+//! runs. Built with the `jit` feature on x86-64 unix.
+//!
+//! Only register arithmetic, loads, `LD I`, `ADD I`, the timer moves and
+//! jumps and skips are translated; a block ends before anything else (draws,
+//! keys, memory access, calls and returns), which is run by the interpreter.
+//! Translated code only touches a copy of the registers in `JitState`, never
+//! memory, so self modifying code is handled the same way as in
+//! `engine::BlockEngine`: blocks are thrown away when memory they were
+//! decoded from is written.
+
+use std::ptr;
+use libc;
+use cpu::Cpu;
+use disasm::Instruction;
+use engine::{Engine, Interpreter};
+use mem::{COVER_FETCH, MEM_SIZE, PAGE_SIZE};
+use quirks::Quirks;
+
+const MAX_BLOCK_LENGTH: usize = 64;
+const NUM_PAGES: usize = MEM_SIZE/PAGE_SIZE;
+/// Size of the buffer holding translated code. When it fills up every
+/// block is thrown away and translation starts again.
+const CODE_SIZE: usize = 1 << 20;
+
+/// The registers translated code works on, pointed to by `rdi`.
+#[repr(C)]
+#[derive(Default)]
+struct JitState {
+    reg: [u8; 16],
+    i: u16,
+    pc: u16,
+    dt: u8,
+    st: u8,
+}
+
+const OFFSET_VF: u8 = 15;
+const OFFSET_I: u8 = 16;
+const OFFSET_PC: u8 = 18;
+const OFFSET_DT: u8 = 20;
+const OFFSET_ST: u8 = 21;
+
+impl JitState {
+    fn load(&mut self, cpu: &Cpu) {
+        for (n, reg) in self.reg.iter_mut().enumerate() {
+            *reg = cpu.get_reg(n as u8);
+        }
+        self.i = cpu.get_i();
+        self.pc = cpu.get_pc();
+        self.dt = cpu.get_dt();
+        self.st = cpu.get_st();
+    }
+
+    fn store(&self, cpu: &mut Cpu) {
+        for (n, &reg) in self.reg.iter().enumerate() {
+            cpu.set_reg(n as u8, reg);
+        }
+        cpu.set_i(self.i);
+        cpu.set_pc(self.pc);
+        cpu.set_dt(self.dt);
+        cpu.set_st(self.st);
+    }
+}
+
+type BlockFn = unsafe extern "C" fn(*mut JitState);
+
+/// Emits x86-64 instructions. Registers are addressed as `[rdi+offset]`.
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+    /// mov al, [rdi+offset]
+    fn load_al(&mut self, offset: u8) { self.emit(&[0x8A, 0x47, offset]); }
+    /// mov cl, [rdi+offset]
+    fn load_cl(&mut self, offset: u8) { self.emit(&[0x8A, 0x4F, offset]); }
+    /// mov [rdi+offset], al
+    fn store_al(&mut self, offset: u8) { self.emit(&[0x88, 0x47, offset]); }
+    /// mov [rdi+offset], cl
+    fn store_cl(&mut self, offset: u8) { self.emit(&[0x88, 0x4F, offset]); }
+    /// mov [rdi+offset], dl
+    fn store_dl(&mut self, offset: u8) { self.emit(&[0x88, 0x57, offset]); }
+    /// mov word [rdi+offset], value
+    fn store_word(&mut self, offset: u8, value: u16) {
+        self.emit(&[0x66, 0xC7, 0x47, offset]);
+        self.emit(&value.to_le_bytes());
+    }
+    /// Sets pc to `next`, or `next+2` if the flags say not equal (`skip_if_equal`
+    /// false) or equal (true), then returns.
+    fn skip(&mut self, next: u16, skip_if_equal: bool) {
+        self.store_word(OFFSET_PC, next);
+        // jne/je over the 6 byte store below; mov doesn't change the flags
+        self.emit(&[if skip_if_equal { 0x75 } else { 0x74 }, 0x06]);
+        self.store_word(OFFSET_PC, next + 2);
+        self.emit(&[0xC3]);
+    }
+
+    /// Translates one instruction at `pc`. Returns false if it can't be
+    /// translated, true with `ends` set if it ends the block.
+    fn instruction(&mut self, ins: Instruction, pc: u16, ends: &mut bool) -> bool {
+        use disasm::Instruction::*;
+        match ins {
+            LdByte { x, kk } => self.emit(&[0xC6, 0x47, x, kk]),
+            AddByte { x, kk } => {
+                self.load_al(x);
+                self.emit(&[0x04, kk]); // add al, kk
+                self.store_al(x);
+            }
+            LdReg { x, y } => {
+                self.load_al(y);
+                self.store_al(x);
+            }
+            Or { x, y } | And { x, y } | Xor { x, y } => {
+                let op = match ins { Or { .. } => 0x0A, And { .. } => 0x22, _ => 0x32 };
+                self.load_al(x);
+                self.emit(&[op, 0x47, y]); // or/and/xor al, [rdi+y]
+                self.store_al(x);
+            }
+            AddReg { x, y } => {
+                self.load_al(x);
+                self.load_cl(y);
+                self.emit(&[0x00, 0xC8]);       // add al, cl
+                self.emit(&[0x0F, 0x92, 0xC2]); // setc dl
+                self.store_dl(OFFSET_VF);
+                self.store_al(x);
+            }
+            Sub { x, y } => {
+                self.load_al(x);
+                self.load_cl(y);
+                self.emit(&[0x38, 0xC8]);       // cmp al, cl
+                self.emit(&[0x0F, 0x97, 0xC2]); // seta dl
+                self.emit(&[0x28, 0xC8]);       // sub al, cl
+                self.store_dl(OFFSET_VF);
+                self.store_al(x);
+            }
+            Subn { x, y } => {
+                self.load_al(x);
+                self.load_cl(y);
+                self.emit(&[0x38, 0xC1]);       // cmp cl, al
+                self.emit(&[0x0F, 0x97, 0xC2]); // seta dl
+                self.emit(&[0x28, 0xC1]);       // sub cl, al
+                self.store_dl(OFFSET_VF);
+                self.store_cl(x);
+            }
+            Shr { x, .. } => {
+                self.load_al(x);
+                self.emit(&[0x88, 0xC2]);       // mov dl, al
+                self.emit(&[0x80, 0xE2, 0x01]); // and dl, 1
+                self.emit(&[0xD0, 0xE8]);       // shr al, 1
+                self.store_dl(OFFSET_VF);
+                if x == OFFSET_VF {
+                    // The interpreter shifts VF after setting the flag
+                    self.emit(&[0xD0, 0xEA]);   // shr dl, 1
+                    self.store_dl(x);
+                } else {
+                    self.store_al(x);
+                }
+            }
+            Shl { x, .. } => {
+                self.load_al(x);
+                self.emit(&[0x88, 0xC2]);       // mov dl, al
+                self.emit(&[0xC0, 0xEA, 0x07]); // shr dl, 7
+                self.emit(&[0xD0, 0xE0]);       // shl al, 1
+                self.store_dl(OFFSET_VF);
+                if x == OFFSET_VF {
+                    self.emit(&[0xD0, 0xE2]);   // shl dl, 1
+                    self.store_dl(x);
+                } else {
+                    self.store_al(x);
+                }
+            }
+            LdI { nnn } => self.store_word(OFFSET_I, nnn),
+            AddI { x } => {
+                self.emit(&[0x0F, 0xB6, 0x47, x]);       // movzx eax, byte [rdi+x]
+                self.emit(&[0x66, 0x01, 0x47, OFFSET_I]); // add [rdi+i], ax
+            }
+            LdVxDt { x } => {
+                self.load_al(OFFSET_DT);
+                self.store_al(x);
+            }
+            LdDtVx { x } | LdStVx { x } => {
+                self.load_al(x);
+                self.store_al(if let LdDtVx { .. } = ins { OFFSET_DT } else { OFFSET_ST });
+            }
+            Jp { nnn } => {
+                self.store_word(OFFSET_PC, nnn);
+                self.emit(&[0xC3]);
+                *ends = true;
+            }
+            SeByte { x, kk } | SneByte { x, kk } => {
+                self.emit(&[0x80, 0x7F, x, kk]); // cmp byte [rdi+x], kk
+                self.skip(pc + 2, matches!(ins, SeByte { .. }));
+                *ends = true;
+            }
+            SeReg { x, y } | SneReg { x, y } => {
+                self.load_al(x);
+                self.emit(&[0x3A, 0x47, y]); // cmp al, [rdi+y]
+                self.skip(pc + 2, matches!(ins, SeReg { .. }));
+                *ends = true;
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// A translated block.
+#[derive(Clone, Copy)]
+struct Block {
+    offset: usize,
+    /// Number of instructions in it.
+    length: u64,
+}
+
+/// Executable memory for translated code.
+struct CodeBuffer {
+    memory: *mut u8,
+    used: usize,
+}
+
+impl CodeBuffer {
+    fn new() -> Result<CodeBuffer, String> {
+        let memory = unsafe {
+            libc::mmap(ptr::null_mut(), CODE_SIZE, libc::PROT_READ | libc::PROT_EXEC,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+        if memory == libc::MAP_FAILED {
+            return Err("Couldn't map memory for the JIT".to_string());
+        }
+        Ok(CodeBuffer { memory: memory as *mut u8, used: 0 })
+    }
+
+    fn protect(&self, flags: libc::c_int) {
+        let result = unsafe { libc::mprotect(self.memory as *mut libc::c_void, CODE_SIZE, flags) };
+        assert!(result == 0, "mprotect failed on JIT code");
+    }
+
+    /// Copies `code` in, returning its offset, or None if it doesn't fit.
+    fn add(&mut self, code: &[u8]) -> Option<usize> {
+        if self.used + code.len() > CODE_SIZE {
+            return None;
+        }
+        let offset = self.used;
+        // Never writable and executable at the same time
+        self.protect(libc::PROT_READ | libc::PROT_WRITE);
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), self.memory.add(offset), code.len()) };
+        self.protect(libc::PROT_READ | libc::PROT_EXEC);
+        self.used += code.len();
+        Some(offset)
+    }
+
+    /// Runs the block at `offset` on `state`.
+    ///
+    /// # Safety
+    /// `offset` must have come from `add` since the last `clear`.
+    unsafe fn call(&self, offset: usize, state: &mut JitState) {
+        let block: BlockFn = ::std::mem::transmute(self.memory.add(offset));
+        block(state);
+    }
+
+    fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.memory as *mut libc::c_void, CODE_SIZE) };
+    }
+}
+
+/// What is known about the code at an address.
+#[derive(Clone, Copy)]
+enum Entry {
+    Unknown,
+    Translated(Block),
+    /// The first instruction can't be translated.
+    Interpret,
+}
+
+pub struct JitEngine {
+    code: CodeBuffer,
+    entries: Vec<Entry>,
+    /// Start addresses of the blocks decoded from each page.
+    page_blocks: Vec<Vec<u16>>,
+    state: JitState,
+}
+
+impl JitEngine {
+    pub fn new() -> Result<JitEngine, String> {
+        Ok(JitEngine {
+            code: CodeBuffer::new()?,
+            entries: vec![Entry::Unknown; MEM_SIZE],
+            page_blocks: vec![Vec::new(); NUM_PAGES],
+            state: Default::default(),
+        })
+    }
+
+    fn invalidate(&mut self, dirty: u64) {
+        if dirty == 0 {
+            return;
+        }
+        for page in (0..NUM_PAGES).filter(|page| dirty & (1 << page) != 0) {
+            for start in self.page_blocks[page].drain(..) {
+                self.entries[start as usize] = Entry::Unknown;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.code.clear();
+        for entry in self.entries.iter_mut() {
+            *entry = Entry::Unknown;
+        }
+        for blocks in self.page_blocks.iter_mut() {
+            blocks.clear();
+        }
+    }
+
+    /// Decodes and translates the block at `pc`.
+    fn translate(&mut self, cpu: &Cpu, pc: u16) -> Entry {
+        let start = pc as usize;
+        let mut asm = Assembler { code: Vec::new() };
+        let mut length = 0;
+        let mut addr = start;
+        let mut ends = false;
+        while !ends && addr + 1 < MEM_SIZE && (length as usize) < MAX_BLOCK_LENGTH {
+            let ins = match Instruction::decode(cpu.memory.read_u16(addr)) {
+                Some(ins) => ins,
+                None => break,
+            };
+            if !asm.instruction(ins, addr as u16, &mut ends) {
+                break;
+            }
+            length += 1;
+            addr += 2;
+        }
+        if length == 0 {
+            // Kept with the page's blocks, so it's retried once the page
+            // is written to
+            for page in start/PAGE_SIZE..(start+1).min(MEM_SIZE-1)/PAGE_SIZE+1 {
+                self.page_blocks[page].push(pc);
+            }
+            return Entry::Interpret;
+        }
+        if !ends {
+            asm.store_word(OFFSET_PC, addr as u16);
+            asm.emit(&[0xC3]);
+        }
+        let offset = match self.code.add(&asm.code) {
+            Some(offset) => offset,
+            None => {
+                self.flush();
+                match self.code.add(&asm.code) {
+                    Some(offset) => offset,
+                    None => return Entry::Interpret,
+                }
+            }
+        };
+        for page in start/PAGE_SIZE..(addr-1)/PAGE_SIZE+1 {
+            self.page_blocks[page].push(pc);
+        }
+        Entry::Translated(Block { offset, length })
+    }
+}
+
+impl Engine for JitEngine {
+    fn run(&mut self, cpu: &mut Cpu, count: u64) -> (u64, Result<(), String>) {
+        // Translated code only knows the default behaviour
+        if cpu.quirks() != Quirks::default() {
+            return Interpreter.run(cpu, count);
+        }
+        let mut ran = 0;
+        while ran < count {
+            let dirty = cpu.memory.take_dirty_pages();
+            self.invalidate(dirty);
+            let pc = cpu.get_pc();
+            let mut entry = Entry::Interpret;
+            if (pc as usize) < MEM_SIZE {
+                if let Entry::Unknown = self.entries[pc as usize] {
+                    self.entries[pc as usize] = self.translate(cpu, pc);
+                }
+                entry = self.entries[pc as usize];
+            }
+            match entry {
+                Entry::Translated(block) if block.length <= count - ran => {
+                    self.state.load(cpu);
+                    unsafe { self.code.call(block.offset, &mut self.state) };
+                    self.state.store(cpu);
+                    cpu.add_executed(block.length);
+                    cpu.memory.mark(pc as usize, block.length as usize * 2, COVER_FETCH);
+                    ran += block.length;
+                }
+                _ => {
+                    ran += 1;
+                    if let Err(e) = cpu.exec_instruction() {
+                        return (ran, Err(e));
+                    }
+                }
+            }
+        }
+        (ran, Ok(()))
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_jit_matches_interpreter(){
+    use engine::{random_program, Interpreter};
+    use rng::XorShift;
+    let mut rng = XorShift::new(4321);
+    let mut jit = JitEngine::new().unwrap();
+    for _ in 0..300 {
+        // Mostly arithmetic, so long blocks get translated
+        let mut program = random_program(&mut rng);
+        for ins in program.chunks_mut(2) {
+            let r = rng.next_u32();
+            let x = (r >> 8) as u8 & 0xf;
+            match r % 4 {
+                0 => (),
+                1 => ins.copy_from_slice(&[0x60 | x, r as u8]),
+                _ => ins.copy_from_slice(&[0x80 | x, (r as u8 & 0xf0) | [0, 1, 2, 3, 4, 5, 6, 7, 0xE][(r >> 16) as usize % 9]]),
+            }
+        }
+        let seed = rng.next_u32();
+        let mut reference = Cpu::new();
+        let mut compiled = Cpu::new();
+        for cpu in [&mut reference, &mut compiled].iter_mut() {
+            cpu.memory.load_rom(&program).unwrap();
+            cpu.set_seed(seed);
+            cpu.keydown((seed % 16) as u8);
+        }
+        for chunk in 0..40 {
+            let (ran_reference, reference_result) = Interpreter.run(&mut reference, chunk*7 % 40 + 1);
+            let (ran_compiled, compiled_result) = jit.run(&mut compiled, chunk*7 % 40 + 1);
+            if ran_reference != ran_compiled || reference_result != compiled_result ||
+                reference.save_state() != compiled.save_state() || reference.get_key_trail(0) != compiled.get_key_trail(0) {
+                panic!("JIT differs after chunk {} of {:02x?}: {:?} vs {:?}",
+                       chunk, program, reference_result, compiled_result);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_jit_retranslates_written_code(){
+    let mut jit = JitEngine::new().unwrap();
+    let mut cpu = Cpu::new();
+    // CLS, which isn't translated; JP 0x200
+    cpu.memory.memset(0x200, &vec![0x00, 0xE0, 0x12, 0x00]);
+    jit.run(&mut cpu, 2).1.unwrap();
+    if let Entry::Translated(_) = jit.entries[0x200] {
+        panic!("CLS shouldn't be translated");
+    }
+    // LD V0, 0x2A
+    cpu.memory.memset(0x200, &vec![0x60, 0x2A]);
+    jit.run(&mut cpu, 1).1.unwrap();
+    match jit.entries[0x200] {
+        Entry::Translated(_) if cpu.get_reg(0) == 0x2A => (),
+        _ => panic!("Code written over an interpreted instruction should be translated"),
+    }
+}
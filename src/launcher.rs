@@ -0,0 +1,204 @@
+//! The screen shown when chippy is started without a ROM. It lists the ROMs
+//! in the configured directories, recently played ones first, with a
+//! snapshot of the selected one's screen a couple of seconds in.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use config;
+use cpu::Cpu;
+use engine::Interpreter;
+use frontend;
+use frontend::{AudioSink, Clock, Command, Frontend, InputSource, NullAudio, VideoSink, FRAMES_PER_SECOND};
+use headless;
+use io;
+use romdb::RomDb;
+
+/// Frames run headless before taking a ROM's snapshot.
+const SNAPSHOT_FRAMES: u64 = 120;
+/// Entries shown at once.
+const VISIBLE_ENTRIES: usize = 7;
+/// How many recently played ROMs are remembered.
+const MAX_RECENT: usize = 10;
+const ROM_EXTENSIONS: [&str; 2] = ["ch8", "c8"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// The title from the ROM database, or the file name.
+    pub title: String,
+    pub recent: bool,
+}
+
+/// Where the recently played list is kept, `<config dir>/recent.txt`.
+pub fn recent_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("recent.txt"))
+}
+
+/// Reads the recently played list, newest first.
+pub fn load_recent(path: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(path)
+        .map(|contents| contents.lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+/// Moves `rom` to the top of the recently played list kept in `path`.
+pub fn add_recent(path: &Path, rom: &Path) -> Result<(), String> {
+    let rom = fs::canonicalize(rom).map_err(|e| format!("Error reading {}: {}", rom.display(), e))?;
+    let mut recent = load_recent(path);
+    recent.retain(|other| *other != rom);
+    recent.insert(0, rom);
+    recent.truncate(MAX_RECENT);
+    let contents: String = recent.iter().map(|rom| format!("{}\n", rom.display())).collect();
+    path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(path, contents))
+        .map_err(|e| format!("Error writing {}: {}", path.display(), e))
+}
+
+/// The ROM files directly inside `dirs`, sorted by name within each.
+pub fn find_roms(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    for dir in dirs {
+        let mut found: Vec<PathBuf> = fs::read_dir(dir).into_iter().flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension()
+                    .is_some_and(|ext| ROM_EXTENSIONS.iter().any(|rom_ext| ext.eq_ignore_ascii_case(rom_ext))))
+            .collect();
+        found.sort();
+        roms.extend(found);
+    }
+    roms
+}
+
+/// The launcher's list: the recently played ROMs that still exist, then
+/// the rest of `roms`.
+pub fn entries(recent: &[PathBuf], roms: &[PathBuf], db: Option<&RomDb>) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let existing = recent.iter().filter(|path| path.is_file()).map(|path| (path, true));
+    for (path, is_recent) in existing.chain(roms.iter().map(|path| (path, false))) {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        if entries.iter().any(|entry| entry.path == canonical) {
+            continue;
+        }
+        let title = db.and_then(|db| io::read_rom(&path.to_string_lossy()).ok().and_then(|rom| db.lookup(&rom)))
+            .map(|info| info.title)
+            .unwrap_or_else(|| path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default());
+        entries.push(Entry { path: canonical, title, recent: is_recent });
+    }
+    entries
+}
+
+/// Runs a ROM headless for its first few frames, with its settings from
+/// the database if it's in `db`, returning the machine to show the screen
+/// it ended on.
+pub fn snapshot(path: &Path, db: Option<&RomDb>) -> Cpu {
+    let mut cpu = Cpu::new();
+    let rom = match io::read_rom(&path.to_string_lossy()) {
+        Ok(rom) => rom,
+        Err(_) => return cpu,
+    };
+    if cpu.memory.load_rom(&rom).is_err() {
+        return cpu;
+    }
+    if let Some(info) = db.and_then(|db| db.lookup(&rom)) {
+        cpu.set_quirks(info.quirks.unwrap_or_default());
+        if let Some(ips) = info.instructions_per_second() {
+            cpu.set_ips(ips);
+        }
+    }
+    let mut frontend = Frontend {
+        video: headless::HeadlessVideo::new(),
+        audio: NullAudio,
+        input: headless::HeadlessInput::new(Some(SNAPSHOT_FRAMES)),
+        clock: headless::VirtualClock::new(),
+    };
+    frontend::run(&mut frontend, &mut cpu, |cpu| cpu.memory.load_rom(&rom).unwrap(), &mut [], &mut Interpreter);
+    cpu
+}
+
+/// The lines listing `entries` around the selected one.
+pub fn lines(entries: &[Entry], selected: usize) -> Vec<String> {
+    let mut lines = vec![format!("ROM {}/{} - Up/Down, Enter to play (* recent)", selected + 1, entries.len())];
+    let first = selected.saturating_sub(VISIBLE_ENTRIES / 2).min(entries.len().saturating_sub(VISIBLE_ENTRIES));
+    for (n, entry) in entries.iter().enumerate().skip(first).take(VISIBLE_ENTRIES) {
+        lines.push(format!("{} {}{}", if n == selected { ">" } else { " " }, entry.title,
+                           if entry.recent { " *" } else { "" }));
+    }
+    lines
+}
+
+/// Shows the launcher until a ROM is picked, returning it, or None if the
+/// user quits.
+pub fn run<V, A, I, C>(frontend: &mut Frontend<V, A, I, C>, entries: &[Entry], db: Option<&RomDb>) -> Option<PathBuf>
+    where V: VideoSink, A: AudioSink, I: InputSource, C: Clock
+{
+    if entries.is_empty() {
+        return None;
+    }
+    let mut selected = 0;
+    let mut shown = None;
+    let mut snapshot_cpu = Cpu::new();
+    // Takes the Chip8 key presses, which the launcher doesn't use
+    let mut keys = Cpu::new();
+    loop {
+        for command in frontend.input.poll_events(&mut keys) {
+            match command {
+                Command::Quit   => return None,
+                Command::Up     => selected = (selected + entries.len() - 1) % entries.len(),
+                Command::Down   => selected = (selected + 1) % entries.len(),
+                Command::Select => return Some(entries[selected].path.clone()),
+                command => frontend.video.handle_command(command),
+            }
+        }
+        if shown != Some(selected) {
+            snapshot_cpu = snapshot(&entries[selected].path, db);
+            shown = Some(selected);
+        }
+        frontend.video.set_overlay(&lines(entries, selected));
+        frontend.video.draw(&snapshot_cpu);
+        frontend.clock.delay(1000 / FRAMES_PER_SECOND as u32);
+    }
+}
+
+/// Adds `rom` to the recently played list, printing any error.
+pub fn remember(rom: &str) {
+    if let Some(path) = recent_path() {
+        if let Err(e) = add_recent(&path, Path::new(rom)) {
+            println!("{}", e);
+        }
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_launcher(){
+    let dir = ::std::env::temp_dir().join("chippy_test_launcher");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("roms")).unwrap();
+    // LD I, sprite 0; DRW V0, V0, 5; JP 0x204
+    for name in ["b.ch8", "a.CH8", "c.c8", "notes.txt"].iter() {
+        fs::write(dir.join("roms").join(name), [0xA0, 0x00, 0xD0, 0x05, 0x12, 0x04]).unwrap();
+    }
+    let roms = find_roms(&[dir.join("roms"), dir.join("missing")]);
+    let names: Vec<String> = roms.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+    if names != ["a.CH8", "b.ch8", "c.c8"] {
+        panic!("Unexpected roms {:?}", names);
+    }
+
+    let recent_file = dir.join("config").join("recent.txt");
+    add_recent(&recent_file, &roms[2]).unwrap();
+    add_recent(&recent_file, &roms[1]).unwrap();
+    add_recent(&recent_file, &roms[2]).unwrap();
+    let recent = load_recent(&recent_file);
+    let entries = entries(&recent, &roms, None);
+    let titles: Vec<(&str, bool)> = entries.iter().map(|entry| (entry.title.as_str(), entry.recent)).collect();
+    if recent.len() != 2 || titles != [("c.c8", true), ("b.ch8", true), ("a.CH8", false)] {
+        panic!("Unexpected entries {:?}", titles);
+    }
+    if lines(&entries, 1) != ["ROM 2/3 - Up/Down, Enter to play (* recent)", "  c.c8 *", "> b.ch8 *", "  a.CH8"] {
+        panic!("Unexpected lines {:?}", lines(&entries, 1));
+    }
+    if !headless::framebuffer_text(&snapshot(&entries[0].path, None)).starts_with("####") {
+        panic!("Expected the snapshot to show the sprite");
+    }
+}
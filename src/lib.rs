@@ -0,0 +1,53 @@
+extern crate argparse;
+extern crate byteorder;
+extern crate libc;
+extern crate sha1_smol;
+extern crate serde_json;
+#[cfg(feature = "scripting")]
+extern crate rhai;
+#[cfg(feature = "sdl")]
+extern crate sdl2;
+#[cfg(test)]
+extern crate syn;
+
+pub mod audio;
+pub mod cheat;
+pub mod config;
+pub mod conformance;
+pub mod coverage;
+pub mod cpu;
+pub mod disasm;
+pub mod engine;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod flicker;
+pub mod frontend;
+pub mod gdb;
+pub mod headless;
+pub mod io;
+pub mod launcher;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub mod jit;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+pub mod lockstep;
+pub mod mem;
+pub mod palette;
+pub mod postfx;
+pub mod profile;
+pub mod quirks;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod recompile;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod reload;
+pub mod rng;
+pub mod romdb;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+pub mod trace;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tui;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -0,0 +1,490 @@
+//! A libretro core, so that chippy can be loaded by RetroArch and other
+//! libretro front ends. Build with `cargo build --release --features libretro`
+//! and load the resulting `libchippy.so` as a core.
+
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+use audio::SquareWave;
+use cpu;
+use cpu::Cpu;
+use flicker::{FlickerFilter, FlickerMode};
+use mem;
+use palette::Palette;
+use postfx;
+use postfx::Effects;
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_DEVICE_KEYBOARD: c_uint = 3;
+
+const FRAMES_PER_SECOND: f64 = 60.0;
+const SAMPLE_RATE: f64 = 44100.0;
+const SAMPLES_PER_FRAME: usize = 735;
+const SAMPLE_VOLUME: f32 = 32767.0;
+
+/// Which keypad key each RetroPad button presses, indexed by libretro's
+/// joypad ids (B, Y, Select, Start, Up, Down, Left, Right, A, X, L, R, L2,
+/// R2, L3, R3). The d-pad sits on 2/4/6/8, which most games use to move.
+const JOYPAD_KEYS: [u8; 16] = [
+    0x0, 0x3, 0xc, 0xf, 0x2, 0x8, 0x4, 0x6,
+    0x5, 0x1, 0x7, 0x9, 0xa, 0xb, 0xd, 0xe,
+];
+
+/// The keyboard layout used by the other front ends, as libretro keycodes,
+/// indexed by keypad key.
+const KEYBOARD_KEYS: &[u8; 16] = b"4567rtyufghjvbnm";
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+pub type RetroEnvironment = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type RetroVideoRefresh = extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type RetroAudioSample = extern "C" fn(left: i16, right: i16);
+pub type RetroAudioSampleBatch = extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type RetroInputPoll = extern "C" fn();
+pub type RetroInputState = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+/// The callbacks handed to us by the front end.
+struct Callbacks {
+    environment: Option<RetroEnvironment>,
+    video_refresh: Option<RetroVideoRefresh>,
+    audio_sample_batch: Option<RetroAudioSampleBatch>,
+    input_poll: Option<RetroInputPoll>,
+    input_state: Option<RetroInputState>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+/// Everything belonging to a loaded game.
+struct Core {
+    cpu: Cpu,
+    rom: Vec<u8>,
+    wave: SquareWave,
+    palette: Palette,
+    flicker: FlickerFilter,
+    video: Vec<u32>,
+    audio: Vec<i16>,
+}
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+impl Core {
+    fn new(rom: Vec<u8>) -> Result<Core, String> {
+        let mut core = Core {
+            cpu: Cpu::new(),
+            rom,
+            wave: SquareWave::new(SAMPLE_RATE as i32),
+            palette: Default::default(),
+            flicker: FlickerFilter::new(FlickerMode::Off),
+            video: vec![0u32; mem::SCREEN_WIDTH*mem::SCREEN_HEIGHT],
+            audio: vec![0i16; SAMPLES_PER_FRAME*2],
+        };
+        core.reset()?;
+        Ok(core)
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        self.cpu.reset();
+        self.cpu.memory.load_rom(&self.rom)
+    }
+
+    fn poll_keys(&mut self, input_state: RetroInputState) {
+        for key in 0..16u8 {
+            let joypad = JOYPAD_KEYS.iter().position(|&k| k == key).unwrap() as c_uint;
+            let down = input_state(0, RETRO_DEVICE_JOYPAD, 0, joypad) != 0
+                || input_state(0, RETRO_DEVICE_KEYBOARD, 0, KEYBOARD_KEYS[key as usize] as c_uint) != 0;
+            if down {
+                self.cpu.keydown(key);
+            } else {
+                self.cpu.keyup(key);
+            }
+        }
+    }
+
+    fn run_frame(&mut self) {
+        for _ in 0..(self.cpu.get_ips() as f64/FRAMES_PER_SECOND) as u64 {
+            if let Err(v) = self.cpu.exec_instruction() {
+                println!("Error in cpu.exec_instruction(): {}", v);
+            }
+        }
+        self.cpu.decr_dt();
+        self.cpu.decr_st();
+
+        let image = postfx::render_frame(&self.cpu, &self.palette, &mut self.flicker, &Effects::default());
+        for (out, pixel) in self.video.iter_mut().zip(image.data.chunks(3)) {
+            *out = (pixel[0] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[2] as u32;
+        }
+
+        let on = self.cpu.get_st_active();
+        for frame in self.audio.chunks_mut(2) {
+            let sample = if on { (self.wave.next_sample()*SAMPLE_VOLUME) as i16 } else { 0 };
+            frame[0] = sample;
+            frame[1] = sample;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: RetroEnvironment) {
+    CALLBACKS.lock().unwrap().environment = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: RetroVideoRefresh) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(cb);
+}
+
+/// Unused, audio is always sent a frame at a time through the batch callback.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: RetroAudioSample) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: RetroAudioSampleBatch) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: RetroInputPoll) {
+    CALLBACKS.lock().unwrap().input_poll = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: RetroInputState) {
+    CALLBACKS.lock().unwrap().input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+/// # Safety
+/// `info` must point to a valid `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: b"chippy\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"ch8|c8|rom\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a valid `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: mem::SCREEN_WIDTH as c_uint,
+            base_height: mem::SCREEN_HEIGHT as c_uint,
+            max_width: mem::SCREEN_WIDTH as c_uint,
+            max_height: mem::SCREEN_HEIGHT as c_uint,
+            aspect_ratio: 2.0,
+        },
+        timing: RetroSystemTiming {
+            fps: FRAMES_PER_SECOND,
+            sample_rate: SAMPLE_RATE,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(ref mut core) = *CORE.lock().unwrap() {
+        if let Err(e) = core.reset() {
+            println!("Error resetting: {}", e);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = CALLBACKS.lock().unwrap();
+    let mut core = CORE.lock().unwrap();
+    let core = match *core {
+        Some(ref mut core) => core,
+        None => return,
+    };
+    if let Some(input_poll) = callbacks.input_poll {
+        input_poll();
+    }
+    if let Some(input_state) = callbacks.input_state {
+        core.poll_keys(input_state);
+    }
+    core.run_frame();
+    if let Some(video_refresh) = callbacks.video_refresh {
+        video_refresh(core.video.as_ptr() as *const c_void,
+                      mem::SCREEN_WIDTH as c_uint,
+                      mem::SCREEN_HEIGHT as c_uint,
+                      mem::SCREEN_WIDTH*4);
+    }
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        audio_sample_batch(core.audio.as_ptr(), SAMPLES_PER_FRAME);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    cpu::STATE_SIZE
+}
+
+/// # Safety
+/// `data` must point to at least `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    match *CORE.lock().unwrap() {
+        Some(ref core) if size >= cpu::STATE_SIZE => {
+            let state = core.cpu.save_state();
+            ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+            true
+        }
+        _ => false,
+    }
+}
+
+/// # Safety
+/// `data` must point to at least `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    match *CORE.lock().unwrap() {
+        Some(ref mut core) => {
+            let state = slice::from_raw_parts(data as *const u8, size);
+            match core.cpu.load_state(state) {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("Error loading state: {}", e);
+                    false
+                }
+            }
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` must be null or point to a valid `retro_game_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    if let Some(environment) = CALLBACKS.lock().unwrap().environment {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+            println!("Front end does not support XRGB8888");
+            return false;
+        }
+    }
+    let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+    match Core::new(rom) {
+        Ok(core) => {
+            *CORE.lock().unwrap() = Some(core);
+            true
+        }
+        Err(e) => {
+            println!("Error loading rom: {}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_type: c_uint, _info: *const RetroGameInfo, _num: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+/// Exposes the Chip8's 4K of RAM, which is also what cheat and achievement
+/// tools look at.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match *CORE.lock().unwrap() {
+        Some(ref mut core) if id == RETRO_MEMORY_SYSTEM_RAM => core.cpu.memory.raw_mut().as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    if id == RETRO_MEMORY_SYSTEM_RAM { mem::MEM_SIZE } else { 0 }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod host {
+    //! A tiny libretro front end that records what the core hands it.
+    use std::os::raw::{c_uint, c_void};
+    use std::slice;
+    use std::sync::Mutex;
+
+    pub static PIXEL_FORMAT: Mutex<Option<c_uint>> = Mutex::new(None);
+    pub static FRAME: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    pub static AUDIO: Mutex<Vec<i16>> = Mutex::new(Vec::new());
+    pub static PRESSED: Mutex<Option<(c_uint, c_uint)>> = Mutex::new(None);
+
+    pub extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+        if cmd == super::RETRO_ENVIRONMENT_SET_PIXEL_FORMAT {
+            *PIXEL_FORMAT.lock().unwrap() = Some(unsafe { *(data as *const c_uint) });
+            return true;
+        }
+        false
+    }
+    pub extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert_eq!(pitch, width as usize*4);
+        let pixels = unsafe { slice::from_raw_parts(data as *const u32, (width*height) as usize) };
+        *FRAME.lock().unwrap() = pixels.to_vec();
+    }
+    pub extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+        *AUDIO.lock().unwrap() = unsafe { slice::from_raw_parts(data, frames*2) }.to_vec();
+        frames
+    }
+    pub extern "C" fn input_poll() {}
+    pub extern "C" fn input_state(_port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+        (*PRESSED.lock().unwrap() == Some((device, id))) as i16
+    }
+}
+
+#[test]
+fn test_libretro_host(){
+    // Draws the "0" sprite at (0, 0), starts the sound timer, then loops
+    let rom: [u8; 10] = [0xA0, 0x00, 0xD0, 0x15, 0x6A, 0x3C, 0xFA, 0x18, 0x12, 0x08];
+    let game = RetroGameInfo {
+        path: ptr::null(),
+        data: rom.as_ptr() as *const c_void,
+        size: rom.len(),
+        meta: ptr::null(),
+    };
+    retro_set_environment(host::environment);
+    retro_set_video_refresh(host::video_refresh);
+    retro_set_audio_sample_batch(host::audio_sample_batch);
+    retro_set_input_poll(host::input_poll);
+    retro_set_input_state(host::input_state);
+    retro_init();
+    if !unsafe { retro_load_game(&game) } {
+        panic!("Core refused to load the game");
+    }
+    if *host::PIXEL_FORMAT.lock().unwrap() != Some(RETRO_PIXEL_FORMAT_XRGB8888) {
+        panic!("Core should ask for XRGB8888");
+    }
+
+    *host::PRESSED.lock().unwrap() = Some((RETRO_DEVICE_JOYPAD, 4));
+    retro_run();
+    let frame = host::FRAME.lock().unwrap().clone();
+    if frame.len() != mem::SCREEN_WIDTH*mem::SCREEN_HEIGHT {
+        panic!("Unexpected frame size {}", frame.len());
+    }
+    let background = frame[mem::SCREEN_WIDTH*mem::SCREEN_HEIGHT - 1];
+    // top row of the "0" sprite is 0xF0
+    if frame[..4].contains(&background) || frame[4] != background {
+        panic!("Sprite was not drawn: {:?}", &frame[..8]);
+    }
+    let audio = host::AUDIO.lock().unwrap().clone();
+    if audio.len() != SAMPLES_PER_FRAME*2 || audio.iter().all(|&s| s == 0) {
+        panic!("Expected a frame of tone while the sound timer is active");
+    }
+    if !CORE.lock().unwrap().as_ref().unwrap().cpu.get_key(0x2) {
+        panic!("Joypad up should press key 2");
+    }
+
+    *host::PRESSED.lock().unwrap() = Some((RETRO_DEVICE_KEYBOARD, b'v' as c_uint));
+    let mut state = vec![0u8; retro_serialize_size()];
+    if !unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) } {
+        panic!("Serializing failed");
+    }
+    retro_run();
+    let st = CORE.lock().unwrap().as_ref().unwrap().cpu.get_st();
+    {
+        let core = CORE.lock().unwrap();
+        let cpu = &core.as_ref().unwrap().cpu;
+        if cpu.get_key(0x2) || !cpu.get_key(0xc) {
+            panic!("Keyboard V should press key C and release key 2");
+        }
+    }
+    if !unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) } {
+        panic!("Unserializing failed");
+    }
+    if CORE.lock().unwrap().as_ref().unwrap().cpu.get_st() != st + 1 {
+        panic!("Save state did not restore the sound timer");
+    }
+    if unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len() - 1) } {
+        panic!("Truncated state should be rejected");
+    }
+    retro_unload_game();
+    retro_deinit();
+}
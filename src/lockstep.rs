@@ -0,0 +1,298 @@
+//! Runs a ROM in lockstep with a trace recorded by another emulator,
+//! stopping at the first instruction where they disagree. This checks
+//! chippy's behaviour, under each set of quirks, against other
+//! implementations without having to reimplement them.
+//!
+//! A trace is text, one line per instruction, giving where it was and the
+//! state after it ran as `KEY=hex` fields:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! PC=200 OP=6A02 V=00000000000000000000020000000000 I=000 SP=0 DT=00 ST=00
+//! PC=202 OP=A300 V0=00 VA=02 I=300
+//! ```
+//!
+//! `PC` and `OP` are required. Registers are given all at once as `V`, 32
+//! hex digits for V0 to VF, or one at a time as `V0` to `VF`. Anything left
+//! out isn't checked, and unknown keys are ignored, so other emulators'
+//! traces only need converting to this layout. chippy writes this format
+//! with `--trace-format state`.
+//!
+//! The results of `Cxkk` can't match another emulator's random numbers, so
+//! they are taken from the trace.
+
+use std::fmt;
+use std::io::BufRead;
+use cpu::Cpu;
+use disasm;
+use disasm::Instruction;
+use mem::MEM_SIZE;
+
+/// The state after one instruction, as recorded in a trace. Fields that
+/// weren't recorded are None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct State {
+    pub pc: u16,
+    pub opcode: u16,
+    pub reg: [Option<u8>; 16],
+    pub i: Option<u16>,
+    pub sp: Option<u8>,
+    pub dt: Option<u8>,
+    pub st: Option<u8>,
+}
+
+fn parse_hex<T>(key: &str, value: &str, max: u32) -> Result<T, String>
+    where T: From<u16>
+{
+    match u32::from_str_radix(value, 16) {
+        Ok(v) if v <= max => Ok(T::from(v as u16)),
+        _ => Err(format!("Invalid value \"{}\" for {}", value, key)),
+    }
+}
+
+impl State {
+    /// Everything about `cpu` after running the instruction `opcode` at `pc`.
+    pub fn capture(cpu: &Cpu, pc: u16, opcode: u16) -> State {
+        let mut reg = [None; 16];
+        for (n, value) in reg.iter_mut().enumerate() {
+            *value = Some(cpu.get_reg(n as u8));
+        }
+        State {
+            pc,
+            opcode,
+            reg,
+            i: Some(cpu.get_i()),
+            sp: Some(cpu.get_sp() as u8),
+            dt: Some(cpu.get_dt()),
+            st: Some(cpu.get_st()),
+        }
+    }
+
+    /// Parses a trace line.
+    pub fn parse(line: &str) -> Result<State, String> {
+        let mut state = State::default();
+        let (mut pc, mut opcode) = (None, None);
+        for field in line.split_whitespace() {
+            let (key, value) = match field.split_once('=') {
+                Some(pair) => pair,
+                None => return Err(format!("Expected KEY=value, got \"{}\"", field)),
+            };
+            let byte = || parse_hex::<u16>(key, value, 0xff).map(|v| v as u8);
+            match key {
+                "PC" => pc = Some(parse_hex(key, value, 0xffff)?),
+                "OP" => opcode = Some(parse_hex(key, value, 0xffff)?),
+                "I"  => state.i = Some(parse_hex(key, value, 0xffff)?),
+                "SP" => state.sp = Some(byte()?),
+                "DT" => state.dt = Some(byte()?),
+                "ST" => state.st = Some(byte()?),
+                "V" => {
+                    if value.len() != 32 {
+                        return Err(format!("Expected 32 hex digits for V, got \"{}\"", value));
+                    }
+                    for n in 0..16 {
+                        state.reg[n] = Some(parse_hex::<u16>(key, &value[n*2..n*2+2], 0xff)? as u8);
+                    }
+                }
+                _ if key.len() == 2 && key.starts_with('V') => {
+                    if let Ok(n) = u8::from_str_radix(&key[1..], 16) {
+                        state.reg[n as usize] = Some(byte()?);
+                    }
+                }
+                _ => (),
+            }
+        }
+        match (pc, opcode) {
+            (Some(pc), Some(opcode)) => {
+                state.pc = pc;
+                state.opcode = opcode;
+                Ok(state)
+            }
+            _ => Err("Expected PC and OP".to_string()),
+        }
+    }
+
+    /// The fields recorded in both `self` and `other` that differ, as
+    /// (name, expected, actual).
+    fn differences(&self, other: &State) -> Vec<(String, String, String)> {
+        let mut out = Vec::new();
+        {
+            let mut check = |name: String, expected: Option<u16>, actual: Option<u16>, width: usize| {
+                if let (Some(e), Some(a)) = (expected, actual) {
+                    if e != a {
+                        out.push((name, format!("{:01$X}", e, width), format!("{:01$X}", a, width)));
+                    }
+                }
+            };
+            for n in 0..16 {
+                check(format!("V{:X}", n), self.reg[n].map(u16::from), other.reg[n].map(u16::from), 2);
+            }
+            check("I".to_string(), self.i, other.i, 3);
+            check("SP".to_string(), self.sp.map(u16::from), other.sp.map(u16::from), 1);
+            check("DT".to_string(), self.dt.map(u16::from), other.dt.map(u16::from), 2);
+            check("ST".to_string(), self.st.map(u16::from), other.st.map(u16::from), 2);
+        }
+        out
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PC={:03X} OP={:04X}", self.pc, self.opcode)?;
+        if self.reg.iter().all(|r| r.is_some()) {
+            write!(f, " V=")?;
+            for value in self.reg.iter().flatten() {
+                write!(f, "{:02X}", value)?;
+            }
+        } else {
+            for (n, value) in self.reg.iter().enumerate() {
+                if let Some(value) = *value {
+                    write!(f, " V{:X}={:02X}", n, value)?;
+                }
+            }
+        }
+        if let Some(i) = self.i { write!(f, " I={:03X}", i)?; }
+        if let Some(sp) = self.sp { write!(f, " SP={:X}", sp)?; }
+        if let Some(dt) = self.dt { write!(f, " DT={:02X}", dt)?; }
+        if let Some(st) = self.st { write!(f, " ST={:02X}", st)?; }
+        Ok(())
+    }
+}
+
+/// Where chippy first disagreed with a trace.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Line number in the trace, counting from 1.
+    pub line: usize,
+    /// Instructions that matched before this one.
+    pub step: u64,
+    pub expected: State,
+    /// chippy's state after the instruction, or before it if it was at the
+    /// wrong place or failed.
+    pub actual: State,
+    pub reason: String,
+    /// The previous line of the trace, which matched.
+    pub previous: Option<State>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged at line {} (instruction {}): {}", self.line, self.step, self.reason)?;
+        if let Some(ref previous) = self.previous {
+            writeln!(f, "  previous {}", previous)?;
+        }
+        writeln!(f, "  expected {}", self.expected)?;
+        writeln!(f, "  actual   {}", self.actual)?;
+        writeln!(f, "  at {:03X}: {}", self.actual.pc, disasm::disassemble(self.actual.opcode))?;
+        for (name, expected, actual) in self.expected.differences(&self.actual) {
+            writeln!(f, "  {:<2} expected {} got {}", name, expected, actual)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a lockstep run ended.
+#[derive(Debug)]
+pub enum Outcome {
+    /// Every instruction in the trace matched.
+    Matched(u64),
+    Diverged(Box<Divergence>),
+}
+
+fn opcode_at(cpu: &Cpu, pc: u16) -> u16 {
+    if (pc as usize) + 1 < MEM_SIZE { cpu.memory.read_u16(pc as usize) } else { 0 }
+}
+
+/// Runs `cpu` one instruction per line of `trace`, stopping at the first
+/// difference. With `timers_from_trace` the timers are copied from the
+/// trace after each instruction instead of being checked, for traces from
+/// emulators that count them down at different times.
+pub fn run<R: BufRead>(cpu: &mut Cpu, trace: R, timers_from_trace: bool) -> Result<Outcome, String> {
+    let mut step = 0;
+    let mut previous: Option<State> = None;
+    for (n, line) in trace.lines().enumerate() {
+        let line = line.map_err(|e| format!("Error reading trace: {}", e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let expected = State::parse(line).map_err(|e| format!("Line {}: {}", n + 1, e))?;
+        let pc = cpu.get_pc();
+        let opcode = opcode_at(cpu, pc);
+        let diverged = |actual: State, reason: String| Ok(Outcome::Diverged(Box::new(Divergence {
+            line: n + 1,
+            step,
+            expected: expected.clone(),
+            actual,
+            reason,
+            previous: previous.clone(),
+        })));
+        if pc != expected.pc || opcode != expected.opcode {
+            return diverged(State::capture(cpu, pc, opcode), "expected a different instruction".to_string());
+        }
+        if let Err(e) = cpu.exec_instruction() {
+            return diverged(State::capture(cpu, pc, opcode), e);
+        }
+        if let Some(Instruction::Rnd { x, kk }) = Instruction::decode(opcode) {
+            if let Some(value) = expected.reg[x as usize] {
+                if value & !kk == 0 {
+                    cpu.set_reg(x, value);
+                }
+            }
+        }
+        if timers_from_trace {
+            if let Some(dt) = expected.dt { cpu.set_dt(dt); }
+            if let Some(st) = expected.st { cpu.set_st(st); }
+        }
+        let actual = State::capture(cpu, pc, opcode);
+        let differences = expected.differences(&actual);
+        if !differences.is_empty() {
+            let names: Vec<String> = differences.into_iter().map(|(name, _, _)| name).collect();
+            return diverged(actual, format!("{} differ", names.join(", ")));
+        }
+        previous = Some(expected);
+        step += 1;
+    }
+    Ok(Outcome::Matched(step))
+}
+
+//TESTS
+
+#[test]
+fn test_lockstep(){
+    use quirks::Quirks;
+    // LD V1, 0x81; LD V2, 0x03; SHR V1, V2; RND V3, 0x0F; JP 0x208
+    let rom = [0x61, 0x81, 0x62, 0x03, 0x81, 0x26, 0xC3, 0x0F, 0x12, 0x08];
+    let trace = "\
+# recorded with shifts working on Vx
+PC=200 OP=6181 V1=81 I=000
+PC=202 OP=6203 V=00810300000000000000000000000000 SP=0
+
+PC=204 OP=8126 V1=40 VF=01 EXTRA=ignored
+PC=206 OP=C30F V3=0A
+PC=208 OP=1208 DT=00
+";
+    let run_with = |quirks: Quirks| {
+        let mut cpu = Cpu::new();
+        cpu.set_quirks(quirks);
+        cpu.memory.load_rom(&rom).unwrap();
+        run(&mut cpu, trace.as_bytes(), false).unwrap()
+    };
+    match run_with(Quirks::default()) {
+        Outcome::Matched(5) => (),
+        outcome => panic!("Expected the trace to match, got {:?}", outcome),
+    }
+    // Shifting Vy gives V1=01
+    let divergence = match run_with(Quirks::parse("cosmac").unwrap()) {
+        Outcome::Diverged(divergence) => divergence,
+        outcome => panic!("Expected the trace to diverge, got {:?}", outcome),
+    };
+    let report = divergence.to_string();
+    if divergence.line != 5 || divergence.step != 2 || !report.contains("  V1 expected 40 got 01\n") ||
+        !report.contains("  previous PC=202 OP=6203 V=00810300000000000000000000000000 SP=0\n") {
+        panic!("Unexpected divergence:\n{}", report);
+    }
+    let state = State::capture(&Cpu::new(), 0x200, 0x6181);
+    if State::parse(&state.to_string()) != Ok(state.clone()) || State::parse("PC=200").is_ok() {
+        panic!("State lines not parsed properly: {}", state);
+    }
+}
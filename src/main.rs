@@ -1,104 +1,493 @@
 extern crate argparse;
-extern crate byteorder;
-extern crate rand;
-extern crate sdl2;
-mod cpu;
-use cpu::Cpu;
-mod mem;
-mod io;
-mod sdl;
+extern crate chippy;
+use chippy::{frontend, headless, io, tui};
+use chippy::cheat::CheatHook;
+#[cfg(feature = "sdl")]
+use chippy::sdl;
+use chippy::config::Config;
+use chippy::conformance;
+use chippy::cpu::Cpu;
+use chippy::flicker::FlickerMode;
+use chippy::frontend::{Frontend, Hook, NullAudio, SystemClock};
+use chippy::gdb;
+use chippy::gdb::GdbStub;
+use chippy::launcher;
+use chippy::lockstep;
+use chippy::palette::Palette;
+use chippy::postfx::Effects;
+#[cfg(feature = "scripting")]
+use chippy::script::ScriptHook;
+use chippy::coverage::CoverageHook;
+use chippy::profile::ProfileHook;
+use chippy::quirks;
+use chippy::quirks::Quirks;
+use chippy::recompile;
+use chippy::reload::ReloadHook;
+use chippy::romdb::{RomDb, RomInfo};
+use chippy::trace;
+use chippy::trace::{TraceFilter, TraceFormat, TraceHook};
+use chippy::tui::Glyphs;
 
-use sdl2::keyboard::Keycode;
-use sdl2::event::Event;
-use sdl::Contexts;
-
-use argparse::{ArgumentParser, StoreTrue, Store};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
 
 //use std::fmt;
 //use std::path;
 
-fn main_loop(mut contexts: Contexts, cpu: &mut Cpu, file_name: &str) {
-    let audio_context = contexts.sdl.audio().unwrap();
-    let mut events_source = contexts.sdl.event_pump().unwrap();
-    let num_frames = 0i64;
-    let frames_per_second = 60i64;
-
-    io::load_rom(cpu, file_name);
-
-    // start timer subsystem
-    let mut timer_subsys  = contexts.sdl.timer().unwrap();
-    'main: loop {
-        let instructions_per_second = cpu.get_ips() as i64;
-        //timing stuff
-        //Note: This will probably break pretty badly if the program starts lagging heavily
-        let start_frame_time = timer_subsys.ticks() as i64;
-        let frame_expected_time = 
-            ((num_frames+1)*1000)/frames_per_second-(num_frames*1000)/frames_per_second;
-        let end_frame_time = frame_expected_time + start_frame_time;
-        let instructions_by_frame_end = (frame_expected_time*instructions_per_second)/1000;
-
-        {
-            for event in events_source.poll_iter() {
-                match event {
-                    Event::Quit {..} => break 'main,
-                    Event::KeyDown {keycode: Some(keycode), ..} => match keycode {
-                        // Exit emulator
-                        Keycode::Escape    => break 'main,
-                        // Increase emulator speed
-                        Keycode::Q           => {
-                            cpu.increase_ips();
-                        }
-                        // Decrease emulator speed
-                        Keycode::A           => {
-                            cpu.decrease_ips();
-                        }
-                        // Reset emulator
-                        Keycode::Backspace => {
-                            cpu.reset();
-                            io::load_rom(cpu, file_name);
-                        }
-                        // Pass input into Chip8 io routine
-                        keycode => io::parse_input(cpu, keycode, io::KeyState::KeyDown),
-                    }
-                    Event::KeyUp {keycode: Some(keycode), ..} => io::parse_input(cpu, keycode, io::KeyState::KeyUp),
-                    _ => (),
-                };
-            }
-            for _ in 0..instructions_by_frame_end {
-                match cpu.exec_instruction() {
-                    Err(v) => println!("Error in cpu.exec_instruction(): {}", v),
-                    Ok(()) => (),
-                }
+#[cfg(feature = "sdl")]
+const DEFAULT_FRONTEND: &str = "sdl";
+#[cfg(not(feature = "sdl"))]
+const DEFAULT_FRONTEND: &str = "tui";
+
+/// Parses an option given on the command line, or failing that in the config
+/// file, exiting with an error message if it is invalid.
+fn parse_setting<T, F>(value: Option<&str>, parse: F) -> Option<T>
+    where F: FnOnce(&str) -> Result<T, String>
+{
+    value.map(|spec| match parse(spec) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    })
+}
+
+#[cfg(feature = "scripting")]
+fn load_script(script_name: &str) -> Box<dyn Hook> {
+    match ScriptHook::load(script_name) {
+        Ok(hook) => Box::new(hook),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_script(_script_name: &str) -> Box<dyn Hook> {
+    println!("chippy was built without scripting support, rebuild with --features scripting");
+    std::process::exit(2);
+}
+
+/// Loads the ROM database in `dir`, or the default one if it exists.
+fn load_rom_db(dir: Option<&str>) -> Option<RomDb> {
+    match dir {
+        Some(dir) => parse_setting(Some(dir), |dir| RomDb::load(std::path::Path::new(dir))),
+        None => RomDb::default_path().filter(|path| path.exists()).and_then(|path| match RomDb::load(&path) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                println!("{}", e);
+                None
             }
-            io::draw_screen(&mut contexts, &cpu);
+        }),
+    }
+}
 
+/// Shows the launcher on the chosen front end, returning the rom picked or
+/// exiting if there's nothing to pick or the user quits.
+fn launch(frontend_name: &str, config: &Config, db: Option<&RomDb>,
+          palette: Palette, flicker: FlickerMode,
+          #[cfg_attr(not(feature = "sdl"), allow(unused_variables))] effects: Effects) -> String {
+    let dirs: Vec<std::path::PathBuf> = config.get("rom_dirs").unwrap_or(".")
+        .split(',').map(|dir| std::path::PathBuf::from(dir.trim())).collect();
+    let recent = launcher::recent_path().map(|path| launcher::load_recent(&path)).unwrap_or_default();
+    let entries = launcher::entries(&recent, &launcher::find_roms(&dirs), db);
+    if entries.is_empty() {
+        println!("No rom given and none found in {}, set rom_dirs in the config file to a comma separated list of directories",
+                 config.get("rom_dirs").unwrap_or("the current directory"));
+        std::process::exit(2);
+    }
+    let picked = match frontend_name {
+        #[cfg(feature = "sdl")]
+        "sdl" => sdl::with_contexts(move |contexts| {
+            let texture_creator = contexts.canvas.texture_creator();
+            let audio = sdl::SdlAudio::new(&contexts.sdl);
+            let input = sdl::SdlInput::new(&contexts.sdl);
+            let clock = sdl::SdlClock::new(&contexts.sdl);
+            let mut frontend = Frontend {
+                video: sdl::SdlVideo::new(contexts, &texture_creator, palette, flicker, effects),
+                audio,
+                input,
+                clock,
+            };
+            launcher::run(&mut frontend, &entries, db)
+        }),
+        "tui" | "tui-braille" => {
+            let glyphs = if frontend_name == "tui" { Glyphs::HalfBlock } else { Glyphs::Braille };
+            let mut frontend = Frontend {
+                video: tui::TuiVideo::new(glyphs, palette, flicker),
+                audio: tui::TuiAudio::new(),
+                input: tui::TuiInput::new(),
+                clock: SystemClock::new(),
+            };
+            launcher::run(&mut frontend, &entries, db)
+        }
+        _ => {
+            println!("A rom file is needed with the {} front end", frontend_name);
+            std::process::exit(2);
         }
+    };
+    match picked {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => std::process::exit(0),
+    }
+}
 
-        //timing stuff
-        let current_time = timer_subsys.ticks();
-        //println!("{}", frame_time_elapsed);
-        timer_subsys.delay((end_frame_time as u32) - current_time);
-        cpu.decr_dt();
-        cpu.decr_st();
+/// `chippy recompile`, writing a Rust crate that runs a ROM natively.
+fn recompile_main(args: Vec<String>) {
+    let mut file_name = String::new();
+    let mut out_dir = String::new();
+    let mut crate_name: Option<String> = None;
+    let mut chippy_path = env!("CARGO_MANIFEST_DIR").to_string();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Recompile a rom into a standalone Rust program");
+        ap.refer(&mut crate_name)
+            .add_option(&["--name"], StoreOption, "Name of the crate, by default taken from the rom file name");
+        ap.refer(&mut chippy_path)
+            .add_option(&["--chippy"], Store, "Path to the chippy crate to build against");
+        ap.refer(&mut file_name)
+            .add_argument("<Rom File>", Store, "Name of rom file")
+            .required();
+        ap.refer(&mut out_dir)
+            .add_argument("<Output Directory>", Store, "Where to write the crate")
+            .required();
+        if let Err(code) = ap.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            std::process::exit(code);
+        }
+    }
+    let rom = match io::read_rom(&file_name) {
+        Ok(rom) => rom,
+        Err(e) => {
+            println!("Error reading {}: {}", file_name, e);
+            std::process::exit(2);
+        }
+    };
+    let crate_name = crate_name.unwrap_or_else(|| recompile::crate_name(&file_name));
+    match recompile::write_crate(std::path::Path::new(&out_dir), &rom, &crate_name, &chippy_path) {
+        Ok(analysis) => println!("Wrote {} to {}: {}", crate_name, out_dir, analysis.summary()),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// `chippy test-suite`, checking test ROMs against golden images.
+fn test_suite_main(args: Vec<String>) {
+    let mut roms = "test-suite/roms".to_string();
+    let mut golden = "test-suite/golden".to_string();
+    let mut profile: Option<String> = None;
+    let mut bless = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Run test ROMs headless and compare their screens against golden images");
+        ap.refer(&mut roms)
+            .add_option(&["--roms"], Store, "Directory holding the test ROMs");
+        ap.refer(&mut golden)
+            .add_option(&["--golden"], Store, "Directory holding the golden images");
+        ap.refer(&mut profile)
+            .add_option(&["--quirks"], StoreOption, "Only run with this quirks profile");
+        ap.refer(&mut bless)
+            .add_option(&["--bless"], StoreTrue, "Write the current screens as the golden images");
+        if let Err(code) = ap.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            std::process::exit(code);
+        }
+    }
+    let profiles: Vec<&str> = match profile {
+        Some(ref profile) => vec![profile.as_str()],
+        None => quirks::PROFILES.to_vec(),
+    };
+    match conformance::run_suite(std::path::Path::new(&roms), std::path::Path::new(&golden), &profiles, bless) {
+        Ok((report, failed)) => {
+            print!("{}", report);
+            if failed {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// `chippy lockstep`, checking a ROM against a trace from another emulator.
+fn lockstep_main(args: Vec<String>) {
+    let mut file_name = String::new();
+    let mut trace_file = String::new();
+    let mut quirks_spec = "chippy".to_string();
+    let mut timers_from_trace = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Run a rom in lockstep with a state trace, stopping where they differ");
+        ap.refer(&mut quirks_spec)
+            .add_option(&["--quirks"], Store, "Interpreter quirks to follow: chippy, cosmac, schip or xochip");
+        ap.refer(&mut timers_from_trace)
+            .add_option(&["--timers-from-trace"], StoreTrue, "Copy the timers from the trace instead of checking them");
+        ap.refer(&mut file_name)
+            .add_argument("<Rom File>", Store, "Name of rom file")
+            .required();
+        ap.refer(&mut trace_file)
+            .add_argument("<Trace File>", Store, "Trace to check against")
+            .required();
+        if let Err(code) = ap.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            std::process::exit(code);
+        }
+    }
+    let mut cpu = Cpu::new();
+    cpu.set_quirks(parse_setting(Some(&quirks_spec), Quirks::parse).unwrap_or_default());
+    let result = io::read_rom(&file_name)
+        .map_err(|e| format!("Error reading {}: {}", file_name, e))
+        .and_then(|rom| cpu.memory.load_rom(&rom))
+        .and_then(|_| std::fs::File::open(&trace_file).map_err(|e| format!("Error reading {}: {}", trace_file, e)))
+        .and_then(|trace| lockstep::run(&mut cpu, std::io::BufReader::new(trace), timers_from_trace));
+    match result {
+        Ok(lockstep::Outcome::Matched(steps)) => println!("All {} instructions matched", steps),
+        Ok(lockstep::Outcome::Diverged(divergence)) => {
+            print!("{}", divergence);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
     }
 }
 
 fn main() {
-    let mut instructions_per_second = cpu::DEFAULT_INS_PER_SECOND;
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("recompile") => return recompile_main(args[1..].to_vec()),
+        Some("test-suite") => return test_suite_main(args[1..].to_vec()),
+        Some("lockstep") => return lockstep_main(args[1..].to_vec()),
+        _ => (),
+    }
+    let mut instructions_per_second: Option<u64> = None;
     let mut file_name = String::new();
+    let mut frontend_name = DEFAULT_FRONTEND.to_string();
+    let mut frames = 0u64;
+    let mut palette_spec: Option<String> = None;
+    let mut flicker_spec: Option<String> = None;
+    let mut effects_spec: Option<String> = None;
+    let mut quirks_spec: Option<String> = None;
+    let mut rom_db_dir: Option<String> = None;
+    let mut script_name: Option<String> = None;
+    let mut cheats = false;
+    let mut reload = false;
+    let mut reload_keep_state = false;
+    let mut gdb_address: Option<String> = None;
+    let mut trace_file: Option<String> = None;
+    let mut profile_file: Option<String> = None;
+    let mut coverage_file: Option<String> = None;
+    let mut trace_format = "text".to_string();
+    let mut trace_addresses: Option<String> = None;
+    let mut trace_window: Option<String> = None;
 
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Chip8 emulator");
         ap.refer(&mut instructions_per_second)
-            .add_option(&["-f", "--freq"], Store, "Instructions per second");
+            .add_option(&["-f", "--freq"], StoreOption, "Instructions per second");
+        ap.refer(&mut frontend_name)
+            .add_option(&["--frontend"], Store,
+                        "Where to run the emulator: sdl, tui, tui-braille or headless");
+        ap.refer(&mut frames)
+            .add_option(&["--frames"], Store,
+                        "Number of frames to run for in headless mode, 0 to run forever");
+        ap.refer(&mut palette_spec)
+            .add_option(&["-p", "--palette"], StoreOption,
+                        "Colour palette: classic, amber, green, lcd, octo, or a comma separated list of hex colours");
+        ap.refer(&mut flicker_spec)
+            .add_option(&["--flicker"], StoreOption,
+                        "Flicker reduction: off, decay[:percent], blend[:frames] or hold");
+        ap.refer(&mut effects_spec)
+            .add_option(&["--crt"], StoreOption,
+                        "Post-processing effects: comma separated list of scanlines, grid, bloom and curvature");
+        ap.refer(&mut quirks_spec)
+            .add_option(&["--quirks"], StoreOption,
+                        "Interpreter quirks to follow: chippy, cosmac, schip or xochip");
+        ap.refer(&mut rom_db_dir)
+            .add_option(&["--rom-db"], StoreOption,
+                        "chip-8-database directory to look the rom up in for its quirks, speed, keys and colours");
+        ap.refer(&mut script_name)
+            .add_option(&["--script"], StoreOption,
+                        "Rhai script to run alongside the rom (needs the scripting feature)");
+        ap.refer(&mut cheats)
+            .add_option(&["--cheats"], StoreTrue,
+                        "Apply saved cheats for this rom and read cheat commands from stdin");
+        ap.refer(&mut reload)
+            .add_option(&["--reload"], StoreTrue,
+                        "Reset and reload the rom whenever its file changes");
+        ap.refer(&mut reload_keep_state)
+            .add_option(&["--reload-keep-state"], StoreTrue,
+                        "Reload only the rom's code whenever its file changes, keeping the machine's state");
+        ap.refer(&mut gdb_address)
+            .add_option(&["--gdb"], StoreOption,
+                        "Wait for a gdb connection on host:port or unix:<path> before running");
+        ap.refer(&mut trace_file)
+            .add_option(&["--trace"], StoreOption, "Log every executed instruction to this file");
+        ap.refer(&mut trace_format)
+            .add_option(&["--trace-format"], Store, "Trace file format: text, binary or state");
+        ap.refer(&mut trace_addresses)
+            .add_option(&["--trace-addresses"], StoreOption,
+                        "Only trace instructions in this hex address range, e.g. 200-2ff");
+        ap.refer(&mut trace_window)
+            .add_option(&["--trace-window"], StoreOption,
+                        "Only trace this range of instructions, counting from 0, e.g. 1000-1999");
+        ap.refer(&mut profile_file)
+            .add_option(&["--profile"], StoreOption,
+                        "Count instructions while running and write a hotspot report to this file on exit");
+        ap.refer(&mut coverage_file)
+            .add_option(&["--coverage"], StoreOption,
+                        "Track which bytes are used as code and data, writing a report (or a .ppm map) to this file on exit");
         ap.refer(&mut file_name)
-            .add_argument("<Rom File>", Store, "Name of rom file")
-            .required();
+            .add_argument("<Rom File>", Store, "Name of rom file, or leave out to pick one from a list");
         ap.parse_args_or_exit();
     }
+    let config = Config::load();
+    let flicker = parse_setting(flicker_spec.as_deref().or(config.get("flicker")), FlickerMode::parse)
+        .unwrap_or_default();
+    let effects = parse_setting(effects_spec.as_deref().or(config.get("crt")), Effects::parse)
+        .unwrap_or_default();
+    let rom_db = load_rom_db(rom_db_dir.as_deref().or(config.get("rom_db")));
+    if file_name.is_empty() {
+        let palette = parse_setting(palette_spec.as_deref().or(config.get("palette")), Palette::parse)
+            .unwrap_or_default();
+        file_name = launch(&frontend_name, &config, rom_db.as_ref(), palette, flicker, effects);
+    }
+    if frontend_name != "headless" {
+        launcher::remember(&file_name);
+    }
+
+    // Settings for the rom in the database take priority over the config
+    // file, but not the command line
     let mut cpu = Cpu::new();
-    cpu.set_ips(instructions_per_second);
+    if let Some(quirks) = parse_setting(config.get("quirks"), Quirks::parse) {
+        cpu.set_quirks(quirks);
+    }
+    let rom_info = io::configure(&mut cpu, &file_name, rom_db.as_ref());
+    if let Some(ips) = instructions_per_second {
+        cpu.set_ips(ips);
+    }
+    if let Some(quirks) = parse_setting(quirks_spec.as_deref(), Quirks::parse) {
+        cpu.set_quirks(quirks);
+    }
+    let rom_palette = rom_info.as_ref().and_then(RomInfo::palette).and_then(|palette| match palette {
+        Ok(palette) => Some(palette),
+        Err(e) => {
+            println!("Ignoring the rom's colours: {}", e);
+            None
+        }
+    });
+
+    let palette = parse_setting(palette_spec.as_deref(), Palette::parse)
+        .or(rom_palette)
+        .or_else(|| parse_setting(config.get("palette"), Palette::parse))
+        .unwrap_or_default();
 
-    sdl::with_contexts(move |contexts| main_loop(contexts, &mut cpu, &file_name));
+    let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+    if let Some(script_name) = script_name {
+        hooks.push(load_script(&script_name));
+    }
+    if cheats {
+        if frontend_name.starts_with("tui") {
+            println!("The cheat console reads from stdin, so it can't be used with the terminal front end");
+            std::process::exit(2);
+        }
+        let hook = io::read_rom(&file_name)
+            .map_err(|e| format!("Error reading {}: {}", file_name, e))
+            .and_then(|rom| CheatHook::new(&io::rom_hash(&rom)));
+        match hook {
+            Ok(hook) => hooks.push(Box::new(hook)),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(trace_file) = trace_file {
+        let filter = TraceFilter {
+            addresses: parse_setting(trace_addresses.as_deref(), |spec| trace::parse_range(spec, 16))
+                .map(|(first, last)| (first as u16, last as u16)),
+            window: parse_setting(trace_window.as_deref(), |spec| trace::parse_range(spec, 10)),
+        };
+        let format = parse_setting(Some(&trace_format), TraceFormat::parse).unwrap_or(TraceFormat::Text);
+        match TraceHook::create(&trace_file, format, filter) {
+            Ok(hook) => hooks.push(Box::new(hook)),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(profile_file) = profile_file {
+        hooks.push(Box::new(ProfileHook::new(&profile_file)));
+    }
+    if let Some(coverage_file) = coverage_file {
+        match io::read_rom(&file_name) {
+            Ok(rom) => hooks.push(Box::new(CoverageHook::new(&coverage_file, rom.len()))),
+            Err(e) => {
+                println!("Error reading {}: {}", file_name, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if reload || reload_keep_state {
+        hooks.push(Box::new(ReloadHook::new(&file_name, reload_keep_state)));
+    }
+    if let Some(address) = gdb_address {
+        match gdb::listen(&address) {
+            Ok(connection) => hooks.push(Box::new(GdbStub::new(connection))),
+            Err(e) => {
+                println!("Error listening on {}: {}", address, e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    match frontend_name.as_str() {
+        #[cfg(feature = "sdl")]
+        "sdl" => sdl::with_contexts(move |contexts| {
+            let texture_creator = contexts.canvas.texture_creator();
+            let audio = sdl::SdlAudio::new(&contexts.sdl);
+            let mut input = sdl::SdlInput::new(&contexts.sdl);
+            if let Some(ref info) = rom_info {
+                input.map_actions(info);
+            }
+            let clock = sdl::SdlClock::new(&contexts.sdl);
+            let mut frontend = Frontend {
+                video: sdl::SdlVideo::new(contexts, &texture_creator, palette, flicker, effects),
+                audio,
+                input,
+                clock,
+            };
+            frontend::main_loop(&mut frontend, &mut cpu, &file_name, &mut hooks);
+        }),
+        "tui" | "tui-braille" => {
+            let glyphs = if frontend_name == "tui" { Glyphs::HalfBlock } else { Glyphs::Braille };
+            let mut frontend = Frontend {
+                video: tui::TuiVideo::new(glyphs, palette, flicker),
+                audio: tui::TuiAudio::new(),
+                input: tui::TuiInput::new(),
+                clock: SystemClock::new(),
+            };
+            frontend::main_loop(&mut frontend, &mut cpu, &file_name, &mut hooks);
+        },
+        "headless" => {
+            let mut frontend = Frontend {
+                video: headless::HeadlessVideo::new(),
+                audio: NullAudio,
+                input: headless::HeadlessInput::new(if frames == 0 { None } else { Some(frames) }),
+                clock: headless::VirtualClock::new(),
+            };
+            frontend::main_loop(&mut frontend, &mut cpu, &file_name, &mut hooks);
+            print!("{}", headless::framebuffer_text(&cpu));
+        },
+        _ => {
+            println!("Unknown frontend \"{}\", expected sdl, tui, tui-braille or headless", frontend_name);
+            std::process::exit(2);
+        }
+    }
 }
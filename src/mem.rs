@@ -1,47 +1,67 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::fmt;
-use std::path::Path;
-use std::fs::File;
-use std::io;
-use std::io::Read;
 
 pub const MEM_SIZE : usize =   0x1000;
 pub const SCREEN_WIDTH : usize =   64;
 pub const SCREEN_HEIGHT: usize =   32;
 pub const SPRITE_DATA_OFFSET: u16 = 0;
+pub const STATE_SIZE: usize = MEM_SIZE + SCREEN_WIDTH*SCREEN_HEIGHT;
+
+/// Coverage flags, set for each address once it is fetched as an
+/// instruction, read as data by `Dxyn` or `Fx65`, or written.
+pub const COVER_FETCH: u8 = 1;
+pub const COVER_READ: u8 = 2;
+pub const COVER_WRITE: u8 = 4;
+
+/// Size of the pages tracked by `take_dirty_pages`.
+pub const PAGE_SIZE: usize = MEM_SIZE/64;
 
 pub struct Mem {
     mem : [u8; MEM_SIZE],
-    vmem: [[bool; 256]; 256]
+    vmem: [[bool; 256]; 256],
+    journal: Option<Vec<(u16, u8)>>,
+    coverage: Option<Box<[u8; MEM_SIZE]>>,
+    /// One bit per `PAGE_SIZE` bytes, set when the page is written.
+    dirty_pages: u64,
 }
 
 impl Mem {
     //Manipulate large amounts of memory directly
-    pub fn load_rom(&mut self, file_name: &str) -> Result<(), io::Error> {
-
-        let file_name= Path::new(file_name);
-        let mut f = try!(File::open(file_name));
-        let mut file_vec: Vec<u8> = Vec::new();
-        let bytes_read = try!(f.read_to_end(&mut file_vec));
-        println!("{} bytes read", bytes_read);
-        if bytes_read > (MEM_SIZE - 0x200) {
-            let e = io::Error::new(io::ErrorKind::Other, "Rom file too large");
-            return Err(e);
+    /// Checks that `rom` fits in memory, without loading it.
+    pub fn check_rom_size(rom: &[u8]) -> Result<(), String> {
+        if rom.len() > (MEM_SIZE - 0x200) {
+            return Err(format!("Rom is too large ({} bytes, at most {} fit)", rom.len(), MEM_SIZE - 0x200));
         }
-        self.memset(0x200, &file_vec);
         Ok(())
-        
+    }
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), String> {
+        Mem::check_rom_size(rom)?;
+        self.mem[0x200..0x200+rom.len()].copy_from_slice(rom);
+        self.dirty_pages = !0;
+        Ok(())
     }
     pub fn memset(&mut self, addr: usize, data: &Vec<u8>){
         for (i, v) in data.iter().enumerate() {
             self.mem[addr+i]=*v;
+            self.record_write(addr+i, *v);
         }
     }
     pub fn get_vec(&self, addr : usize, n: usize) -> Vec<u8> {
         self.mem[addr..addr+n].to_vec()
     }
+    /// Like `get_vec`, but wraps around the end of memory instead of
+    /// panicking, as the instructions that read at I do.
+    pub fn get_vec_wrapping(&self, addr: usize, n: usize) -> Vec<u8> {
+        (addr..addr+n).map(|addr| self.mem[addr % MEM_SIZE]).collect()
+    }
+    /// Gives direct access to all of memory, e.g. for external debuggers.
+    pub fn raw_mut(&mut self) -> &mut [u8; MEM_SIZE] {
+        self.dirty_pages = !0;
+        &mut self.mem
+    }
     pub fn reset(&mut self){
         self.mem = [0u8; MEM_SIZE];
+        self.dirty_pages = !0;
         self.clear_screen();
         self.set_sprite_data();
     }
@@ -58,10 +78,76 @@ impl Mem {
     #[allow(dead_code)]
     pub fn write_u16(&mut self, addr: usize, val: u16) {
         let mut foo = &mut self.mem[addr..addr+2];
-        foo.write_u16::<BigEndian>(val).unwrap()
+        foo.write_u16::<BigEndian>(val).unwrap();
+        self.record_write(addr, (val >> 8) as u8);
+        self.record_write(addr+1, val as u8);
     }
     pub fn write_u8(&mut self, addr: usize, val: u8) {
         self.mem[addr]=val;
+        self.record_write(addr, val);
+    }
+    /// Writes a byte on behalf of a script, debugger or cheat rather than
+    /// the program. It isn't journalled or counted as a write in the
+    /// coverage, but its page is marked dirty so cached code is rebuilt.
+    pub fn poke(&mut self, addr: usize, val: u8) {
+        self.mem[addr]=val;
+        self.dirty_pages |= 1 << (addr/PAGE_SIZE);
+    }
+
+    //write journal, for watching what a program writes
+    /// Starts or stops recording writes made through `write_u8`,
+    /// `write_u16` and `memset`.
+    pub fn set_journal(&mut self, enabled: bool) {
+        self.journal = if enabled { Some(Vec::new()) } else { None };
+    }
+    pub fn journal_enabled(&self) -> bool {
+        self.journal.is_some()
+    }
+    /// Writes recorded since the last `clear_writes`, as (address, value).
+    pub fn writes(&self) -> &[(u16, u8)] {
+        match self.journal {
+            Some(ref journal) => journal,
+            None => &[],
+        }
+    }
+    pub fn clear_writes(&mut self) {
+        if let Some(ref mut journal) = self.journal {
+            journal.clear();
+        }
+    }
+    fn record_write(&mut self, addr: usize, val: u8) {
+        if let Some(ref mut journal) = self.journal {
+            journal.push((addr as u16, val));
+        }
+        self.mark(addr, 1, COVER_WRITE);
+        self.dirty_pages |= 1 << (addr/PAGE_SIZE);
+    }
+
+    /// Returns which pages have been written since the last call, as a bit
+    /// per `PAGE_SIZE` bytes, and clears them. Used to throw away decoded
+    /// code when it is overwritten.
+    pub fn take_dirty_pages(&mut self) -> u64 {
+        ::std::mem::replace(&mut self.dirty_pages, 0)
+    }
+
+    //coverage, for finding code, data and dead bytes in a rom
+    /// Starts or stops tracking coverage. Turning it on clears what was
+    /// tracked before.
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = if enabled { Some(Box::new([0u8; MEM_SIZE])) } else { None };
+    }
+    /// The `COVER_*` flags for every address, if coverage is being tracked.
+    pub fn coverage(&self) -> Option<&[u8; MEM_SIZE]> {
+        self.coverage.as_deref()
+    }
+    /// Sets `flag` for `len` addresses starting at `addr`, if coverage is
+    /// being tracked. Addresses past the end of memory are ignored.
+    pub fn mark(&mut self, addr: usize, len: usize, flag: u8) {
+        if let Some(ref mut coverage) = self.coverage {
+            for addr in addr..addr+len {
+                coverage[addr % MEM_SIZE] |= flag;
+            }
+        }
     }
 
     //vmem functions
@@ -75,12 +161,25 @@ impl Mem {
     pub fn get_cell(&self, x: u8, y: u8) -> bool {
         self.vmem[x as usize][y as usize]
     }
-    pub fn draw_sprite(&mut self, addr: u16, x: u8, y: u8, n: u8) -> bool {
-        let sprite_vec: Vec<u8> = self.get_vec(addr as usize, n as usize);
+    /// Copies the visible screen out row by row, one byte per pixel.
+    pub fn framebuffer(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(SCREEN_WIDTH*SCREEN_HEIGHT);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                frame.push(self.vmem[x][y] as u8);
+            }
+        }
+        frame
+    }
+    /// Draws a sprite, returning whether any pixel was erased. Pixels past
+    /// the edge of the screen wrap around if `wrap` is set, or are clipped.
+    pub fn draw_sprite(&mut self, addr: u16, x: u8, y: u8, n: u8, wrap: bool) -> bool {
+        let sprite_vec: Vec<u8> = self.get_vec_wrapping(addr as usize, n as usize);
         let mut ret_val = false;
         for (yi, val) in sprite_vec.iter().enumerate() {
 
             let mut draw_pixel = |x: usize, y: usize| {
+                let (x, y) = if wrap { (x % SCREEN_WIDTH, y % SCREEN_HEIGHT) } else { (x, y) };
                 if x < SCREEN_WIDTH && y < SCREEN_HEIGHT {
                     self.vmem[x][y]=!self.vmem[x][y];
                     if !self.vmem[x][y] { 
@@ -99,6 +198,25 @@ impl Mem {
         }
         ret_val
     }
+    /// Appends the contents of memory and the visible screen to `out`.
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.mem);
+        out.extend(self.framebuffer());
+    }
+    /// Restores a state written by `save_state`, which must be exactly
+    /// `STATE_SIZE` bytes long.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != STATE_SIZE {
+            return Err(format!("Memory state is {} bytes, expected {}", data.len(), STATE_SIZE));
+        }
+        self.mem.copy_from_slice(&data[..MEM_SIZE]);
+        self.dirty_pages = !0;
+        self.clear_screen();
+        for (i, &pixel) in data[MEM_SIZE..].iter().enumerate() {
+            self.vmem[i % SCREEN_WIDTH][i / SCREEN_WIDTH] = pixel != 0;
+        }
+        Ok(())
+    }
     fn set_sprite_data(&mut self){
         let sprite_data = 
             vec![0xf0, 0x90, 0x90, 0x90, 0xf0,
@@ -117,7 +235,9 @@ impl Mem {
                  0xE0, 0x90, 0x90, 0x90, 0xE0,
                  0xF0, 0x80, 0xF0, 0x80, 0xF0,
                  0xF0, 0x80, 0xF0, 0x80, 0x80];
-        self.memset(SPRITE_DATA_OFFSET as usize, &sprite_data);
+        // Not a write by the program, so not journaled
+        let start = SPRITE_DATA_OFFSET as usize;
+        self.mem[start..start+sprite_data.len()].copy_from_slice(&sprite_data);
     }
     pub fn get_sprite_addr(sprite_no: u8) -> u16 {
         SPRITE_DATA_OFFSET + ((5*sprite_no) as u16)
@@ -128,7 +248,10 @@ impl Default for Mem {
     fn default() -> Mem {
         let mut ret_val = Mem {
             mem : [0u8; MEM_SIZE],
-            vmem: [[false; 256]; 256]
+            vmem: [[false; 256]; 256],
+            journal: None,
+            coverage: None,
+            dirty_pages: !0,
         };
         ret_val.set_sprite_data();
         ret_val
@@ -204,3 +327,22 @@ fn test_get_vec(){
         panic!("Expected {:?}, got {:?}", test_vec, res_vec);
     }
 }
+
+#[test]
+fn test_write_journal(){
+    let mut mem: Mem = Default::default();
+    mem.write_u8(0x300, 1);
+    if !mem.writes().is_empty() {
+        panic!("Writes should not be recorded until the journal is on");
+    }
+    mem.set_journal(true);
+    mem.write_u8(0x300, 2);
+    mem.write_u16(0x310, 0xabcd);
+    if mem.writes() != [(0x300, 2), (0x310, 0xab), (0x311, 0xcd)] {
+        panic!("Unexpected journal {:?}", mem.writes());
+    }
+    mem.clear_writes();
+    if !mem.writes().is_empty() {
+        panic!("Journal should be empty after clearing");
+    }
+}
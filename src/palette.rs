@@ -0,0 +1,207 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    /// Linearly interpolates between `self` (amount 0) and `other` (amount 255).
+    pub fn blend(self, other: Rgb, amount: u8) -> Rgb {
+        let mix = |a: u8, b: u8| {
+            let a = a as u32;
+            let b = b as u32;
+            let t = amount as u32;
+            ((a*(255-t) + b*t)/255) as u8
+        };
+        Rgb(mix(self.0, other.0), mix(self.1, other.1), mix(self.2, other.2))
+    }
+
+    /// Parses a colour written as `RRGGBB`, with an optional leading `#`.
+    pub fn parse(hex: &str) -> Result<Rgb, String> {
+        let digits = hex.trim().trim_start_matches('#');
+        if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid colour \"{}\", expected RRGGBB", hex));
+        }
+        let channel = |i: usize| u8::from_str_radix(&digits[i..i+2], 16).unwrap();
+        Ok(Rgb(channel(0), channel(2), channel(4)))
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+/// Colours used to draw the display, keypad and UI text.
+///
+/// `planes` is indexed by the XO-CHIP plane bits of a pixel: 0 is the
+/// background, 1 and 2 are the first and second planes, and 3 is used where
+/// both planes are lit. Plain CHIP-8 programs only ever use 0 and 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub planes: [Rgb; 4],
+    pub keypad: Rgb,
+    pub keypad_trail: Rgb,
+    pub ui_text: Rgb,
+    pub ui_background: Rgb,
+}
+
+pub const BUILTIN_NAMES: [&str; 5] = ["classic", "amber", "green", "lcd", "octo"];
+
+impl Palette {
+    pub fn pixel(&self, planes: u8) -> Rgb {
+        self.planes[(planes & 0x3) as usize]
+    }
+
+    /// Looks up one of the built in palettes by name.
+    pub fn builtin(name: &str) -> Option<Palette> {
+        let (planes, keypad, keypad_trail, ui_text, ui_background) = match name {
+            "classic" => (
+                [Rgb(0x00, 0x00, 0x00), Rgb(0xff, 0xff, 0xff), Rgb(0xaa, 0xaa, 0xaa), Rgb(0x55, 0x55, 0x55)],
+                Rgb(0xff, 0xff, 0xff), Rgb(0x00, 0x00, 0xff), Rgb(0xff, 0xff, 0xff), Rgb(0x00, 0x00, 0x00)),
+            "amber" => (
+                [Rgb(0x1a, 0x0f, 0x00), Rgb(0xff, 0xb0, 0x00), Rgb(0xb3, 0x6b, 0x00), Rgb(0xff, 0xd8, 0x80)],
+                Rgb(0xff, 0xb0, 0x00), Rgb(0x80, 0x40, 0x00), Rgb(0xff, 0xb0, 0x00), Rgb(0x0d, 0x07, 0x00)),
+            "green" => (
+                [Rgb(0x00, 0x14, 0x00), Rgb(0x33, 0xff, 0x33), Rgb(0x1a, 0x99, 0x1a), Rgb(0xaa, 0xff, 0xaa)],
+                Rgb(0x33, 0xff, 0x33), Rgb(0x00, 0x66, 0x00), Rgb(0x33, 0xff, 0x33), Rgb(0x00, 0x0a, 0x00)),
+            "lcd" => (
+                [Rgb(0xf9, 0xff, 0xb3), Rgb(0x3d, 0x80, 0x26), Rgb(0xab, 0xcc, 0x47), Rgb(0x00, 0x13, 0x1a)],
+                Rgb(0x3d, 0x80, 0x26), Rgb(0xab, 0xcc, 0x47), Rgb(0x00, 0x13, 0x1a), Rgb(0xf9, 0xff, 0xb3)),
+            "octo" => (
+                [Rgb(0x99, 0x66, 0x00), Rgb(0xff, 0xcc, 0x00), Rgb(0xff, 0x66, 0x00), Rgb(0x66, 0x22, 0x00)],
+                Rgb(0xff, 0xcc, 0x00), Rgb(0xff, 0x66, 0x00), Rgb(0xff, 0xcc, 0x00), Rgb(0x66, 0x22, 0x00)),
+            _ => return None,
+        };
+        Some(Palette {
+            name: name.to_string(),
+            planes,
+            keypad,
+            keypad_trail,
+            ui_text,
+            ui_background,
+        })
+    }
+
+    /// Parses a palette description, which is either the name of a built in
+    /// palette or a comma separated list of hex colours.
+    ///
+    /// A colour list holds two to four plane colours, optionally followed by
+    /// the keypad trail colour and the UI text colour, e.g.
+    /// `#000000,#ffffff,#ff0000,#ffff00,#0000ff,#ffffff`. Planes that are left
+    /// out are drawn in the first plane's colour.
+    pub fn parse(spec: &str) -> Result<Palette, String> {
+        let spec = spec.trim();
+        if let Some(palette) = Palette::builtin(spec) {
+            return Ok(palette);
+        }
+        if !spec.contains(',') && !spec.starts_with('#') {
+            return Err(format!("Unknown palette \"{}\", expected one of {} or a list of hex colours",
+                               spec, BUILTIN_NAMES.join(", ")));
+        }
+        let mut colours = Vec::new();
+        for hex in spec.split(',') {
+            colours.push(Rgb::parse(hex)?);
+        }
+        if colours.len() < 2 || colours.len() > 6 {
+            return Err(format!("Expected 2 to 6 colours in palette \"{}\", got {}", spec, colours.len()));
+        }
+        let plane_count = colours.len().min(4);
+        let mut planes = [colours[1]; 4];
+        planes[..plane_count].copy_from_slice(&colours[..plane_count]);
+        let keypad_trail = colours.get(4).cloned().unwrap_or(planes[1].blend(planes[0], 128));
+        let ui_text = colours.get(5).cloned().unwrap_or(planes[1]);
+        Ok(Palette {
+            name: "custom".to_string(),
+            planes,
+            keypad: ui_text,
+            keypad_trail,
+            ui_text,
+            ui_background: planes[0],
+        })
+    }
+
+    /// Returns the built in palette that follows this one, wrapping around at
+    /// the end of the list. Custom palettes are followed by the first one.
+    pub fn next(&self) -> Palette {
+        let index = BUILTIN_NAMES.iter()
+            .position(|name| *name == self.name)
+            .map(|i| (i + 1) % BUILTIN_NAMES.len())
+            .unwrap_or(0);
+        Palette::builtin(BUILTIN_NAMES[index]).unwrap()
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::builtin("classic").unwrap()
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_builtin_palettes(){
+    for name in BUILTIN_NAMES.iter() {
+        match Palette::builtin(name) {
+            Some(ref palette) if palette.name == *name => (),
+            _ => panic!("Built in palette {} is missing", name),
+        }
+    }
+    let classic: Palette = Default::default();
+    if classic.pixel(0) != Rgb(0, 0, 0) || classic.pixel(1) != Rgb(255, 255, 255) {
+        panic!("Classic palette should be white on black, got {:?}", classic.planes);
+    }
+}
+
+#[test]
+fn test_parse_palette(){
+    let palette = Palette::parse("#000000,#FFFFFF,ff0000,#00ff00,#0000ff,#123456").unwrap();
+    if palette.planes != [Rgb(0, 0, 0), Rgb(255, 255, 255), Rgb(255, 0, 0), Rgb(0, 255, 0)] {
+        panic!("Plane colours parsed incorrectly: {:?}", palette.planes);
+    }
+    if palette.keypad_trail != Rgb(0, 0, 255) || palette.ui_text != Rgb(0x12, 0x34, 0x56) {
+        panic!("Keypad/UI colours parsed incorrectly: {:?}", palette);
+    }
+
+    let palette = Palette::parse("#101010,#f0f0f0").unwrap();
+    if palette.pixel(3) != Rgb(0xf0, 0xf0, 0xf0) {
+        panic!("Missing planes should use the first plane colour, got {:?}", palette.planes);
+    }
+
+    for bad in ["mauve", "#12345", "#000000", "#gg0000,#000000"].iter() {
+        if Palette::parse(bad).is_ok() {
+            panic!("Palette \"{}\" should not have parsed", bad);
+        }
+    }
+}
+
+#[test]
+fn test_palette_cycle(){
+    let mut palette: Palette = Default::default();
+    for name in BUILTIN_NAMES.iter().skip(1) {
+        palette = palette.next();
+        if palette.name != *name {
+            panic!("Expected palette {}, got {}", name, palette.name);
+        }
+    }
+    if palette.next().name != BUILTIN_NAMES[0] {
+        panic!("Palette cycle did not wrap around");
+    }
+    if Palette::parse("#000000,#ffffff").unwrap().next().name != BUILTIN_NAMES[0] {
+        panic!("Custom palettes should cycle to the first built in palette");
+    }
+}
+
+#[test]
+fn test_blend(){
+    let black = Rgb(0, 0, 0);
+    let white = Rgb(255, 255, 255);
+    if black.blend(white, 0) != black || black.blend(white, 255) != white {
+        panic!("Blend endpoints are wrong");
+    }
+    if black.blend(white, 128) != Rgb(128, 128, 128) {
+        panic!("Blend midpoint is wrong: {:?}", black.blend(white, 128));
+    }
+}
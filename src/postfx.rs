@@ -0,0 +1,317 @@
+use std::io;
+use std::io::Write;
+use cpu::Cpu;
+use flicker::FlickerFilter;
+use mem;
+use palette::{Palette, Rgb};
+
+const SCALE: usize = 6;
+const SCANLINE_STRENGTH: u8 = 96;
+const GRID_STRENGTH: u8 = 64;
+const BLOOM_RADIUS: usize = 3;
+const BLOOM_STRENGTH: u8 = 96;
+const CURVATURE: f32 = 0.08;
+
+/// An RGB image, stored row by row with three bytes per pixel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            data: vec![0u8; width*height*3],
+        }
+    }
+    pub fn get(&self, x: usize, y: usize) -> Rgb {
+        let offset = (y*self.width + x)*3;
+        Rgb(self.data[offset], self.data[offset+1], self.data[offset+2])
+    }
+    pub fn set(&mut self, x: usize, y: usize, Rgb(r, g, b): Rgb) {
+        let offset = (y*self.width + x)*3;
+        self.data[offset..offset+3].copy_from_slice(&[r, g, b]);
+    }
+    /// Writes the image as a binary PPM.
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.data)
+    }
+}
+
+/// Enlarges every pixel to a `factor`x`factor` block.
+pub fn scale(image: &Image, factor: usize) -> Image {
+    let mut out = Image::new(image.width*factor, image.height*factor);
+    for y in 0..out.height {
+        for x in 0..out.width {
+            out.set(x, y, image.get(x/factor, y/factor));
+        }
+    }
+    out
+}
+
+fn darken(Rgb(r, g, b): Rgb, amount: u8) -> Rgb {
+    Rgb(r, g, b).blend(Rgb(0, 0, 0), amount)
+}
+
+/// Darkens every other row.
+pub fn scanlines(image: &Image, strength: u8) -> Image {
+    let mut out = image.clone();
+    for y in (1..image.height).step_by(2) {
+        for x in 0..image.width {
+            out.set(x, y, darken(image.get(x, y), strength));
+        }
+    }
+    out
+}
+
+/// Darkens the last row and column of every `cell`x`cell` block, outlining
+/// each of the original pixels.
+pub fn pixel_grid(image: &Image, cell: usize, strength: u8) -> Image {
+    let mut out = image.clone();
+    for y in 0..image.height {
+        for x in 0..image.width {
+            if x % cell == cell - 1 || y % cell == cell - 1 {
+                out.set(x, y, darken(image.get(x, y), strength));
+            }
+        }
+    }
+    out
+}
+
+/// Adds a blurred copy of the image on top of itself, so that lit areas glow
+/// into their surroundings.
+pub fn bloom(image: &Image, radius: usize, strength: u8) -> Image {
+    let blur_pass = |src: &Image, dx: usize, dy: usize| {
+        let mut dst = Image::new(src.width, src.height);
+        for y in 0..src.height {
+            for x in 0..src.width {
+                let mut total = [0u32; 3];
+                let mut count = 0u32;
+                for i in 0..(2*radius + 1) {
+                    let sx = (x + i*dx) as isize - (radius*dx) as isize;
+                    let sy = (y + i*dy) as isize - (radius*dy) as isize;
+                    if sx < 0 || sy < 0 || sx as usize >= src.width || sy as usize >= src.height {
+                        continue;
+                    }
+                    let Rgb(r, g, b) = src.get(sx as usize, sy as usize);
+                    total[0] += r as u32;
+                    total[1] += g as u32;
+                    total[2] += b as u32;
+                    count += 1;
+                }
+                dst.set(x, y, Rgb((total[0]/count) as u8, (total[1]/count) as u8, (total[2]/count) as u8));
+            }
+        }
+        dst
+    };
+    let blurred = blur_pass(&blur_pass(image, 1, 0), 0, 1);
+    let mut out = image.clone();
+    for (o, b) in out.data.iter_mut().zip(blurred.data.iter()) {
+        let glow = (*b as u32)*(strength as u32)/255;
+        *o = (*o as u32 + glow).min(255) as u8;
+    }
+    out
+}
+
+/// Bends the image outwards like the face of a CRT. Areas that fall outside
+/// of the source image are left black.
+pub fn curvature(image: &Image, amount: f32) -> Image {
+    let mut out = Image::new(image.width, image.height);
+    let half_w = image.width as f32/2.0;
+    let half_h = image.height as f32/2.0;
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let u = (x as f32 + 0.5 - half_w)/half_w;
+            let v = (y as f32 + 0.5 - half_h)/half_h;
+            let bend = 1.0 + amount*(u*u + v*v);
+            let sx = (u*bend*half_w + half_w).floor();
+            let sy = (v*bend*half_h + half_h).floor();
+            if sx >= 0.0 && sy >= 0.0 && (sx as usize) < image.width && (sy as usize) < image.height {
+                out.set(x, y, image.get(sx as usize, sy as usize));
+            }
+        }
+    }
+    out
+}
+
+/// The set of post-processing effects applied to rendered frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Effects {
+    pub scanlines: bool,
+    pub grid: bool,
+    pub bloom: bool,
+    pub curvature: bool,
+}
+
+impl Effects {
+    /// Parses a comma separated list of `scanlines`, `grid`, `bloom` and
+    /// `curvature`, or `none`.
+    pub fn parse(spec: &str) -> Result<Effects, String> {
+        let mut effects: Effects = Default::default();
+        for name in spec.split(',').map(|s| s.trim()) {
+            match name {
+                "none" => (),
+                "scanlines" => effects.scanlines = true,
+                "grid" => effects.grid = true,
+                "bloom" => effects.bloom = true,
+                "curvature" => effects.curvature = true,
+                _ => return Err(format!("Unknown effect \"{}\", expected scanlines, grid, bloom or curvature", name)),
+            }
+        }
+        Ok(effects)
+    }
+
+    pub fn any(&self) -> bool {
+        self.scanlines || self.grid || self.bloom || self.curvature
+    }
+
+    /// Runs a frame through the enabled effects. Frames are scaled up first
+    /// so that the effects have room to work with, unless nothing is enabled.
+    pub fn apply(&self, frame: &Image) -> Image {
+        if !self.any() {
+            return frame.clone();
+        }
+        let mut image = scale(frame, SCALE);
+        if self.grid {
+            image = pixel_grid(&image, SCALE, GRID_STRENGTH);
+        }
+        if self.scanlines {
+            image = scanlines(&image, SCANLINE_STRENGTH);
+        }
+        if self.bloom {
+            image = bloom(&image, BLOOM_RADIUS, BLOOM_STRENGTH);
+        }
+        if self.curvature {
+            image = curvature(&image, CURVATURE);
+        }
+        image
+    }
+}
+
+/// Turns the framebuffer into the image shown on screen: flicker reduction,
+/// then colouring, then post-processing. Every front end and screenshot uses
+/// this, so they all show the same image.
+pub fn render_frame(cpu: &Cpu, palette: &Palette, flicker: &mut FlickerFilter, effects: &Effects) -> Image {
+    let intensities = flicker.apply(&cpu.memory.framebuffer());
+    let background = palette.pixel(0);
+    let foreground = palette.pixel(1);
+    let mut image = Image::new(mem::SCREEN_WIDTH, mem::SCREEN_HEIGHT);
+    for (pixel, &intensity) in image.data.chunks_mut(3).zip(intensities) {
+        let Rgb(r, g, b) = background.blend(foreground, intensity);
+        pixel.copy_from_slice(&[r, g, b]);
+    }
+    effects.apply(&image)
+}
+
+//TESTS
+
+#[cfg(test)]
+fn checkerboard() -> Image {
+    let mut image = Image::new(2, 2);
+    image.set(0, 0, Rgb(255, 255, 255));
+    image.set(1, 1, Rgb(255, 255, 255));
+    image
+}
+
+#[test]
+fn test_scale(){
+    let image = scale(&checkerboard(), 2);
+    if image.width != 4 || image.height != 4 {
+        panic!("Scaled image has wrong size {}x{}", image.width, image.height);
+    }
+    for &(x, y, lit) in [(0, 0, true), (1, 1, true), (2, 1, false), (3, 3, true), (0, 3, false)].iter() {
+        if (image.get(x, y) == Rgb(255, 255, 255)) != lit {
+            panic!("Pixel ({}, {}) scaled incorrectly", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_scanlines(){
+    let image = scanlines(&scale(&checkerboard(), 2), 128);
+    if image.get(0, 0) != Rgb(255, 255, 255) {
+        panic!("Even rows should be untouched");
+    }
+    if image.get(0, 1) != Rgb(127, 127, 127) {
+        panic!("Odd rows should be darkened, got {:?}", image.get(0, 1));
+    }
+}
+
+#[test]
+fn test_pixel_grid(){
+    let image = pixel_grid(&scale(&checkerboard(), 3), 3, 255);
+    if image.get(0, 0) != Rgb(255, 255, 255) || image.get(1, 1) != Rgb(255, 255, 255) {
+        panic!("Inside of a cell should be untouched");
+    }
+    if image.get(2, 0) != Rgb(0, 0, 0) || image.get(0, 2) != Rgb(0, 0, 0) {
+        panic!("Cell edges should be darkened");
+    }
+}
+
+#[test]
+fn test_bloom(){
+    let mut image = Image::new(5, 1);
+    image.set(2, 0, Rgb(255, 0, 0));
+    let bloomed = bloom(&image, 1, 255);
+    if bloomed.get(2, 0) != Rgb(255, 0, 0) {
+        panic!("Lit pixel should stay lit, got {:?}", bloomed.get(2, 0));
+    }
+    if bloomed.get(1, 0) != Rgb(85, 0, 0) || bloomed.get(3, 0) != Rgb(85, 0, 0) {
+        panic!("Light should spread to neighbours, got {:?}", bloomed.data);
+    }
+    if bloomed.get(0, 0) != Rgb(0, 0, 0) {
+        panic!("Light should not spread past the blur radius");
+    }
+}
+
+#[test]
+fn test_curvature(){
+    let mut image = Image::new(8, 8);
+    for y in 0..8 {
+        for x in 0..8 {
+            image.set(x, y, Rgb(255, 255, 255));
+        }
+    }
+    let curved = curvature(&image, 0.5);
+    if curved.get(4, 4) != Rgb(255, 255, 255) {
+        panic!("Centre of the image should be unchanged");
+    }
+    if curved.get(0, 0) != Rgb(0, 0, 0) {
+        panic!("Corners should be pushed out of the image");
+    }
+    if curvature(&image, 0.0) != image {
+        panic!("Zero curvature should leave the image unchanged");
+    }
+}
+
+#[test]
+fn test_parse_effects(){
+    let effects = Effects::parse("scanlines, bloom").unwrap();
+    if !effects.scanlines || !effects.bloom || effects.grid || effects.curvature {
+        panic!("Effects parsed incorrectly: {:?}", effects);
+    }
+    if Effects::parse("none").unwrap().any() {
+        panic!("\"none\" should not enable any effects");
+    }
+    if Effects::parse("vhs").is_ok() {
+        panic!("Unknown effect should be rejected");
+    }
+    let frame = checkerboard();
+    if Effects::parse("none").unwrap().apply(&frame) != frame {
+        panic!("Frames should pass through untouched with no effects");
+    }
+}
+
+#[test]
+fn test_write_ppm(){
+    let mut out = Vec::new();
+    checkerboard().write_ppm(&mut out).unwrap();
+    if &out[..11] != b"P6\n2 2\n255\n" || out.len() != 11 + 12 {
+        panic!("Unexpected PPM output: {:?}", out);
+    }
+}
@@ -0,0 +1,227 @@
+//! An instruction level profiler, for finding where a ROM spends its cycle
+//! budget. It counts executions per address and per opcode, instructions
+//! spent waiting for a key in `Fx0A`, and inclusive counts per subroutine
+//! by following `CALL` and `RET`, then writes a report when the emulator
+//! stops.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Write;
+use cpu::Cpu;
+use disasm;
+use disasm::Instruction;
+use frontend::Hook;
+use mem::MEM_SIZE;
+
+const TOP_ADDRESSES: usize = 20;
+
+/// Where execution started, used as the caller for calls made outside any
+/// subroutine.
+const ENTRY: u16 = 0x200;
+
+/// A subroutine call that hasn't returned yet.
+struct Frame {
+    addr: u16,
+    /// Instruction count when it was called.
+    start: u64,
+}
+
+/// Counts collected while running.
+pub struct Profile {
+    total: u64,
+    key_wait: u64,
+    per_address: Vec<u64>,
+    opcodes: Vec<u16>,
+    per_class: HashMap<&'static str, u64>,
+    /// Inclusive instruction counts and number of calls, per subroutine.
+    subroutines: BTreeMap<u16, (u64, u64)>,
+    /// Number of calls from one subroutine to another.
+    call_graph: BTreeMap<(u16, u16), u64>,
+    stack: Vec<Frame>,
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            total: 0,
+            key_wait: 0,
+            per_address: vec![0; MEM_SIZE],
+            opcodes: vec![0; MEM_SIZE],
+            per_class: HashMap::new(),
+            subroutines: BTreeMap::new(),
+            call_graph: BTreeMap::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Records one executed instruction. `sp` and `next_pc` are the stack
+    /// pointer before it ran and the program counter after.
+    pub fn record(&mut self, pc: u16, opcode: u16, sp: usize, next_pc: u16) {
+        // The machine may have been reset or had a state loaded
+        self.stack.truncate(sp);
+        self.total += 1;
+        if (pc as usize) < MEM_SIZE {
+            self.per_address[pc as usize] += 1;
+            self.opcodes[pc as usize] = opcode;
+        }
+        let instruction = Instruction::decode(opcode);
+        let class = instruction.map_or("invalid", |i| i.pattern());
+        *self.per_class.entry(class).or_insert(0) += 1;
+        match instruction {
+            Some(Instruction::LdKey { .. }) if next_pc == pc => self.key_wait += 1,
+            Some(Instruction::Call { nnn }) if next_pc == nnn => {
+                let caller = self.stack.last().map_or(ENTRY, |frame| frame.addr);
+                *self.call_graph.entry((caller, nnn)).or_insert(0) += 1;
+                self.subroutines.entry(nnn).or_insert((0, 0)).1 += 1;
+                self.stack.push(Frame { addr: nnn, start: self.total });
+            }
+            Some(Instruction::Ret) if sp > 0 && next_pc != pc => {
+                if let Some(frame) = self.stack.pop() {
+                    self.subroutines.entry(frame.addr).or_insert((0, 0)).0 += self.total - frame.start;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Inclusive counts, including subroutines that are still running.
+    fn inclusive_counts(&self) -> BTreeMap<u16, (u64, u64)> {
+        let mut counts = self.subroutines.clone();
+        for frame in self.stack.iter() {
+            counts.entry(frame.addr).or_insert((0, 0)).0 += self.total - frame.start;
+        }
+        counts
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let _ = self.write_report(&mut out);
+        out
+    }
+
+    fn write_report(&self, out: &mut String) -> ::std::fmt::Result {
+        writeln!(out, "Instructions executed: {}", self.total)?;
+        writeln!(out, "Waiting for a key (Fx0A): {} ({:.1}% idle)",
+                 self.key_wait, percent(self.key_wait, self.total))?;
+
+        writeln!(out, "\nTop addresses:")?;
+        let mut addresses: Vec<usize> = (0..MEM_SIZE).filter(|&a| self.per_address[a] > 0).collect();
+        addresses.sort_by_key(|&a| (::std::cmp::Reverse(self.per_address[a]), a));
+        for &addr in addresses.iter().take(TOP_ADDRESSES) {
+            let count = self.per_address[addr];
+            writeln!(out, "  {:03X}  {:>10}  {:5.1}%  {}", addr, count, percent(count, self.total),
+                     disasm::disassemble(self.opcodes[addr]))?;
+        }
+
+        writeln!(out, "\nOpcodes:")?;
+        let mut classes: Vec<(&str, u64)> = self.per_class.iter().map(|(&c, &n)| (c, n)).collect();
+        classes.sort_by_key(|&(class, count)| (::std::cmp::Reverse(count), class));
+        for (class, count) in classes {
+            writeln!(out, "  {:<7} {:>10}  {:5.1}%", class, count, percent(count, self.total))?;
+        }
+
+        let subroutines = self.inclusive_counts();
+        if !subroutines.is_empty() {
+            writeln!(out, "\nSubroutines (inclusive):")?;
+            let mut sorted: Vec<(&u16, &(u64, u64))> = subroutines.iter().collect();
+            sorted.sort_by_key(|&(addr, &(count, _))| (::std::cmp::Reverse(count), *addr));
+            for (addr, &(count, calls)) in sorted {
+                writeln!(out, "  {:03X}  {:>10}  {:5.1}%  {} calls", addr, count, percent(count, self.total), calls)?;
+            }
+            writeln!(out, "\nCall graph:")?;
+            for (&(caller, callee), calls) in self.call_graph.iter() {
+                writeln!(out, "  {:03X} -> {:03X}  {} calls", caller, callee, calls)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::new()
+    }
+}
+
+/// Profiles the running ROM, writing the report to a file when it stops.
+pub struct ProfileHook {
+    file_name: String,
+    profile: Profile,
+    pc: u16,
+    opcode: u16,
+    sp: usize,
+}
+
+impl ProfileHook {
+    pub fn new(file_name: &str) -> ProfileHook {
+        ProfileHook {
+            file_name: file_name.to_string(),
+            profile: Profile::new(),
+            pc: 0,
+            opcode: 0,
+            sp: 0,
+        }
+    }
+}
+
+impl Hook for ProfileHook {
+    fn before_instruction(&mut self, cpu: &mut Cpu) {
+        self.pc = cpu.get_pc();
+        self.sp = cpu.get_sp();
+        self.opcode = if (self.pc as usize) + 1 < MEM_SIZE { cpu.memory.read_u16(self.pc as usize) } else { 0 };
+    }
+
+    fn after_instruction(&mut self, cpu: &mut Cpu) {
+        self.profile.record(self.pc, self.opcode, self.sp, cpu.get_pc());
+    }
+
+    fn finish(&mut self, _cpu: &mut Cpu) {
+        let result = File::create(&self.file_name)
+            .and_then(|mut f| f.write_all(self.profile.report().as_bytes()));
+        match result {
+            Ok(()) => println!("Wrote profile to {}", self.file_name),
+            Err(e) => println!("Error writing profile to {}: {}", self.file_name, e),
+        }
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_profile(){
+    // 200: CALL 0x206; LD V0, K; JP 0x202
+    // 206: LD V1, 1; CALL 0x20C; RET
+    // 20C: RET
+    let rom = vec![0x22, 0x06, 0xF0, 0x0A, 0x12, 0x02,
+                   0x61, 0x01, 0x22, 0x0C, 0x00, 0xEE,
+                   0x00, 0xEE];
+    let mut cpu = Cpu::new();
+    cpu.memory.memset(0x200, &rom);
+    let mut hook = ProfileHook::new("");
+    for _ in 0..10 {
+        hook.before_instruction(&mut cpu);
+        cpu.exec_instruction().unwrap();
+        hook.after_instruction(&mut cpu);
+    }
+    let profile = &hook.profile;
+    // 5 instructions to get back, then 5 waiting for a key
+    if profile.total != 10 || profile.key_wait != 5 || profile.per_address[0x202] != 5 {
+        panic!("Unexpected counts: {} total, {} waiting", profile.total, profile.key_wait);
+    }
+    let subroutines = profile.inclusive_counts();
+    if subroutines.get(&0x206) != Some(&(4, 1)) || subroutines.get(&0x20C) != Some(&(1, 1)) {
+        panic!("Unexpected subroutine counts: {:?}", subroutines);
+    }
+    let report = profile.report();
+    for line in ["(50.0% idle)", "  202           5   50.0%  LD V0, K", "  Fx0A             5   50.0%",
+                 "  206           4   40.0%  1 calls", "  206 -> 20C  1 calls"].iter() {
+        if !report.contains(line) {
+            panic!("Report is missing {:?}:\n{}", line, report);
+        }
+    }
+}
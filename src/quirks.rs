@@ -0,0 +1,36 @@
+/// Behaviours that differ between CHIP-8 interpreters. The default is how
+/// chippy has always behaved.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quirks {
+    /// `8xy1`, `8xy2` and `8xy3` reset VF to 0.
+    pub vf_reset: bool,
+    /// `Fx55` and `Fx65` leave I pointing after the last register.
+    pub memory_increment: bool,
+    /// `8xy6` and `8xyE` shift Vy into Vx instead of shifting Vx.
+    pub shift_vy: bool,
+    /// `Bnnn` jumps to nnn + Vx, where x is the top nibble of nnn.
+    pub jump_vx: bool,
+    /// Sprites drawn past the edge of the screen wrap around instead of
+    /// being clipped.
+    pub wrap_sprites: bool,
+}
+
+/// Names of the profiles `Quirks::parse` knows, in the order the test
+/// suite runs them.
+pub const PROFILES: [&str; 4] = ["chippy", "cosmac", "schip", "xochip"];
+
+impl Quirks {
+    /// Parses a profile name: `chippy` for the default, `cosmac` for the
+    /// original COSMAC VIP interpreter, `schip` for SUPER-CHIP 1.1 or
+    /// `xochip` for XO-CHIP.
+    pub fn parse(name: &str) -> Result<Quirks, String> {
+        let none = Quirks::default();
+        match name.trim() {
+            "chippy" => Ok(none),
+            "cosmac" => Ok(Quirks { vf_reset: true, memory_increment: true, shift_vy: true, ..none }),
+            "schip"  => Ok(Quirks { jump_vx: true, ..none }),
+            "xochip" => Ok(Quirks { memory_increment: true, shift_vy: true, wrap_sprites: true, ..none }),
+            _ => Err(format!("Unknown quirks profile \"{}\", expected {}", name, PROFILES.join(", "))),
+        }
+    }
+}
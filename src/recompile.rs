@@ -0,0 +1,518 @@
+//! A static recompiler, turning a ROM into the source of a standalone Rust
+//! program that runs it natively against the usual front ends.
+//!
+//! Control flow is followed from the entry point to find the reachable
+//! instructions and split them into basic blocks. Each block becomes a Rust
+//! function, with register arithmetic, loads, jumps and skips written out
+//! as Rust and anything else (drawing, keys, calls, memory access) passed
+//! to `Cpu::exec_decoded`. At run time `Recompiled` dispatches on the
+//! program counter and falls back to the interpreter wherever there is no
+//! block, such as `Bnnn` jumps to anywhere but a jump table, or where the
+//! code in memory no longer matches the ROM it was recompiled from.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+use argparse::{ArgumentParser, Store};
+use cpu;
+use cpu::Cpu;
+use disasm::Instruction;
+use engine::{ends_block, Engine, Interpreter};
+use flicker::FlickerMode;
+use frontend;
+use frontend::{Frontend, NullAudio, SystemClock};
+use headless;
+use mem::{MEM_SIZE, PAGE_SIZE};
+use palette::Palette;
+use quirks::Quirks;
+#[cfg(feature = "sdl")]
+use postfx::Effects;
+#[cfg(feature = "sdl")]
+use sdl;
+use tui;
+use tui::Glyphs;
+
+const ROM_START: u16 = 0x200;
+/// Longest block written out as one function.
+const MAX_BLOCK_LENGTH: usize = 64;
+const NUM_PAGES: usize = MEM_SIZE/PAGE_SIZE;
+
+/// What control flow analysis found in a ROM.
+pub struct Analysis {
+    /// Addresses of the instructions reachable from the entry point.
+    pub code: BTreeSet<u16>,
+    /// Blocks as (start address, instructions).
+    pub blocks: Vec<(u16, Vec<Instruction>)>,
+    /// Addresses of `Bnnn` jumps, whose targets are only known at run time.
+    pub indirect: Vec<u16>,
+}
+
+impl Analysis {
+    pub fn summary(&self) -> String {
+        let mut out = format!("{} instructions in {} blocks", self.code.len(), self.blocks.len());
+        if !self.indirect.is_empty() {
+            let sites: Vec<String> = self.indirect.iter().map(|addr| format!("{:03X}", addr)).collect();
+            let _ = write!(out, ", indirect jumps at {}", sites.join(" "));
+        }
+        out
+    }
+}
+
+fn fetch(rom: &[u8], addr: u16) -> Option<Instruction> {
+    let offset = (addr as usize).checked_sub(ROM_START as usize)?;
+    if offset + 1 >= rom.len() {
+        return None;
+    }
+    Instruction::decode((rom[offset] as u16) << 8 | rom[offset + 1] as u16)
+}
+
+/// Where control can go after the instruction at `addr`.
+fn successors(ins: Instruction, addr: u16) -> Vec<u16> {
+    use disasm::Instruction::*;
+    let next = addr + 2;
+    match ins {
+        Jp { nnn } => vec![nnn],
+        Call { nnn } => vec![nnn, next],
+        Ret | JpV0 { .. } => vec![],
+        SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } |
+        Skp { .. } | Sknp { .. } => vec![next, next + 2],
+        // Waiting for a key runs the same instruction again
+        LdKey { .. } => vec![addr, next],
+        _ => vec![next],
+    }
+}
+
+/// Finds the code reachable from the entry point and splits it into blocks.
+pub fn analyse(rom: &[u8]) -> Analysis {
+    let mut code = BTreeSet::new();
+    let mut leaders = BTreeSet::new();
+    let mut indirect = Vec::new();
+    let mut pending = vec![ROM_START];
+    leaders.insert(ROM_START);
+    while let Some(addr) = pending.pop() {
+        if code.contains(&addr) {
+            continue;
+        }
+        let ins = match fetch(rom, addr) {
+            Some(ins) => ins,
+            None => continue,
+        };
+        code.insert(addr);
+        if let Instruction::JpV0 { nnn } = ins {
+            // Usually a jump table, so follow the table's jumps. Anything
+            // else lands where there's no block and is interpreted.
+            indirect.push(addr);
+            leaders.insert(nnn);
+            pending.push(nnn);
+            let mut entry = nnn;
+            while let Some(Instruction::Jp { .. }) = fetch(rom, entry) {
+                leaders.insert(entry);
+                pending.push(entry);
+                entry += 2;
+            }
+        }
+        for target in successors(ins, addr) {
+            if ends_block(&ins) {
+                leaders.insert(target);
+            }
+            pending.push(target);
+        }
+    }
+    indirect.sort();
+
+    let mut blocks = Vec::new();
+    let mut pending: Vec<u16> = leaders.iter().cloned().filter(|addr| code.contains(addr)).collect();
+    let mut done = BTreeSet::new();
+    while let Some(start) = pending.pop() {
+        if !done.insert(start) {
+            continue;
+        }
+        let mut block = Vec::new();
+        let mut addr = start;
+        while let Some(ins) = fetch(rom, addr) {
+            block.push(ins);
+            addr += 2;
+            if ends_block(&ins) || leaders.contains(&addr) || !code.contains(&addr) {
+                break;
+            }
+            if block.len() == MAX_BLOCK_LENGTH {
+                leaders.insert(addr);
+                pending.push(addr);
+                break;
+            }
+        }
+        blocks.push((start, block));
+    }
+    blocks.sort_by_key(|&(start, _)| start);
+    Analysis { code, blocks, indirect }
+}
+
+/// Rust for an instruction that doesn't end a block, or None if it has to
+/// go through `exec_decoded`.
+fn native(ins: Instruction) -> Option<String> {
+    use disasm::Instruction::*;
+    Some(match ins {
+        LdByte { x, kk } => format!("cpu.set_reg({}, 0x{:02X});", x, kk),
+        AddByte { x, kk } => format!("cpu.set_reg({0}, cpu.get_reg({0}).wrapping_add(0x{1:02X}));", x, kk),
+        LdReg { x, y } => format!("cpu.set_reg({}, cpu.get_reg({}));", x, y),
+        Or { x, y } => format!("cpu.set_reg({0}, cpu.get_reg({0}) | cpu.get_reg({1}));", x, y),
+        And { x, y } => format!("cpu.set_reg({0}, cpu.get_reg({0}) & cpu.get_reg({1}));", x, y),
+        Xor { x, y } => format!("cpu.set_reg({0}, cpu.get_reg({0}) ^ cpu.get_reg({1}));", x, y),
+        AddReg { x, y } => format!(
+            "let (v, carry) = cpu.get_reg({0}).overflowing_add(cpu.get_reg({1})); cpu.set_reg(15, carry as u8); cpu.set_reg({0}, v);", x, y),
+        Sub { x, y } => format!(
+            "let (a, b) = (cpu.get_reg({0}), cpu.get_reg({1})); cpu.set_reg(15, (a > b) as u8); cpu.set_reg({0}, a.wrapping_sub(b));", x, y),
+        Subn { x, y } => format!(
+            "let (a, b) = (cpu.get_reg({0}), cpu.get_reg({1})); cpu.set_reg(15, (b > a) as u8); cpu.set_reg({0}, b.wrapping_sub(a));", x, y),
+        // The flag is set first, so shifting VF shifts the flag
+        Shr { x, .. } => format!("cpu.set_reg(15, cpu.get_reg({0}) & 1); cpu.set_reg({0}, cpu.get_reg({0}) >> 1);", x),
+        Shl { x, .. } => format!("cpu.set_reg(15, cpu.get_reg({0}) >> 7); cpu.set_reg({0}, cpu.get_reg({0}) << 1);", x),
+        LdI { nnn } => format!("cpu.set_i(0x{:03X});", nnn),
+        AddI { x } => format!("cpu.set_i(cpu.get_i().wrapping_add(cpu.get_reg({}) as u16));", x),
+        LdVxDt { x } => format!("cpu.set_reg({}, cpu.get_dt());", x),
+        LdDtVx { x } => format!("cpu.set_dt(cpu.get_reg({}));", x),
+        LdStVx { x } => format!("cpu.set_st(cpu.get_reg({}));", x),
+        _ => return None,
+    })
+}
+
+/// The condition under which a skip instruction skips, if it can be
+/// written out natively.
+fn skip_condition(ins: Instruction) -> Option<String> {
+    use disasm::Instruction::*;
+    Some(match ins {
+        SeByte { x, kk } => format!("cpu.get_reg({}) == 0x{:02X}", x, kk),
+        SneByte { x, kk } => format!("cpu.get_reg({}) != 0x{:02X}", x, kk),
+        SeReg { x, y } => format!("cpu.get_reg({}) == cpu.get_reg({})", x, y),
+        SneReg { x, y } => format!("cpu.get_reg({}) != cpu.get_reg({})", x, y),
+        _ => return None,
+    })
+}
+
+
+/// Brings the instruction count up to date with instructions run natively.
+fn write_count(out: &mut String, uncounted: &mut u64) {
+    if *uncounted > 0 {
+        let _ = writeln!(out, "    cpu.add_executed({});", uncounted);
+        *uncounted = 0;
+    }
+}
+
+/// Writes out a block as a function named `block_xxx`, with the start
+/// address in lowercase hex so the name is snake case.
+fn write_block(out: &mut String, start: u16, block: &[Instruction]) {
+    let _ = writeln!(out, "\nfn block_{:03x}(cpu: &mut Cpu) -> Result<(), String> {{", start);
+    // Instructions run natively since the count was last brought up to date
+    let mut uncounted = 0;
+    // Whether the program counter points at the current instruction
+    let mut pc_current = true;
+    let mut addr = start;
+    for (n, &ins) in block.iter().enumerate() {
+        let _ = writeln!(out, "    // {:03X}: {}", addr, ins);
+        if let Some(code) = native(ins) {
+            let _ = writeln!(out, "    {{ {} }}", code);
+            uncounted += 1;
+            pc_current = false;
+        } else if let Some(condition) = skip_condition(ins) {
+            uncounted += 1;
+            write_count(out, &mut uncounted);
+            let _ = writeln!(out, "    let skip = {};", condition);
+            let _ = writeln!(out, "    cpu.set_pc(if skip {{ 0x{:03X} }} else {{ 0x{:03X} }});", addr + 4, addr + 2);
+            out.push_str("    Ok(())\n}\n");
+            return;
+        } else if let Instruction::Jp { nnn } = ins {
+            uncounted += 1;
+            write_count(out, &mut uncounted);
+            let _ = writeln!(out, "    cpu.set_pc(0x{:03X});", nnn);
+            out.push_str("    Ok(())\n}\n");
+            return;
+        } else {
+            write_count(out, &mut uncounted);
+            if !pc_current {
+                let _ = writeln!(out, "    cpu.set_pc(0x{:03X});", addr);
+            }
+            if n + 1 == block.len() {
+                let _ = writeln!(out, "    cpu.exec_decoded({:?})\n}}", ins);
+                return;
+            }
+            let _ = writeln!(out, "    cpu.exec_decoded({:?})?;", ins);
+            pc_current = true;
+        }
+        addr += 2;
+    }
+    write_count(out, &mut uncounted);
+    if !pc_current {
+        let _ = writeln!(out, "    cpu.set_pc(0x{:03X});", addr);
+    }
+    out.push_str("    Ok(())\n}\n");
+}
+
+/// The source of the program's `main.rs`, which expects the ROM alongside
+/// it in `rom.ch8`.
+pub fn generate(analysis: &Analysis, name: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "//! {}, recompiled from a CHIP-8 ROM by chippy.", name);
+    out.push_str("\nextern crate chippy;\n\n");
+    out.push_str("use chippy::cpu::Cpu;\n");
+    out.push_str("#[allow(unused_imports)]\nuse chippy::disasm::Instruction::*;\n");
+    out.push_str("use chippy::recompile;\nuse chippy::recompile::Block;\n\n");
+    out.push_str("static ROM: &[u8] = include_bytes!(\"rom.ch8\");\n\n");
+    out.push_str("static BLOCKS: &[Block] = &[\n");
+    for &(start, ref block) in analysis.blocks.iter() {
+        let _ = writeln!(out, "    Block {{ start: 0x{0:03X}, length: {1}, run: block_{0:03x} }},", start, block.len());
+    }
+    out.push_str("];\n\nfn main() {\n");
+    let _ = writeln!(out, "    recompile::run_main({:?}, ROM, BLOCKS);", name);
+    out.push_str("}\n");
+    for &(start, ref block) in analysis.blocks.iter() {
+        write_block(&mut out, start, block);
+    }
+    out
+}
+
+/// A crate name made from a ROM's file name.
+pub fn crate_name(file_name: &str) -> String {
+    let stem = Path::new(file_name).file_stem().and_then(|s| s.to_str()).unwrap_or("rom");
+    let name: String = stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        _ => format!("rom_{}", name),
+    }
+}
+
+/// Writes a crate that runs `rom` to `dir`, depending on the chippy crate
+/// found at `chippy_path`.
+pub fn write_crate(dir: &Path, rom: &[u8], name: &str, chippy_path: &str) -> Result<Analysis, String> {
+    let analysis = analyse(rom);
+    let manifest = format!("[package]\nname = {:?}\nversion = \"0.1.0\"\n\n\
+                            [features]\ndefault = [\"sdl\"]\nsdl = [\"chippy/sdl\"]\n\n\
+                            [dependencies]\nchippy = {{ path = {:?}, default-features = false }}\n\n\
+                            [workspace]\n", name, chippy_path);
+    let src = dir.join("src");
+    fs::create_dir_all(&src)
+        .and_then(|_| fs::write(dir.join("Cargo.toml"), manifest))
+        .and_then(|_| fs::write(src.join("rom.ch8"), rom))
+        .and_then(|_| fs::write(src.join("main.rs"), generate(&analysis, name)))
+        .map_err(|e| format!("Error writing {}: {}", dir.display(), e))?;
+    Ok(analysis)
+}
+
+/// A recompiled block, as listed by the generated program.
+pub struct Block {
+    pub start: u16,
+    /// Number of instructions.
+    pub length: u16,
+    pub run: fn(&mut Cpu) -> Result<(), String>,
+}
+
+/// Runs recompiled blocks, interpreting wherever there isn't one or its
+/// code in memory has changed since it was recompiled.
+pub struct Recompiled<'a> {
+    rom: &'a [u8],
+    blocks: &'a [Block],
+    /// Index into `blocks` by start address.
+    index: Vec<Option<usize>>,
+    /// Whether each block's code in memory still matches the ROM.
+    valid: Vec<bool>,
+    /// Blocks with code in each page.
+    page_blocks: Vec<Vec<usize>>,
+}
+
+impl<'a> Recompiled<'a> {
+    pub fn new(rom: &'a [u8], blocks: &'a [Block]) -> Recompiled<'a> {
+        let mut index = vec![None; MEM_SIZE];
+        let mut page_blocks = vec![Vec::new(); NUM_PAGES];
+        for (n, block) in blocks.iter().enumerate() {
+            let start = block.start as usize;
+            let end = start + block.length as usize*2 - 1;
+            index[start] = Some(n);
+            for blocks in page_blocks[start/PAGE_SIZE..end/PAGE_SIZE+1].iter_mut() {
+                blocks.push(n);
+            }
+        }
+        Recompiled {
+            rom,
+            blocks,
+            index,
+            valid: vec![false; blocks.len()],
+            page_blocks,
+        }
+    }
+
+    /// Checks the blocks in the pages set in `dirty` against the ROM.
+    fn revalidate(&mut self, cpu: &Cpu, dirty: u64) {
+        for page in (0..NUM_PAGES).filter(|page| dirty & (1 << page) != 0) {
+            for &n in self.page_blocks[page].iter() {
+                let block = &self.blocks[n];
+                let offset = (block.start - ROM_START) as usize;
+                let length = block.length as usize*2;
+                self.valid[n] = self.rom.get(offset..offset + length)
+                    .is_some_and(|code| *code == cpu.memory.get_vec(block.start as usize, length)[..]);
+            }
+        }
+    }
+}
+
+impl<'a> Engine for Recompiled<'a> {
+    fn run(&mut self, cpu: &mut Cpu, count: u64) -> (u64, Result<(), String>) {
+        // Recompiled code only knows the default behaviour
+        if cpu.quirks() != Quirks::default() {
+            return Interpreter.run(cpu, count);
+        }
+        let mut ran = 0;
+        while ran < count {
+            let dirty = cpu.memory.take_dirty_pages();
+            if dirty != 0 {
+                self.revalidate(cpu, dirty);
+            }
+            let pc = cpu.get_pc() as usize;
+            let block = if pc < MEM_SIZE { self.index[pc] } else { None };
+            match block {
+                Some(n) if self.valid[n] && self.blocks[n].length as u64 <= count - ran => {
+                    ran += self.blocks[n].length as u64;
+                    if let Err(e) = (self.blocks[n].run)(cpu) {
+                        return (ran, Err(e));
+                    }
+                }
+                _ => {
+                    ran += 1;
+                    if let Err(e) = cpu.exec_instruction() {
+                        return (ran, Err(e));
+                    }
+                }
+            }
+        }
+        (ran, Ok(()))
+    }
+}
+
+/// The `main` of a recompiled program, taking a cut down set of the
+/// emulator's options.
+pub fn run_main(name: &str, rom: &[u8], blocks: &[Block]) {
+    let mut instructions_per_second = cpu::DEFAULT_INS_PER_SECOND;
+    let mut frontend_name = if cfg!(feature = "sdl") { "sdl" } else { "tui" }.to_string();
+    let mut frames = 0u64;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(name);
+        ap.refer(&mut instructions_per_second)
+            .add_option(&["-f", "--freq"], Store, "Instructions per second");
+        ap.refer(&mut frontend_name)
+            .add_option(&["--frontend"], Store,
+                        "Where to run: sdl, tui, tui-braille or headless");
+        ap.refer(&mut frames)
+            .add_option(&["--frames"], Store,
+                        "Number of frames to run for in headless mode, 0 to run forever");
+        ap.parse_args_or_exit();
+    }
+    let mut cpu = Cpu::new();
+    cpu.set_ips(instructions_per_second);
+    let mut engine = Recompiled::new(rom, blocks);
+    let load = |cpu: &mut Cpu| {
+        if let Err(e) = cpu.memory.load_rom(rom) {
+            panic!("Error loading rom: {}", e);
+        }
+    };
+    match frontend_name.as_str() {
+        #[cfg(feature = "sdl")]
+        "sdl" => sdl::with_contexts(move |contexts| {
+            let texture_creator = contexts.canvas.texture_creator();
+            let audio = sdl::SdlAudio::new(&contexts.sdl);
+            let input = sdl::SdlInput::new(&contexts.sdl);
+            let clock = sdl::SdlClock::new(&contexts.sdl);
+            let mut frontend = Frontend {
+                video: sdl::SdlVideo::new(contexts, &texture_creator, Palette::default(),
+                                          FlickerMode::default(), Effects::default()),
+                audio,
+                input,
+                clock,
+            };
+            frontend::run(&mut frontend, &mut cpu, load, &mut [], &mut engine);
+        }),
+        "tui" | "tui-braille" => {
+            let glyphs = if frontend_name == "tui" { Glyphs::HalfBlock } else { Glyphs::Braille };
+            let mut frontend = Frontend {
+                video: tui::TuiVideo::new(glyphs, Palette::default(), FlickerMode::default()),
+                audio: tui::TuiAudio::new(),
+                input: tui::TuiInput::new(),
+                clock: SystemClock::new(),
+            };
+            frontend::run(&mut frontend, &mut cpu, load, &mut [], &mut engine);
+        },
+        "headless" => {
+            let mut frontend = Frontend {
+                video: headless::HeadlessVideo::new(),
+                audio: NullAudio,
+                input: headless::HeadlessInput::new(if frames == 0 { None } else { Some(frames) }),
+                clock: headless::VirtualClock::new(),
+            };
+            frontend::run(&mut frontend, &mut cpu, load, &mut [], &mut engine);
+            print!("{}", headless::framebuffer_text(&cpu));
+        },
+        _ => {
+            println!("Unknown frontend \"{}\", expected sdl, tui, tui-braille or headless", frontend_name);
+            ::std::process::exit(2);
+        }
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+fn test_block(cpu: &mut Cpu) -> Result<(), String> {
+    // 200: LD V0, 0x01 as recompiled
+    cpu.set_reg(0, 0x01);
+    cpu.add_executed(1);
+    cpu.set_pc(0x202);
+    Ok(())
+}
+
+#[test]
+fn test_recompile(){
+    // 200: LD V0, 0x01; CALL 0x208; JP V0, 0x20C
+    // 208: ADD V1, 1; RET
+    // 20C: JP 0x210; JP 0x200
+    // 210: JP 0x210
+    let rom = vec![0x60, 0x01, 0x22, 0x08, 0xB2, 0x0C, 0x00, 0x00,
+                   0x71, 0x01, 0x00, 0xEE, 0x12, 0x10, 0x12, 0x00,
+                   0x12, 0x10];
+    let analysis = analyse(&rom);
+    let starts: Vec<u16> = analysis.blocks.iter().map(|&(start, _)| start).collect();
+    if starts != vec![0x200, 0x204, 0x208, 0x20C, 0x20E, 0x210] || analysis.indirect != vec![0x204] {
+        panic!("Unexpected blocks {:03X?} and indirect jumps {:03X?}", starts, analysis.indirect);
+    }
+    let source = generate(&analysis, "test");
+    if let Err(e) = syn::parse_file(&source) {
+        panic!("Generated source doesn't parse: {}\n{}", e, source);
+    }
+    for line in ["    Block { start: 0x200, length: 2, run: block_200 },",
+                 "    Block { start: 0x20C, length: 1, run: block_20c },",
+                 "    // 202: CALL 0x208\n    cpu.add_executed(1);\n    cpu.set_pc(0x202);\n    cpu.exec_decoded(Call { nnn: 520 })\n}",
+                 "    { cpu.set_reg(1, cpu.get_reg(1).wrapping_add(0x01)); }"].iter() {
+        if !source.contains(line) {
+            panic!("Generated source is missing {:?}:\n{}", line, source);
+        }
+    }
+
+    // The block is only used while the code in memory matches the ROM
+    let rom = vec![0x60, 0x01, 0x12, 0x00];
+    let blocks = [Block { start: 0x200, length: 1, run: test_block }];
+    let mut engine = Recompiled::new(&rom, &blocks);
+    let mut cpu = Cpu::new();
+    cpu.memory.load_rom(&rom).unwrap();
+    cpu.memory.raw_mut()[0x201] = 0x02;
+    engine.run(&mut cpu, 1).1.unwrap();
+    if cpu.get_reg(0) != 2 {
+        panic!("Ran the recompiled block for modified code");
+    }
+    cpu.memory.raw_mut()[0x201] = 0x01;
+    cpu.set_pc(0x200);
+    cpu.set_reg(0, 0);
+    engine.run(&mut cpu, 1).1.unwrap();
+    if cpu.get_reg(0) != 1 || cpu.get_pc() != 0x202 {
+        panic!("Unexpected state after the recompiled block: V0 {} PC {:03X}", cpu.get_reg(0), cpu.get_pc());
+    }
+}
@@ -0,0 +1,162 @@
+//! Reloads the ROM when its file changes, for working on a ROM with an
+//! external assembler. The file is polled a few times a second and counts
+//! as changed when its modification time or length does.
+
+use std::fs;
+use std::time::SystemTime;
+use cpu::Cpu;
+use frontend::{Hook, FRAMES_PER_SECOND};
+use io;
+use mem::Mem;
+use recompile;
+
+/// Frames between checks of the file.
+const CHECK_FRAMES: u64 = 15;
+/// How long the message after a reload is shown, in frames.
+const MESSAGE_FRAMES: u64 = 2 * FRAMES_PER_SECOND as u64;
+
+fn signature(file_name: &str) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(file_name).ok()?;
+    metadata.modified().ok().map(|modified| (modified, metadata.len()))
+}
+
+pub struct ReloadHook {
+    file_name: String,
+    keep_state: bool,
+    /// The file's signature when it was last loaded.
+    loaded: Option<(SystemTime, u64)>,
+    frame: u64,
+    next_check: u64,
+    message: String,
+    /// Frame to stop showing `message` at, None to keep showing it.
+    message_until: Option<u64>,
+}
+
+impl ReloadHook {
+    /// Watches `file_name`, which should be the file the ROM was loaded
+    /// from. With `keep_state` only the instructions the new ROM can run
+    /// are copied into memory, leaving the registers, timers, screen and
+    /// data alone; otherwise the machine is reset.
+    pub fn new(file_name: &str, keep_state: bool) -> ReloadHook {
+        ReloadHook {
+            file_name: file_name.to_string(),
+            keep_state,
+            loaded: signature(file_name),
+            frame: 0,
+            next_check: CHECK_FRAMES,
+            message: String::new(),
+            message_until: Some(0),
+        }
+    }
+
+    /// Loads the file again, returning what was done.
+    pub fn reload(&self, cpu: &mut Cpu) -> Result<String, String> {
+        let rom = io::read_rom(&self.file_name).map_err(|e| format!("Error reading {}: {}", self.file_name, e))?;
+        Mem::check_rom_size(&rom).map_err(|e| format!("Error reloading {}: {}", self.file_name, e))?;
+        if !self.keep_state {
+            cpu.reset();
+            cpu.memory.load_rom(&rom)?;
+            return Ok(format!("Reloaded {}", self.file_name));
+        }
+        // Written directly so that the copy isn't seen as the program
+        // writing to itself by coverage, the write journal or hooks
+        let analysis = recompile::analyse(&rom);
+        let memory = cpu.memory.raw_mut();
+        for &addr in analysis.code.iter() {
+            let addr = addr as usize;
+            if let Some(offset) = addr.checked_sub(0x200) {
+                for (cell, &byte) in memory[addr..].iter_mut().zip(rom.iter().skip(offset).take(2)) {
+                    *cell = byte;
+                }
+            }
+        }
+        Ok(format!("Reloaded {} instructions from {}", analysis.code.len(), self.file_name))
+    }
+}
+
+impl Hook for ReloadHook {
+    fn end_frame(&mut self, cpu: &mut Cpu) {
+        self.frame += 1;
+        if self.frame < self.next_check {
+            return;
+        }
+        self.next_check = self.frame + CHECK_FRAMES;
+        let current = signature(&self.file_name);
+        if current.is_none() || current == self.loaded {
+            return;
+        }
+        self.loaded = current;
+        // Errors stay up until the next reload
+        let (message, until) = match self.reload(cpu) {
+            Ok(message) => (message, Some(self.frame + MESSAGE_FRAMES)),
+            Err(e) => (e, None),
+        };
+        self.message = message;
+        self.message_until = until;
+    }
+    fn overlay(&self) -> Vec<String> {
+        match self.message_until {
+            Some(until) if self.frame >= until => Vec::new(),
+            _ => vec![self.message.clone()],
+        }
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_reload(){
+    let file = ::std::env::temp_dir().join("chippy_test_reload.ch8");
+    let file_name = file.to_str().unwrap();
+    // LD V0, 1; LD V1, 2; JP 0x204; then data
+    fs::write(&file, [0x60, 0x01, 0x61, 0x02, 0x12, 0x04, 0xAA]).unwrap();
+    let run_frames = |hook: &mut ReloadHook, cpu: &mut Cpu, frames: u64| {
+        for _ in 0..frames {
+            cpu.exec_instruction().unwrap();
+            hook.end_frame(cpu);
+        }
+    };
+    let mut cpu = Cpu::new();
+    io::load_rom(&mut cpu, file_name);
+    let mut hook = ReloadHook::new(file_name, false);
+    run_frames(&mut hook, &mut cpu, CHECK_FRAMES);
+    if cpu.get_reg(1) != 2 || !hook.overlay().is_empty() {
+        panic!("Expected the rom to run without reloading");
+    }
+
+    // Each new version has a different length, which counts as a change
+    // even within the resolution of the modification time
+    fs::write(&file, [0x60, 0x03, 0x61, 0x04, 0x12, 0x04, 0xBB, 0xBB]).unwrap();
+    run_frames(&mut hook, &mut cpu, CHECK_FRAMES);
+    if cpu.get_pc() != 0x200 || cpu.get_reg(1) != 0 || hook.overlay() != [format!("Reloaded {}", file_name)] {
+        panic!("Expected the rom to be reset and reloaded, {:?}", hook.overlay());
+    }
+    run_frames(&mut hook, &mut cpu, 2);
+    if cpu.get_reg(0) != 3 || cpu.get_reg(1) != 4 {
+        panic!("Expected the new rom to run");
+    }
+
+    // Keeping state only copies the code, which doesn't count as the
+    // program writing to memory
+    cpu.memory.write_u8(0x206, 0x11);
+    cpu.memory.set_coverage(true);
+    let mut hook = ReloadHook::new(file_name, true);
+    fs::write(&file, [0x60, 0x05, 0x61, 0x06, 0x12, 0x02, 0xCC]).unwrap();
+    run_frames(&mut hook, &mut cpu, CHECK_FRAMES);
+    if cpu.get_reg(0) != 3 || cpu.memory.read_u16(0x204) != 0x1202 || cpu.memory.get_vec(0x206, 1) != [0x11] {
+        panic!("Expected only the code to be reloaded");
+    }
+    if cpu.memory.coverage().unwrap()[0x200..0x206].iter().any(|&flags| flags & ::mem::COVER_WRITE != 0) {
+        panic!("Expected reloading not to mark the code as written");
+    }
+    run_frames(&mut hook, &mut cpu, 2);
+    if cpu.get_reg(1) != 6 {
+        panic!("Expected the new code to run");
+    }
+
+    fs::write(&file, vec![0; 0x1000]).unwrap();
+    run_frames(&mut hook, &mut cpu, CHECK_FRAMES);
+    if !hook.overlay()[0].starts_with("Error reloading") {
+        panic!("Expected a rom that is too large to be reported, {:?}", hook.overlay());
+    }
+}
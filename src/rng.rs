@@ -0,0 +1,66 @@
+/// A small xorshift random number generator for the RND instruction. Games
+/// only need something that looks random, and unlike `rand` this builds for
+/// every target, including wasm.
+#[derive(Clone, Debug)]
+pub struct XorShift {
+    state: u32,
+}
+
+impl XorShift {
+    pub fn new(seed: u32) -> XorShift {
+        // xorshift gets stuck on zero, so nudge it away
+        XorShift { state: if seed == 0 { 0x9e3779b9 } else { seed } }
+    }
+
+    /// Seeds from the system clock where there is one.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_time() -> XorShift {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+            .unwrap_or(0);
+        XorShift::new(nanos)
+    }
+
+    /// wasm32-unknown-unknown has no clock to seed from, the page can call
+    /// `set_seed` if it cares.
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_time() -> XorShift {
+        XorShift::new(0)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u32() >> 24) as u8
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_xorshift(){
+    let mut a = XorShift::new(1234);
+    let mut b = XorShift::new(1234);
+    let first: Vec<u8> = (0..64).map(|_| a.next_u8()).collect();
+    if first != (0..64).map(|_| b.next_u8()).collect::<Vec<u8>>() {
+        panic!("Same seed should give the same sequence");
+    }
+    let mut seen = [false; 256];
+    for _ in 0..4096 {
+        seen[a.next_u8() as usize] = true;
+    }
+    if seen.iter().filter(|&&s| s).count() < 250 {
+        panic!("Random bytes don't cover the range");
+    }
+    if XorShift::new(0).next_u32() == 0 {
+        panic!("Zero seed should not get stuck");
+    }
+}
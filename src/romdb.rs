@@ -0,0 +1,222 @@
+//! Metadata for known ROMs, read from a copy of the community
+//! [chip-8-database](https://github.com/chip-8/chip-8-database): the
+//! `sha1-hashes.json`, `programs.json` and `platforms.json` files from its
+//! `database` directory.
+//!
+//! ROMs are looked up by SHA-1. The first platform listed for a ROM gives
+//! its quirks, adjusted by the ROM's `quirkyPlatforms` entry for that
+//! platform, and its speed if the ROM doesn't have a `tickrate`. chippy
+//! doesn't emulate the `vblank` quirk, and treats `memoryIncrementByX` like
+//! any other platform that moves I.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde_json;
+use serde_json::Value;
+use config;
+use frontend::FRAMES_PER_SECOND;
+use io;
+use palette::Palette;
+use quirks::Quirks;
+
+/// What the database knows about a ROM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    /// The database's platform id, such as `originalChip8` or `xochip`.
+    pub platform: Option<String>,
+    pub quirks: Option<Quirks>,
+    /// Instructions per frame.
+    pub tickrate: Option<u64>,
+    /// What the keys do, such as ("up", 5), in the database's names.
+    pub keys: Vec<(String, u8)>,
+    /// Pixel colours as hex, the background first.
+    pub colours: Vec<String>,
+}
+
+impl RomInfo {
+    pub fn instructions_per_second(&self) -> Option<u64> {
+        self.tickrate.map(|tickrate| tickrate * FRAMES_PER_SECOND as u64)
+    }
+
+    /// The ROM's colours as a palette. XO-CHIP ROMs can give up to 16 for
+    /// four planes; only the first four are used.
+    pub fn palette(&self) -> Option<Result<Palette, String>> {
+        if self.colours.len() < 2 {
+            return None;
+        }
+        let colours = &self.colours[..self.colours.len().min(4)];
+        Some(Palette::parse(&colours.join(",")))
+    }
+
+    /// The key bound to one of the database's actions, such as `up` or `a`.
+    pub fn key(&self, action: &str) -> Option<u8> {
+        self.keys.iter().find(|&(name, _)| name == action).map(|&(_, key)| key)
+    }
+
+    /// A few lines describing the ROM, for printing when it's loaded.
+    pub fn summary(&self) -> String {
+        let mut out = self.title.clone();
+        if !self.authors.is_empty() {
+            out += &format!(" by {}", self.authors.join(", "));
+        }
+        if let Some(ref platform) = self.platform {
+            out += &format!(" ({})", platform);
+        }
+        if !self.keys.is_empty() {
+            let keys: Vec<String> = self.keys.iter().map(|(name, key)| format!("{} {:X}", name, key)).collect();
+            out += &format!("\nKeys: {}", keys.join(", "));
+        }
+        out
+    }
+}
+
+/// Applies a database quirks object to `base`, keeping anything it doesn't
+/// mention.
+fn apply_quirks(flags: &Value, base: Quirks) -> Quirks {
+    let flag = |name: &str, default: bool| flags.get(name).and_then(Value::as_bool).unwrap_or(default);
+    Quirks {
+        vf_reset: flag("logic", base.vf_reset),
+        memory_increment: !flag("memoryLeaveIUnchanged", !base.memory_increment),
+        shift_vy: !flag("shift", !base.shift_vy),
+        jump_vx: flag("jump", base.jump_vx),
+        wrap_sprites: flag("wrap", base.wrap_sprites),
+    }
+}
+
+/// A loaded copy of the database.
+#[derive(Debug, Default)]
+pub struct RomDb {
+    /// SHA-1 to index in `programs`.
+    hashes: HashMap<String, usize>,
+    programs: Vec<Value>,
+    /// Platform id to its entry.
+    platforms: HashMap<String, Value>,
+}
+
+impl RomDb {
+    /// Where the database is looked for by default,
+    /// `<config dir>/chip-8-database`.
+    pub fn default_path() -> Option<PathBuf> {
+        config::config_dir().map(|dir| dir.join("chip-8-database"))
+    }
+
+    /// Loads the database's JSON files from `dir`.
+    pub fn load(dir: &Path) -> Result<RomDb, String> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {}", path.display(), e))
+        };
+        RomDb::parse(&read("sha1-hashes.json")?, &read("programs.json")?, &read("platforms.json")?)
+    }
+
+    pub fn parse(hashes: &str, programs: &str, platforms: &str) -> Result<RomDb, String> {
+        let hashes: HashMap<String, usize> = serde_json::from_str(hashes)
+            .map_err(|e| format!("Error in sha1-hashes.json: {}", e))?;
+        let programs = match serde_json::from_str(programs) {
+            Ok(Value::Array(programs)) => programs,
+            Ok(_) => return Err("Error in programs.json: expected an array".to_string()),
+            Err(e) => return Err(format!("Error in programs.json: {}", e)),
+        };
+        let platforms = match serde_json::from_str(platforms) {
+            Ok(Value::Array(platforms)) => platforms,
+            Ok(_) => return Err("Error in platforms.json: expected an array".to_string()),
+            Err(e) => return Err(format!("Error in platforms.json: {}", e)),
+        };
+        let platforms = platforms.into_iter()
+            .filter_map(|platform| platform.get("id").and_then(Value::as_str).map(str::to_string)
+                        .map(|id| (id, platform)))
+            .collect();
+        Ok(RomDb { hashes, programs, platforms })
+    }
+
+    /// Looks up a ROM by its contents.
+    pub fn lookup(&self, rom: &[u8]) -> Option<RomInfo> {
+        let hash = io::rom_hash(rom);
+        let program = self.programs.get(*self.hashes.get(&hash)?)?;
+        let entry = program.get("roms").and_then(|roms| roms.get(&hash)).unwrap_or(&Value::Null);
+        let strings = |value: Option<&Value>| -> Vec<String> {
+            value.and_then(Value::as_array)
+                .map(|values| values.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                .unwrap_or_default()
+        };
+
+        let mut info = RomInfo {
+            title: program.get("title").and_then(Value::as_str).unwrap_or(&hash).to_string(),
+            authors: strings(program.get("authors")),
+            tickrate: entry.get("tickrate").and_then(Value::as_u64),
+            colours: strings(entry.get("colors").and_then(|colours| colours.get("pixels"))),
+            ..Default::default()
+        };
+        if let Some(keys) = entry.get("keys").and_then(Value::as_object) {
+            for (name, key) in keys {
+                if let Some(key) = key.as_u64().filter(|&key| key < 16) {
+                    info.keys.push((name.clone(), key as u8));
+                }
+            }
+        }
+        if let Some(id) = strings(entry.get("platforms")).into_iter().next() {
+            if let Some(platform) = self.platforms.get(&id) {
+                let mut quirks = apply_quirks(platform.get("quirks").unwrap_or(&Value::Null), Quirks::default());
+                if let Some(flags) = entry.get("quirkyPlatforms").and_then(|quirky| quirky.get(&id)) {
+                    quirks = apply_quirks(flags, quirks);
+                }
+                info.quirks = Some(quirks);
+                if info.tickrate.is_none() {
+                    info.tickrate = platform.get("defaultTickrate").and_then(Value::as_u64);
+                }
+            }
+            info.platform = Some(id);
+        }
+        Some(info)
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_rom_db(){
+    let rom = b"\x12\x00";
+    let hash = io::rom_hash(rom);
+    let hashes = format!(r#"{{"{}": 1, "0000000000000000000000000000000000000000": 0}}"#, hash);
+    let programs = format!(r##"[
+        {{"title": "Other", "roms": {{}}}},
+        {{
+            "title": "Spin",
+            "authors": ["A. Person", "B. Person"],
+            "roms": {{
+                "{}": {{
+                    "file": "spin.ch8",
+                    "platforms": ["superchip", "xochip"],
+                    "quirkyPlatforms": {{"superchip": {{"shift": false}}}},
+                    "keys": {{"left": 7, "right": 9, "a": 6}},
+                    "colors": {{"pixels": ["#102030", "#ffeedd"], "buzzer": "#ffaa00"}}
+                }}
+            }}
+        }}
+    ]"##, hash);
+    let platforms = r#"[
+        {"id": "superchip", "defaultTickrate": 30,
+         "quirks": {"shift": true, "memoryLeaveIUnchanged": true, "wrap": false, "jump": true, "vblank": false, "logic": false}},
+        {"id": "xochip", "defaultTickrate": 1000, "quirks": {"wrap": true}}
+    ]"#;
+    let db = RomDb::parse(&hashes, &programs, platforms).unwrap();
+    let info = db.lookup(rom).unwrap();
+    let quirks = Quirks { shift_vy: true, jump_vx: true, ..Quirks::default() };
+    if info.title != "Spin" || info.authors.len() != 2 || info.platform.as_deref() != Some("superchip") ||
+        info.quirks != Some(quirks) || info.instructions_per_second() != Some(1800) || info.key("a") != Some(6) {
+        panic!("Unexpected entry {:?}", info);
+    }
+    let palette = info.palette().unwrap().unwrap();
+    if palette.pixel(0).to_string() != "#102030" || palette.pixel(1).to_string() != "#ffeedd" {
+        panic!("Unexpected palette {:?}", palette);
+    }
+    if info.summary() != "Spin by A. Person, B. Person (superchip)\nKeys: a 6, left 7, right 9" {
+        panic!("Unexpected summary {}", info.summary());
+    }
+    if db.lookup(b"unknown").is_some() || RomDb::parse("{}", "{}", "[]").is_ok() {
+        panic!("Expected unknown ROMs and bad files to be rejected");
+    }
+}
@@ -0,0 +1,282 @@
+//! Rhai scripts that run alongside a ROM, for memory displays, automated
+//! input and simple cheats. A script can define any of
+//!
+//! ```text
+//! fn on_frame() { ... }              // after each frame's instructions
+//! fn on_exec(addr) { ... }           // before executing a watched address
+//! fn on_write(addr, value) { ... }   // after the program (not a poke) writes to memory
+//! ```
+//!
+//! and call these to look at or change the machine:
+//!
+//! ```text
+//! pc() set_pc(a)  i() set_i(a)  sp()  dt() set_dt(v)  st() set_st(v)
+//! reg(n) set_reg(n, v)  peek(addr) poke(addr, v)
+//! key(k) press(k) release(k)  frame()
+//! watch(addr)    // call on_exec when pc reaches addr
+//! show(text)     // add a line to the text shown beside the screen
+//! ```
+//!
+//! The top level of the script runs once when it is loaded, before the ROM,
+//! so it is the place to call `watch`.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+use rhai::{Dynamic, Engine, EvalAltResult, FuncArgs, Scope, AST};
+use cpu::Cpu;
+use frontend::Hook;
+use mem::MEM_SIZE;
+
+/// What the script's functions can see. The real `Cpu` is swapped in for
+/// the duration of each callback.
+struct State {
+    cpu: Cpu,
+    frame: u64,
+    watched: HashSet<u16>,
+    lines: Vec<String>,
+}
+
+pub struct ScriptHook {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    state: Rc<RefCell<State>>,
+    on_frame: bool,
+    on_exec: bool,
+    on_write: bool,
+    overlay: Vec<String>,
+}
+
+fn check(what: &str, value: i64, limit: i64) -> Result<(), Box<EvalAltResult>> {
+    if value < 0 || value >= limit {
+        return Err(format!("{} {} out of range", what, value).into());
+    }
+    Ok(())
+}
+
+impl ScriptHook {
+    pub fn load(file_name: &str) -> Result<ScriptHook, String> {
+        let mut source = String::new();
+        File::open(file_name)
+            .and_then(|mut f| f.read_to_string(&mut source))
+            .map_err(|e| format!("Error reading {}: {}", file_name, e))?;
+        ScriptHook::new(&source).map_err(|e| format!("Error in {}: {}", file_name, e))
+    }
+
+    pub fn new(source: &str) -> Result<ScriptHook, String> {
+        let state = Rc::new(RefCell::new(State {
+            cpu: Cpu::new(),
+            frame: 0,
+            watched: HashSet::new(),
+            lines: Vec::new(),
+        }));
+        let mut engine = Engine::new();
+        register_functions(&mut engine, &state);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast).map_err(|e| e.to_string())?;
+        let defines = |name: &str| ast.iter_functions().any(|f| f.name == name);
+        Ok(ScriptHook {
+            on_frame: defines("on_frame"),
+            on_exec: defines("on_exec"),
+            on_write: defines("on_write"),
+            engine,
+            ast,
+            scope,
+            state,
+            overlay: Vec::new(),
+        })
+    }
+
+    /// Calls a script function with `cpu` swapped in. If it fails the error
+    /// is printed and false is returned.
+    fn call<A: FuncArgs>(&mut self, cpu: &mut Cpu, name: &str, args: A) -> bool {
+        ::std::mem::swap(cpu, &mut self.state.borrow_mut().cpu);
+        let result = self.engine.call_fn::<Dynamic>(&mut self.scope, &self.ast, name, args);
+        ::std::mem::swap(cpu, &mut self.state.borrow_mut().cpu);
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Script error in {}, disabling it: {}", name, e);
+                false
+            }
+        }
+    }
+}
+
+impl Hook for ScriptHook {
+    fn before_instruction(&mut self, cpu: &mut Cpu) {
+        if self.on_write && !cpu.memory.journal_enabled() {
+            cpu.memory.set_journal(true);
+        }
+        if self.on_exec && self.state.borrow().watched.contains(&cpu.get_pc()) {
+            let pc = cpu.get_pc() as i64;
+            self.on_exec = self.call(cpu, "on_exec", (pc,));
+        }
+    }
+
+    fn after_instruction(&mut self, cpu: &mut Cpu) {
+        if !self.on_write || cpu.memory.writes().is_empty() {
+            return;
+        }
+        for (addr, value) in cpu.memory.writes().to_vec() {
+            if !self.call(cpu, "on_write", (addr as i64, value as i64)) {
+                self.on_write = false;
+                return;
+            }
+        }
+    }
+
+    fn end_frame(&mut self, cpu: &mut Cpu) {
+        if self.on_frame {
+            self.on_frame = self.call(cpu, "on_frame", ());
+        }
+        let mut state = self.state.borrow_mut();
+        state.frame += 1;
+        self.overlay = ::std::mem::take(&mut state.lines);
+    }
+
+    fn overlay(&self) -> Vec<String> {
+        self.overlay.clone()
+    }
+}
+
+fn register_functions(engine: &mut Engine, state: &Rc<RefCell<State>>) {
+    macro_rules! getter {
+        ($name:expr, |$cpu:ident| $body:expr) => {{
+            let state = state.clone();
+            engine.register_fn($name, move || -> i64 { let $cpu = &state.borrow().cpu; $body as i64 });
+        }}
+    }
+    getter!("pc", |cpu| cpu.get_pc());
+    getter!("i", |cpu| cpu.get_i());
+    getter!("sp", |cpu| cpu.get_sp());
+    getter!("dt", |cpu| cpu.get_dt());
+    getter!("st", |cpu| cpu.get_st());
+
+    macro_rules! setter {
+        ($name:expr, $limit:expr, |$cpu:ident, $v:ident| $body:expr) => {{
+            let state = state.clone();
+            engine.register_fn($name, move |$v: i64| -> Result<(), Box<EvalAltResult>> {
+                check($name, $v, $limit)?;
+                let $cpu = &mut state.borrow_mut().cpu;
+                $body;
+                Ok(())
+            });
+        }}
+    }
+    // PC needs room for a whole instruction
+    setter!("set_pc", MEM_SIZE as i64 - 1, |cpu, v| cpu.set_pc(v as u16));
+    setter!("set_i", MEM_SIZE as i64, |cpu, v| cpu.set_i(v as u16));
+    setter!("set_dt", 0x100, |cpu, v| cpu.set_dt(v as u8));
+    setter!("set_st", 0x100, |cpu, v| cpu.set_st(v as u8));
+    setter!("press", 16, |cpu, v| cpu.keydown(v as u8));
+    setter!("release", 16, |cpu, v| cpu.keyup(v as u8));
+
+    let s = state.clone();
+    engine.register_fn("reg", move |n: i64| -> Result<i64, Box<EvalAltResult>> {
+        check("register", n, 16)?;
+        Ok(s.borrow().cpu.get_reg(n as u8) as i64)
+    });
+    let s = state.clone();
+    engine.register_fn("set_reg", move |n: i64, v: i64| -> Result<(), Box<EvalAltResult>> {
+        check("register", n, 16)?;
+        check("value", v, 0x100)?;
+        s.borrow_mut().cpu.set_reg(n as u8, v as u8);
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("peek", move |addr: i64| -> Result<i64, Box<EvalAltResult>> {
+        check("address", addr, MEM_SIZE as i64)?;
+        Ok(s.borrow().cpu.memory.get_vec(addr as usize, 1)[0] as i64)
+    });
+    let s = state.clone();
+    engine.register_fn("poke", move |addr: i64, v: i64| -> Result<(), Box<EvalAltResult>> {
+        check("address", addr, MEM_SIZE as i64)?;
+        check("value", v, 0x100)?;
+        s.borrow_mut().cpu.memory.poke(addr as usize, v as u8);
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("key", move |k: i64| -> Result<bool, Box<EvalAltResult>> {
+        check("key", k, 16)?;
+        Ok(s.borrow().cpu.get_key(k as u8))
+    });
+    let s = state.clone();
+    engine.register_fn("frame", move || s.borrow().frame as i64);
+    let s = state.clone();
+    engine.register_fn("watch", move |addr: i64| -> Result<(), Box<EvalAltResult>> {
+        check("address", addr, MEM_SIZE as i64)?;
+        s.borrow_mut().watched.insert(addr as u16);
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("show", move |text: &str| s.borrow_mut().lines.push(text.to_string()));
+}
+
+//TESTS
+
+#[test]
+fn test_script_hook(){
+    let mut hook = ScriptHook::new(r#"
+        watch(0x202);
+        fn on_exec(addr) {
+            set_reg(1, 7);
+            press(0xA);
+            poke(0x320, 9);
+        }
+        fn on_write(addr, value) {
+            if addr == 0x300 { poke(0x301, value + 1); }
+            if addr == 0x320 { set_reg(2, 1); }
+        }
+        fn on_frame() {
+            show("V3 = " + reg(3) + ", frame " + frame());
+            poke(0x310, 0x55);
+        }
+    "#).unwrap();
+    let mut cpu = Cpu::new();
+    // LD V3, 4; LD I, 0x300; LD [I], V0 (stores V0 at 0x300)
+    cpu.memory.memset(0x200, &vec![0x63, 0x04, 0xA3, 0x00, 0xF0, 0x55]);
+    for _ in 0..3 {
+        cpu.memory.clear_writes();
+        hook.before_instruction(&mut cpu);
+        cpu.exec_instruction().unwrap();
+        hook.after_instruction(&mut cpu);
+    }
+    hook.end_frame(&mut cpu);
+    if cpu.get_reg(1) != 7 || !cpu.get_key(0xA) || cpu.memory.get_vec(0x320, 1) != vec![9] {
+        panic!("on_exec should have run at 0x202");
+    }
+    if cpu.get_reg(2) != 0 {
+        panic!("on_write shouldn't see the script's own writes");
+    }
+    if cpu.memory.get_vec(0x300, 2) != vec![0, 1] {
+        panic!("on_write should have seen the write to 0x300: {:?}", cpu.memory.get_vec(0x300, 2));
+    }
+    if cpu.memory.get_vec(0x310, 1) != vec![0x55] {
+        panic!("on_frame should poke memory");
+    }
+    if hook.overlay() != vec!["V3 = 4, frame 0".to_string()] {
+        panic!("Unexpected overlay {:?}", hook.overlay());
+    }
+
+    if ScriptHook::new("fn on_frame() { set_reg(16, 0); }").map(|mut h| {
+        h.end_frame(&mut cpu);
+        h.on_frame
+    }).unwrap() {
+        panic!("A failing callback should be disabled");
+    }
+    for script in ["fn on_frame() { set_pc(0xFFF); }", "fn on_frame() { set_i(0x1000); }"].iter() {
+        let mut h = ScriptHook::new(script).unwrap();
+        h.end_frame(&mut cpu);
+        if h.on_frame || cpu.get_pc() == 0xFFF || cpu.get_i() == 0x1000 {
+            panic!("PC and I should be limited to memory: {}", script);
+        }
+    }
+    if ScriptHook::new("fn broken( {").is_ok() {
+        panic!("Syntax errors should be reported");
+    }
+}
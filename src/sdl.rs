@@ -1,5 +1,257 @@
+use std::fs::File;
+use std::io;
 use sdl2;
-use io;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
+use audio::SquareWave;
+use cpu::Cpu;
+use flicker::{FlickerFilter, FlickerMode};
+use frontend::{AudioSink, Clock, Command, InputSource, VideoSink};
+use mem;
+use palette::{Palette, Rgb};
+use postfx;
+use postfx::{Effects, Image};
+use romdb::RomInfo;
+
+pub const WINDOW_WIDTH : u32 = 800;
+pub const WINDOW_HEIGHT: u32 = 600;
+
+const VIEW_WIDTH:  u32 = 640;
+const VIEW_HEIGHT: u32 = 320;
+
+const KEYS_WIDTH:  u32 = 280;
+const KEYS_HEIGHT: u32 = 280;
+const OVERLAY_LINE_HEIGHT: u32 = 28;
+
+/// Cached textures used to draw a frame.
+///
+/// Each frame the framebuffer is turned into an image (see
+/// `postfx::render_frame`),
+/// which is streamed into a single texture and scaled up by the renderer.
+/// Text is only re-rendered when its contents change.
+pub struct Renderer<'r> {
+    texture_creator: &'r TextureCreator<WindowContext>,
+    screen: Texture<'r>,
+    frame: Image,
+    ips_text: Option<CachedText<'r>>,
+    overlay_text: Vec<Option<CachedText<'r>>>,
+    pub overlay: Vec<String>,
+    pub palette: Palette,
+    pub flicker: FlickerFilter,
+    pub effects: Effects,
+}
+
+struct CachedText<'r> {
+    text: String,
+    color: Rgb,
+    texture: Texture<'r>,
+    width: u32,
+    height: u32,
+}
+
+impl<'r> Renderer<'r> {
+    pub fn new(texture_creator: &'r TextureCreator<WindowContext>,
+               palette: Palette,
+               flicker: FlickerMode,
+               effects: Effects) -> Renderer<'r> {
+        let frame = Image::new(mem::SCREEN_WIDTH, mem::SCREEN_HEIGHT);
+        let screen = create_screen_texture(texture_creator, &frame);
+        Renderer {
+            texture_creator,
+            screen,
+            frame,
+            ips_text: None,
+            overlay_text: Vec::new(),
+            overlay: Vec::new(),
+            palette,
+            flicker: FlickerFilter::new(flicker),
+            effects,
+        }
+    }
+
+    fn update_screen(&mut self, cpu: &Cpu) {
+        let frame = postfx::render_frame(cpu, &self.palette, &mut self.flicker, &self.effects);
+        if frame.width != self.frame.width || frame.height != self.frame.height {
+            self.screen = create_screen_texture(self.texture_creator, &frame);
+        }
+        self.frame = frame;
+        if let Err(v) = self.screen.update(None, &self.frame.data, self.frame.width*3) {
+            panic!("Failed to update screen texture: {}", v);
+        }
+    }
+
+    /// Saves the last frame drawn, after post-processing, as a PPM image.
+    pub fn save_screenshot(&self, file_name: &str) -> io::Result<()> {
+        let mut f = File::create(file_name)?;
+        self.frame.write_ppm(&mut f)
+    }
+}
+
+fn create_screen_texture<'r>(texture_creator: &'r TextureCreator<WindowContext>,
+                             frame: &Image) -> Texture<'r> {
+    texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, frame.width as u32, frame.height as u32)
+        .unwrap()
+}
+
+fn sdl_color(Rgb(r, g, b): Rgb) -> pixels::Color {
+    pixels::Color::RGB(r, g, b)
+}
+
+fn draw_view(canvas: &mut WindowCanvas, renderer: &mut Renderer, cpu: &Cpu, x: i32, y: i32){
+    renderer.update_screen(cpu);
+    let view_rect = sdl2::rect::Rect::new(x, y, VIEW_WIDTH, VIEW_HEIGHT);
+    if let Err(v) = canvas.copy(&renderer.screen, None, Some(view_rect)) {
+        panic!("Call to copy({:?}) failed: {}", view_rect, v);
+    }
+    canvas.set_draw_color(sdl_color(renderer.palette.ui_text));
+    let border_rect = sdl2::rect::Rect::new(0, 0, VIEW_WIDTH, VIEW_HEIGHT);
+    if let Err(v) = canvas.draw_rect(border_rect) {
+        panic!("Call to draw_rect({:?}) failed: {}", border_rect, v);
+    }
+}
+
+fn draw_keys(canvas: &mut WindowCanvas, palette: &Palette, cpu: &Cpu, x: i32, y: i32){
+    let x_off = x;
+    let y_off = y;
+    for x in 0..4 {
+        for y in 0..4 {
+            let x0: i32 = 
+                ((x*KEYS_WIDTH)/(4 as u32)) as i32;
+            let y0: i32 = 
+                ((y*KEYS_HEIGHT)/(4 as u32)) as i32;
+            let x1: i32 = 
+                (((x+1)*KEYS_WIDTH)/(4 as u32)) as i32;
+            let y1: i32 = 
+                (((y+1)*KEYS_HEIGHT)/(4 as u32)) as i32;
+
+            let key_num = (x as u8)+(y as u8)*4u8;
+            canvas.set_draw_color(sdl_color(palette.keypad));
+            let square_rect = sdl2::rect::Rect::new(x_off+x0, y_off+y0, (x1-x0) as u32, (y1-y0) as u32);
+            if cpu.get_key(key_num) {
+                if let Err(v) = canvas.fill_rect(square_rect) {
+                    panic!("Call to fill_rect({:?}) failed: {}", square_rect, v);
+                }
+            }else{
+                let key_fade = cpu.get_key_trail(key_num);
+                canvas.set_draw_color(sdl_color(palette.ui_background.blend(palette.keypad_trail, key_fade)));
+                if let Err(v) = canvas.fill_rect(square_rect) {
+                    panic!("Call to fill_rect({:?}) failed: {}", square_rect, v);
+                }
+                canvas.set_draw_color(sdl_color(palette.keypad));
+                if let Err(v) = canvas.draw_rect(square_rect) {
+                    panic!("Call to draw_rect({:?}) failed: {}", square_rect, v);
+                }
+            }
+        }
+    }
+}
+
+fn draw_text<'r>(contexts: &mut Contexts,
+                 texture_creator: &'r TextureCreator<WindowContext>,
+                 cache: &mut Option<CachedText<'r>>,
+                 render_text: String,
+                 text_color: Rgb,
+                 x: i32,
+                 y: i32) {
+    let stale = match *cache {
+        Some(ref cached) => cached.text != render_text || cached.color != text_color,
+        None => true,
+    };
+    if stale {
+        // SDL_ttf can't render empty strings
+        if render_text.is_empty() {
+            *cache = None;
+            return;
+        }
+        let rendered_text =
+            contexts.font.render(&render_text).blended(sdl_color(text_color)).unwrap();
+        let texture =
+            texture_creator.create_texture_from_surface(&rendered_text).unwrap();
+        *cache = Some(CachedText {
+            text: render_text,
+            color: text_color,
+            texture,
+            width: rendered_text.width(),
+            height: rendered_text.height(),
+        });
+    }
+
+    if let Some(ref cached) = *cache {
+        let target = sdl2::rect::Rect::new(x, y, cached.width, cached.height);
+        contexts.canvas.copy(&cached.texture, None, Some(target)).unwrap();
+    }
+}
+
+fn draw_ips(contexts: &mut Contexts, renderer: &mut Renderer, ips: u64) {
+    let text_color = renderer.palette.ui_text;
+    draw_text(contexts, renderer.texture_creator, &mut renderer.ips_text,
+              format!("Instructions Per Second: {}", ips), text_color,
+              (KEYS_WIDTH + 10) as i32, (VIEW_HEIGHT + 10) as i32);
+}
+
+/// Draws the lines set by hooks such as scripts below the IPS counter.
+fn draw_overlay(contexts: &mut Contexts, renderer: &mut Renderer) {
+    let text_color = renderer.palette.ui_text;
+    renderer.overlay_text.resize_with(renderer.overlay.len(), || None);
+    for (i, line) in renderer.overlay.iter().enumerate() {
+        draw_text(contexts, renderer.texture_creator, &mut renderer.overlay_text[i],
+                  line.clone(), text_color,
+                  (KEYS_WIDTH + 10) as i32, (VIEW_HEIGHT + 10 + OVERLAY_LINE_HEIGHT*(i as u32 + 1)) as i32);
+    }
+}
+
+pub fn draw_screen(contexts: &mut Contexts, renderer: &mut Renderer, cpu: &Cpu) {
+    contexts.canvas.set_draw_color(sdl_color(renderer.palette.ui_background));
+    contexts.canvas.clear();
+    draw_view(&mut contexts.canvas, renderer, cpu, 0, 0);
+    draw_keys(&mut contexts.canvas, &renderer.palette, cpu, 0, 320);
+    draw_ips(contexts, renderer, cpu.get_ips());
+    draw_overlay(contexts, renderer);
+    contexts.canvas.present();
+}
+
+pub enum KeyState {
+    KeyDown,
+    KeyUp,
+}
+
+#[inline(always)]
+pub fn parse_input(cpu: &mut Cpu, keycode: Keycode, state: KeyState) {
+    let key_index = match keycode {
+        Keycode::Num4 => Some(0x0u8),
+        Keycode::Num5 => Some(0x1u8),
+        Keycode::Num6 => Some(0x2u8),
+        Keycode::Num7 => Some(0x3u8),
+
+        Keycode::R    => Some(0x4u8),
+        Keycode::T    => Some(0x5u8),
+        Keycode::Y    => Some(0x6u8),
+        Keycode::U    => Some(0x7u8),
+
+        Keycode::F    => Some(0x8u8),
+        Keycode::G    => Some(0x9u8),
+        Keycode::H    => Some(0xau8),
+        Keycode::J    => Some(0xbu8),
+
+        Keycode::V    => Some(0xcu8),
+        Keycode::B    => Some(0xdu8),
+        Keycode::N    => Some(0xeu8),
+        Keycode::M    => Some(0xfu8),
+        _ => None,
+    };
+    if let Some(key_index) = key_index {
+        match state {
+            KeyState::KeyDown => cpu.keydown(key_index),
+            KeyState::KeyUp   => cpu.keyup(key_index),
+        };
+    }
+}
 
 pub struct Contexts<'scope> {
     pub sdl: sdl2::Sdl,
@@ -14,7 +266,7 @@ pub fn with_contexts<T, F>(func: F) -> T
     let sdl_ttf_context = sdl2::ttf::init().unwrap();
     let font = sdl_ttf_context.load_font("fonts/Carlito-Regular.ttf", 24).unwrap();
     let video_sybsys = sdl_context.video().unwrap();
-    let window = video_sybsys.window("Chippy", io::WINDOW_WIDTH, io::WINDOW_HEIGHT)
+    let window = video_sybsys.window("Chippy", WINDOW_WIDTH, WINDOW_HEIGHT)
         .position_centered()
         .opengl()
         .build()
@@ -27,3 +279,208 @@ pub fn with_contexts<T, F>(func: F) -> T
     };
     func(contexts)
 }
+
+/// Draws the emulator into the SDL window.
+pub struct SdlVideo<'scope, 'r> {
+    contexts: Contexts<'scope>,
+    renderer: Renderer<'r>,
+    screenshot_count: u32,
+}
+
+impl<'scope, 'r> SdlVideo<'scope, 'r> {
+    pub fn new(contexts: Contexts<'scope>,
+               texture_creator: &'r TextureCreator<WindowContext>,
+               palette: Palette,
+               flicker: FlickerMode,
+               effects: Effects) -> SdlVideo<'scope, 'r> {
+        SdlVideo {
+            contexts,
+            renderer: Renderer::new(texture_creator, palette, flicker, effects),
+            screenshot_count: 0,
+        }
+    }
+}
+
+impl<'scope, 'r> VideoSink for SdlVideo<'scope, 'r> {
+    fn draw(&mut self, cpu: &Cpu) {
+        draw_screen(&mut self.contexts, &mut self.renderer, cpu);
+    }
+    fn set_overlay(&mut self, lines: &[String]) {
+        self.renderer.overlay = lines.to_vec();
+    }
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::CyclePalette => {
+                self.renderer.palette = self.renderer.palette.next();
+            }
+            Command::CycleFlicker => {
+                let mode = self.renderer.flicker.mode().next();
+                println!("Flicker reduction: {}", mode);
+                self.renderer.flicker.set_mode(mode);
+            }
+            Command::Screenshot => {
+                self.screenshot_count += 1;
+                let screenshot_name = format!("chippy-{}.ppm", self.screenshot_count);
+                match self.renderer.save_screenshot(&screenshot_name) {
+                    Ok(()) => println!("Saved screenshot {}", screenshot_name),
+                    Err(e) => println!("Error saving screenshot {}: {}", screenshot_name, e),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+struct Beeper {
+    wave: SquareWave,
+}
+
+impl AudioCallback for Beeper {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        // The device is paused while the tone is off
+        self.wave.fill(out, true);
+    }
+}
+
+/// Plays the buzzer through SDL audio. If no audio device can be opened the
+/// emulator carries on silently.
+pub struct SdlAudio {
+    device: Option<AudioDevice<Beeper>>,
+    playing: bool,
+}
+
+impl SdlAudio {
+    pub fn new(sdl: &sdl2::Sdl) -> SdlAudio {
+        let desired_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None,
+        };
+        let device = sdl.audio().and_then(|audio| {
+            audio.open_playback(None, &desired_spec, |spec| Beeper { wave: SquareWave::new(spec.freq) })
+        });
+        if let Err(ref e) = device {
+            println!("Could not open audio device, sound is disabled: {}", e);
+        }
+        SdlAudio {
+            device: device.ok(),
+            playing: false,
+        }
+    }
+}
+
+impl AudioSink for SdlAudio {
+    fn set_tone(&mut self, on: bool) {
+        if on == self.playing {
+            return;
+        }
+        self.playing = on;
+        if let Some(ref device) = self.device {
+            if on {
+                device.resume();
+            } else {
+                device.pause();
+            }
+        }
+    }
+}
+
+/// Keys bound to the ROM database's actions, on top of the usual keypad.
+const ACTION_KEYS: [(Keycode, &str); 6] = [
+    (Keycode::Up, "up"), (Keycode::Down, "down"), (Keycode::Left, "left"), (Keycode::Right, "right"),
+    (Keycode::Space, "a"), (Keycode::Return, "b"),
+];
+
+/// Reads keyboard input from SDL events.
+pub struct SdlInput {
+    events: sdl2::EventPump,
+    actions: Vec<(Keycode, u8)>,
+}
+
+impl SdlInput {
+    pub fn new(sdl: &sdl2::Sdl) -> SdlInput {
+        SdlInput { events: sdl.event_pump().unwrap(), actions: Vec::new() }
+    }
+
+    /// Binds the arrow keys, Space and Return to the keys a ROM uses for
+    /// up, down, left, right, a and b.
+    pub fn map_actions(&mut self, info: &RomInfo) {
+        self.actions = ACTION_KEYS.iter()
+            .filter_map(|&(keycode, action)| info.key(action).map(|key| (keycode, key)))
+            .collect();
+    }
+}
+
+/// Passes a key to `cpu`, through the ROM's action bindings if it has one.
+fn action_input(actions: &[(Keycode, u8)], cpu: &mut Cpu, keycode: Keycode, state: KeyState) {
+    match actions.iter().find(|&&(bound, _)| bound == keycode) {
+        Some(&(_, key)) => match state {
+            KeyState::KeyDown => cpu.keydown(key),
+            KeyState::KeyUp   => cpu.keyup(key),
+        },
+        None => parse_input(cpu, keycode, state),
+    }
+}
+
+impl InputSource for SdlInput {
+    fn poll_events(&mut self, cpu: &mut Cpu) -> Vec<Command> {
+        let mut commands = Vec::new();
+        for event in self.events.poll_iter() {
+            match event {
+                Event::Quit {..} => commands.push(Command::Quit),
+                Event::KeyDown {keycode: Some(keycode), ..} => match keycode {
+                    // Exit emulator
+                    Keycode::Escape    => commands.push(Command::Quit),
+                    // Increase emulator speed
+                    Keycode::Q         => commands.push(Command::IncreaseSpeed),
+                    // Decrease emulator speed
+                    Keycode::A         => commands.push(Command::DecreaseSpeed),
+                    // Cycle colour palette
+                    Keycode::P         => commands.push(Command::CyclePalette),
+                    // Cycle flicker reduction mode
+                    Keycode::O         => commands.push(Command::CycleFlicker),
+                    // Save a screenshot
+                    Keycode::F12       => commands.push(Command::Screenshot),
+                    // Reset emulator
+                    Keycode::Backspace => commands.push(Command::Reset),
+                    // Pass input into Chip8 io routine, also moving through
+                    // the launcher's list
+                    keycode => {
+                        match keycode {
+                            Keycode::Up     => commands.push(Command::Up),
+                            Keycode::Down   => commands.push(Command::Down),
+                            Keycode::Return => commands.push(Command::Select),
+                            _ => (),
+                        }
+                        action_input(&self.actions, cpu, keycode, KeyState::KeyDown)
+                    }
+                },
+                Event::KeyUp {keycode: Some(keycode), ..} => action_input(&self.actions, cpu, keycode, KeyState::KeyUp),
+                _ => (),
+            };
+        }
+        commands
+    }
+}
+
+/// Paces frames with SDL's timer.
+pub struct SdlClock {
+    timer: sdl2::TimerSubsystem,
+}
+
+impl SdlClock {
+    pub fn new(sdl: &sdl2::Sdl) -> SdlClock {
+        SdlClock { timer: sdl.timer().unwrap() }
+    }
+}
+
+impl Clock for SdlClock {
+    fn ticks(&mut self) -> u32 {
+        self.timer.ticks()
+    }
+    fn delay(&mut self, ms: u32) {
+        self.timer.delay(ms);
+    }
+}
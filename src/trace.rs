@@ -0,0 +1,414 @@
+//! Logs executed instructions to a file, for comparing runs against other
+//! emulators. Each record holds the instruction's index in the run, its
+//! address, the opcode and what it changed: registers, I, SP, the timers and
+//! memory. PC isn't listed, it changes every time.
+//!
+//! Traces are written as text, one instruction per line:
+//!
+//! ```text
+//! 12 20A 6A02 LD VA, 0x02      VA=02
+//! 13 20C F355 LD [I], V3       [300]=00 [301]=00 [302]=00 [303]=2A
+//! ```
+//!
+//! as full state lines for `lockstep` to check other emulators against, or
+//! in a compact binary form for long runs, read back with `TraceReader`.
+//! It starts with `CH8TRACE` and a version byte, followed by the records:
+//! the gap in index since the previous record as a LEB128 number, PC and
+//! opcode (u16 big endian), the number of changes, then each change as a tag
+//! (0x0-0xF for V0-VF, 0x10 I, 0x11 SP, 0x12 DT, 0x13 ST, 0x20 memory)
+//! followed by its value; I is a u16, memory an address (u16) and a byte.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use cpu::Cpu;
+use disasm;
+use frontend::Hook;
+use lockstep::State;
+use mem::MEM_SIZE;
+
+const MAGIC: &[u8; 8] = b"CH8TRACE";
+const VERSION: u8 = 1;
+
+const TAG_I: u8 = 0x10;
+const TAG_SP: u8 = 0x11;
+const TAG_DT: u8 = 0x12;
+const TAG_ST: u8 = 0x13;
+const TAG_MEM: u8 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+    State,
+}
+
+impl TraceFormat {
+    pub fn parse(spec: &str) -> Result<TraceFormat, String> {
+        match spec {
+            "text" => Ok(TraceFormat::Text),
+            "binary" => Ok(TraceFormat::Binary),
+            "state" => Ok(TraceFormat::State),
+            _ => Err(format!("Unknown trace format \"{}\", expected text, binary or state", spec)),
+        }
+    }
+}
+
+/// Parses an inclusive range written as `first-last`, in the given radix.
+pub fn parse_range(spec: &str, radix: u32) -> Result<(u64, u64), String> {
+    let parse = |v: &str| u64::from_str_radix(v.trim_start_matches("0x"), radix)
+        .map_err(|_| format!("Invalid number \"{}\" in range \"{}\"", v, spec));
+    let (first, last) = match spec.split_once('-') {
+        Some((first, last)) => (parse(first)?, parse(last)?),
+        None => return Err(format!("Invalid range \"{}\", expected first-last", spec)),
+    };
+    if first > last {
+        return Err(format!("Range \"{}\" is backwards", spec));
+    }
+    Ok((first, last))
+}
+
+/// Which instructions to log. Both ranges are inclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraceFilter {
+    /// Only log instructions at these addresses.
+    pub addresses: Option<(u16, u16)>,
+    /// Only log instructions with these indices, counting from 0 at the
+    /// start of the run.
+    pub window: Option<(u64, u64)>,
+}
+
+impl TraceFilter {
+    fn matches(&self, index: u64, pc: u16) -> bool {
+        self.addresses.is_none_or(|(first, last)| first <= pc && pc <= last) &&
+            self.window.is_none_or(|(first, last)| first <= index && index <= last)
+    }
+}
+
+/// Something an instruction changed, with its new value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Reg(u8, u8),
+    I(u16),
+    Sp(u8),
+    Dt(u8),
+    St(u8),
+    Mem(u16, u8),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::Reg(reg, value) => write!(f, "V{:X}={:02X}", reg, value),
+            Change::I(value)        => write!(f, "I={:03X}", value),
+            Change::Sp(value)       => write!(f, "SP={:X}", value),
+            Change::Dt(value)       => write!(f, "DT={:02X}", value),
+            Change::St(value)       => write!(f, "ST={:02X}", value),
+            Change::Mem(addr, value) => write!(f, "[{:03X}]={:02X}", addr, value),
+        }
+    }
+}
+
+/// One executed instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    pub index: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub changes: Vec<Change>,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let changes: Vec<String> = self.changes.iter().map(|c| c.to_string()).collect();
+        let line = format!("{} {:03X} {:04X} {:<16} {}", self.index, self.pc, self.opcode,
+                           disasm::disassemble(self.opcode), changes.join(" "));
+        write!(f, "{}", line.trim_end())
+    }
+}
+
+fn write_leb128<W: Write>(out: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return out.write_u8(byte);
+        }
+        out.write_u8(byte | 0x80)?;
+    }
+}
+
+fn read_leb128<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = input.read_u8()?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "number too long"))
+}
+
+impl TraceRecord {
+    fn write_binary<W: Write>(&self, out: &mut W, gap: u64) -> io::Result<()> {
+        write_leb128(out, gap)?;
+        out.write_u16::<BigEndian>(self.pc)?;
+        out.write_u16::<BigEndian>(self.opcode)?;
+        out.write_u8(self.changes.len() as u8)?;
+        for change in self.changes.iter() {
+            match *change {
+                Change::Reg(reg, value) => { out.write_u8(reg)?; out.write_u8(value)?; }
+                Change::I(value)        => { out.write_u8(TAG_I)?; out.write_u16::<BigEndian>(value)?; }
+                Change::Sp(value)       => { out.write_u8(TAG_SP)?; out.write_u8(value)?; }
+                Change::Dt(value)       => { out.write_u8(TAG_DT)?; out.write_u8(value)?; }
+                Change::St(value)       => { out.write_u8(TAG_ST)?; out.write_u8(value)?; }
+                Change::Mem(addr, value) => {
+                    out.write_u8(TAG_MEM)?;
+                    out.write_u16::<BigEndian>(addr)?;
+                    out.write_u8(value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads back a binary trace.
+pub struct TraceReader<R: Read> {
+    input: R,
+    index: Option<u64>,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> Result<TraceReader<R>, String> {
+        let mut header = [0u8; 9];
+        input.read_exact(&mut header).map_err(|e| format!("Error reading trace header: {}", e))?;
+        if &header[..8] != MAGIC || header[8] != VERSION {
+            return Err("Not a chippy binary trace".to_string());
+        }
+        Ok(TraceReader { input, index: None })
+    }
+
+    /// Reads the next record, or None at the end of the trace.
+    pub fn next_record(&mut self) -> Result<Option<TraceRecord>, String> {
+        let gap = match read_leb128(&mut self.input) {
+            Ok(gap) => gap,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(format!("Error reading trace: {}", e)),
+        };
+        self.read_record(gap).map_err(|e| format!("Error reading trace: {}", e)).map(Some)
+    }
+
+    fn read_record(&mut self, gap: u64) -> io::Result<TraceRecord> {
+        let index = self.index.map_or(gap, |index| index + gap);
+        self.index = Some(index);
+        let input = &mut self.input;
+        let pc = input.read_u16::<BigEndian>()?;
+        let opcode = input.read_u16::<BigEndian>()?;
+        let count = input.read_u8()?;
+        let mut changes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            changes.push(match input.read_u8()? {
+                reg @ 0x0..=0xF => Change::Reg(reg, input.read_u8()?),
+                TAG_I   => Change::I(input.read_u16::<BigEndian>()?),
+                TAG_SP  => Change::Sp(input.read_u8()?),
+                TAG_DT  => Change::Dt(input.read_u8()?),
+                TAG_ST  => Change::St(input.read_u8()?),
+                TAG_MEM => Change::Mem(input.read_u16::<BigEndian>()?, input.read_u8()?),
+                tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown tag {:#x}", tag))),
+            });
+        }
+        Ok(TraceRecord { index, pc, opcode, changes })
+    }
+}
+
+/// The machine state before an instruction, to work out what it changed.
+struct Snapshot {
+    pc: u16,
+    opcode: u16,
+    reg: [u8; 16],
+    i: u16,
+    sp: u8,
+    dt: u8,
+    st: u8,
+}
+
+impl Snapshot {
+    fn take(cpu: &Cpu) -> Snapshot {
+        let pc = cpu.get_pc();
+        let mut reg = [0u8; 16];
+        for (n, value) in reg.iter_mut().enumerate() {
+            *value = cpu.get_reg(n as u8);
+        }
+        Snapshot {
+            pc,
+            opcode: if (pc as usize) + 1 < MEM_SIZE { cpu.memory.read_u16(pc as usize) } else { 0 },
+            reg,
+            i: cpu.get_i(),
+            sp: cpu.get_sp() as u8,
+            dt: cpu.get_dt(),
+            st: cpu.get_st(),
+        }
+    }
+
+    fn changes(&self, cpu: &Cpu) -> Vec<Change> {
+        let mut changes: Vec<Change> = (0..16u8)
+            .filter(|&n| cpu.get_reg(n) != self.reg[n as usize])
+            .map(|n| Change::Reg(n, cpu.get_reg(n)))
+            .collect();
+        if cpu.get_i() != self.i {
+            changes.push(Change::I(cpu.get_i()));
+        }
+        if cpu.get_sp() as u8 != self.sp {
+            changes.push(Change::Sp(cpu.get_sp() as u8));
+        }
+        if cpu.get_dt() != self.dt {
+            changes.push(Change::Dt(cpu.get_dt()));
+        }
+        if cpu.get_st() != self.st {
+            changes.push(Change::St(cpu.get_st()));
+        }
+        changes.extend(cpu.memory.writes().iter().map(|&(addr, value)| Change::Mem(addr, value)));
+        changes
+    }
+}
+
+/// Writes a trace of the instructions matching a filter.
+pub struct TraceHook<W: Write> {
+    out: W,
+    format: TraceFormat,
+    filter: TraceFilter,
+    count: u64,
+    last_logged: Option<u64>,
+    before: Option<Snapshot>,
+    failed: bool,
+}
+
+impl TraceHook<BufWriter<File>> {
+    pub fn create(file_name: &str, format: TraceFormat, filter: TraceFilter) -> Result<TraceHook<BufWriter<File>>, String> {
+        let file = File::create(file_name).map_err(|e| format!("Error creating {}: {}", file_name, e))?;
+        TraceHook::new(BufWriter::new(file), format, filter)
+            .map_err(|e| format!("Error writing {}: {}", file_name, e))
+    }
+}
+
+impl<W: Write> TraceHook<W> {
+    pub fn new(mut out: W, format: TraceFormat, filter: TraceFilter) -> Result<TraceHook<W>, String> {
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC).and_then(|_| out.write_u8(VERSION)).map_err(|e| e.to_string())?;
+        }
+        Ok(TraceHook {
+            out,
+            format,
+            filter,
+            count: 0,
+            last_logged: None,
+            before: None,
+            failed: false,
+        })
+    }
+
+    /// Flushes and returns the output.
+    pub fn into_inner(mut self) -> W {
+        let _ = self.out.flush();
+        self.out
+    }
+
+    fn write(&mut self, before: &Snapshot, cpu: &Cpu) -> io::Result<()> {
+        if self.format == TraceFormat::State {
+            return writeln!(self.out, "{}", State::capture(cpu, before.pc, before.opcode));
+        }
+        let record = TraceRecord {
+            index: self.count,
+            pc: before.pc,
+            opcode: before.opcode,
+            changes: before.changes(cpu),
+        };
+        match self.format {
+            TraceFormat::Binary => {
+                let gap = self.last_logged.map_or(record.index, |last| record.index - last);
+                record.write_binary(&mut self.out, gap)
+            }
+            _ => writeln!(self.out, "{}", record),
+        }
+    }
+}
+
+impl<W: Write> Hook for TraceHook<W> {
+    fn before_instruction(&mut self, cpu: &mut Cpu) {
+        if !cpu.memory.journal_enabled() {
+            cpu.memory.set_journal(true);
+        }
+        if !self.failed && self.filter.matches(self.count, cpu.get_pc()) {
+            self.before = Some(Snapshot::take(cpu));
+        }
+    }
+
+    fn after_instruction(&mut self, cpu: &mut Cpu) {
+        if let Some(before) = self.before.take() {
+            if let Err(e) = self.write(&before, cpu) {
+                println!("Error writing trace, stopping it: {}", e);
+                self.failed = true;
+            }
+            self.last_logged = Some(self.count);
+        }
+        self.count += 1;
+    }
+}
+
+//TESTS
+
+#[test]
+fn test_trace_hook(){
+    // LD VA, 2; LD I, 0x300; LD [I], V1; CALL 0x20A; JP 0x20A
+    let rom = vec![0x6A, 0x02, 0xA3, 0x00, 0xF1, 0x55, 0x22, 0x0A, 0x00, 0x00, 0x12, 0x0A];
+    let run = |format: TraceFormat, filter: TraceFilter| -> Vec<u8> {
+        let mut cpu = Cpu::new();
+        cpu.memory.memset(0x200, &rom);
+        cpu.set_reg(1, 0x2A);
+        let mut hook = TraceHook::new(Vec::new(), format, filter).unwrap();
+        for _ in 0..6 {
+            cpu.memory.clear_writes();
+            hook.before_instruction(&mut cpu);
+            cpu.exec_instruction().unwrap();
+            hook.after_instruction(&mut cpu);
+        }
+        hook.into_inner()
+    };
+
+    let text = String::from_utf8(run(TraceFormat::Text, Default::default())).unwrap();
+    let expected = "\
+0 200 6A02 LD VA, 0x02      VA=02
+1 202 A300 LD I, 0x300      I=300
+2 204 F155 LD [I], V1       [300]=00 [301]=2A
+3 206 220A CALL 0x20A       SP=1
+4 20A 120A JP 0x20A
+5 20A 120A JP 0x20A
+";
+    if text != expected {
+        panic!("Unexpected text trace:\n{}", text);
+    }
+
+    let filter = TraceFilter { addresses: Some((0x202, 0x20A)), window: Some((2, 4)) };
+    let binary = run(TraceFormat::Binary, filter);
+    let mut reader = TraceReader::new(&binary[..]).unwrap();
+    let mut records = Vec::new();
+    while let Some(record) = reader.next_record().unwrap() {
+        records.push(record.to_string());
+    }
+    if records != expected.lines().skip(2).take(3).collect::<Vec<_>>() {
+        panic!("Unexpected binary trace: {:?}", records);
+    }
+
+    let state = String::from_utf8(run(TraceFormat::State, Default::default())).unwrap();
+    if !state.starts_with("PC=200 OP=6A02 V=002A0000000000000000020000000000 I=000 SP=0 DT=00 ST=00\n") {
+        panic!("Unexpected state trace:\n{}", state);
+    }
+
+    if parse_range("300-2ff", 16).is_ok() || parse_range("10", 10).is_ok() || parse_range("0x200-2FF", 16) != Ok((0x200, 0x2ff)) {
+        panic!("Ranges not parsed properly");
+    }
+}
@@ -0,0 +1,362 @@
+use libc;
+use std::io;
+use std::io::{Read, Write};
+use cpu::Cpu;
+use flicker::{FlickerFilter, FlickerMode};
+use frontend::{AudioSink, Command, InputSource, VideoSink};
+use mem;
+use palette::{Palette, Rgb};
+
+/// Terminals only report key presses, so a Chip8 key is held down for this
+/// many frames after its last press (key repeat keeps it held).
+const KEY_HOLD_FRAMES: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Glyphs {
+    /// Two pixels per character cell using the upper half block.
+    HalfBlock,
+    /// Eight pixels per character cell using braille dots.
+    Braille,
+}
+
+/// Draws the emulator inside the terminal, without opening a window.
+pub struct TuiVideo {
+    glyphs: Glyphs,
+    palette: Palette,
+    flicker: FlickerFilter,
+    overlay: Vec<String>,
+    first_frame: bool,
+}
+
+impl TuiVideo {
+    pub fn new(glyphs: Glyphs, palette: Palette, flicker: FlickerMode) -> TuiVideo {
+        // Switch to the alternate screen and hide the cursor
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = io::stdout().flush();
+        TuiVideo {
+            glyphs,
+            palette,
+            flicker: FlickerFilter::new(flicker),
+            overlay: Vec::new(),
+            first_frame: true,
+        }
+    }
+}
+
+impl Drop for TuiVideo {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
+
+impl VideoSink for TuiVideo {
+    fn draw(&mut self, cpu: &Cpu) {
+        let intensities = self.flicker.apply(&cpu.memory.framebuffer()).to_vec();
+        let screen = match self.glyphs {
+            Glyphs::HalfBlock => render_half_blocks(&intensities, &self.palette),
+            Glyphs::Braille => render_braille(&intensities, &self.palette),
+        };
+        let mut panel = register_panel(cpu, self.flicker.mode());
+        panel.extend(self.overlay.iter().cloned());
+
+        let mut out = String::new();
+        if self.first_frame {
+            out.push_str("\x1b[2J");
+            self.first_frame = false;
+        }
+        out.push_str("\x1b[H");
+        for i in 0..screen.len().max(panel.len()) {
+            out.push_str(screen.get(i).map(|s| s.as_str()).unwrap_or(""));
+            out.push_str("\x1b[0m  ");
+            out.push_str(panel.get(i).map(|s| s.as_str()).unwrap_or(""));
+            out.push_str("\x1b[K\r\n");
+        }
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let _ = handle.write_all(out.as_bytes());
+        let _ = handle.flush();
+    }
+    fn set_overlay(&mut self, lines: &[String]) {
+        self.overlay = lines.to_vec();
+    }
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::CyclePalette => self.palette = self.palette.next(),
+            Command::CycleFlicker => {
+                let mode = self.flicker.mode().next();
+                self.flicker.set_mode(mode);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Rings the terminal bell when the buzzer starts.
+pub struct TuiAudio {
+    playing: bool,
+}
+
+impl TuiAudio {
+    pub fn new() -> TuiAudio {
+        TuiAudio { playing: false }
+    }
+}
+
+impl Default for TuiAudio {
+    fn default() -> TuiAudio {
+        TuiAudio::new()
+    }
+}
+
+impl AudioSink for TuiAudio {
+    fn set_tone(&mut self, on: bool) {
+        if on && !self.playing {
+            print!("\x07");
+            let _ = io::stdout().flush();
+        }
+        self.playing = on;
+    }
+}
+
+/// Reads keys from the terminal, which is put into raw mode while this
+/// exists.
+pub struct TuiInput {
+    saved_termios: Option<libc::termios>,
+    held_keys: [u8; 16],
+}
+
+impl TuiInput {
+    pub fn new() -> TuiInput {
+        TuiInput {
+            saved_termios: enter_raw_mode(),
+            held_keys: [0u8; 16],
+        }
+    }
+}
+
+impl Default for TuiInput {
+    fn default() -> TuiInput {
+        TuiInput::new()
+    }
+}
+
+impl Drop for TuiInput {
+    fn drop(&mut self) {
+        if let Some(ref termios) = self.saved_termios {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+            }
+        }
+    }
+}
+
+/// Turns off line buffering and echo on stdin and makes reads non-blocking,
+/// returning the previous settings so they can be restored.
+fn enter_raw_mode() -> Option<libc::termios> {
+    unsafe {
+        let mut termios: libc::termios = ::std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return None;
+        }
+        let saved = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 0;
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        Some(saved)
+    }
+}
+
+fn key_index(byte: u8) -> Option<u8> {
+    match byte.to_ascii_lowercase() {
+        b'4' => Some(0x0u8),
+        b'5' => Some(0x1u8),
+        b'6' => Some(0x2u8),
+        b'7' => Some(0x3u8),
+
+        b'r' => Some(0x4u8),
+        b't' => Some(0x5u8),
+        b'y' => Some(0x6u8),
+        b'u' => Some(0x7u8),
+
+        b'f' => Some(0x8u8),
+        b'g' => Some(0x9u8),
+        b'h' => Some(0xau8),
+        b'j' => Some(0xbu8),
+
+        b'v' => Some(0xcu8),
+        b'b' => Some(0xdu8),
+        b'n' => Some(0xeu8),
+        b'm' => Some(0xfu8),
+        _ => None,
+    }
+}
+
+impl InputSource for TuiInput {
+    fn poll_events(&mut self, cpu: &mut Cpu) -> Vec<Command> {
+        let mut commands = Vec::new();
+        for (key, held) in self.held_keys.iter_mut().enumerate() {
+            if *held > 0 {
+                *held -= 1;
+                if *held == 0 {
+                    cpu.keyup(key as u8);
+                }
+            }
+        }
+
+        let mut input = [0u8; 64];
+        let count = io::stdin().read(&mut input).unwrap_or(0);
+        let input = &input[..count];
+        // A lone escape is the escape key, anything longer is an escape
+        // sequence such as an arrow key
+        if input.first() == Some(&0x1b) {
+            match input {
+                [0x1b] => commands.push(Command::Quit),
+                b"\x1b[A" | b"\x1bOA" => commands.push(Command::Up),
+                b"\x1b[B" | b"\x1bOB" => commands.push(Command::Down),
+                _ => (),
+            }
+            return commands;
+        }
+        for &byte in input {
+            match byte {
+                // Ctrl-C
+                0x03        => commands.push(Command::Quit),
+                b'q' | b'Q' => commands.push(Command::IncreaseSpeed),
+                b'a' | b'A' => commands.push(Command::DecreaseSpeed),
+                b'p' | b'P' => commands.push(Command::CyclePalette),
+                b'o' | b'O' => commands.push(Command::CycleFlicker),
+                // Backspace
+                0x7f | 0x08 => commands.push(Command::Reset),
+                b'\r' | b'\n' => commands.push(Command::Select),
+                byte => if let Some(key) = key_index(byte) {
+                    cpu.keydown(key);
+                    self.held_keys[key as usize] = KEY_HOLD_FRAMES;
+                },
+            }
+        }
+        commands
+    }
+}
+
+fn pixel_colour(palette: &Palette, intensity: u8) -> Rgb {
+    palette.pixel(0).blend(palette.pixel(1), intensity)
+}
+
+fn fg(Rgb(r, g, b): Rgb) -> String {
+    format!("\x1b[38;2;{};{};{}m", r, g, b)
+}
+
+fn bg(Rgb(r, g, b): Rgb) -> String {
+    format!("\x1b[48;2;{};{};{}m", r, g, b)
+}
+
+/// Draws the screen two rows of pixels per line: the foreground colour of an
+/// upper half block is the top pixel, and the background the bottom pixel.
+pub fn render_half_blocks(intensities: &[u8], palette: &Palette) -> Vec<String> {
+    let mut lines = Vec::new();
+    for y in (0..mem::SCREEN_HEIGHT).step_by(2) {
+        let mut line = String::new();
+        let mut last = None;
+        for x in 0..mem::SCREEN_WIDTH {
+            let top = pixel_colour(palette, intensities[y*mem::SCREEN_WIDTH + x]);
+            let bottom = pixel_colour(palette, intensities[(y+1)*mem::SCREEN_WIDTH + x]);
+            if last != Some((top, bottom)) {
+                line.push_str(&fg(top));
+                line.push_str(&bg(bottom));
+                last = Some((top, bottom));
+            }
+            line.push('\u{2580}');
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Draws the screen as braille characters, each covering a 2x4 block of
+/// pixels. Dots are either on or off, so faded pixels are cut off at half
+/// brightness.
+pub fn render_braille(intensities: &[u8], palette: &Palette) -> Vec<String> {
+    // Bit for each dot in a braille cell, indexed by [y][x]
+    const DOT_BITS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    let mut lines = Vec::new();
+    for y in (0..mem::SCREEN_HEIGHT).step_by(4) {
+        let mut line = fg(palette.pixel(1)) + bg(palette.pixel(0)).as_str();
+        for x in (0..mem::SCREEN_WIDTH).step_by(2) {
+            let mut bits = 0u32;
+            for (dy, row) in DOT_BITS.iter().enumerate() {
+                for (dx, bit) in row.iter().enumerate() {
+                    if intensities[(y+dy)*mem::SCREEN_WIDTH + x + dx] >= 128 {
+                        bits |= *bit;
+                    }
+                }
+            }
+            line.push(::std::char::from_u32(0x2800 + bits).unwrap());
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn register_panel(cpu: &Cpu, flicker: FlickerMode) -> Vec<String> {
+    let mut panel = vec![
+        format!("PC  0x{:03x}", cpu.get_pc()),
+        format!("I   0x{:03x}", cpu.get_i()),
+        format!("SP  {:<5} DT {:3}", cpu.get_sp(), cpu.get_dt()),
+        format!("IPS {:<5} ST {:3}", cpu.get_ips(), cpu.get_st()),
+    ];
+    for reg in 0..8u8 {
+        panel.push(format!("V{:X}  0x{:02x}  V{:X} 0x{:02x}",
+                           reg, cpu.get_reg(reg), reg + 8, cpu.get_reg(reg + 8)));
+    }
+    panel.push(format!("Flicker: {}", flicker));
+    panel.push("Esc quit, Q/A speed, P/O palette/flicker".to_string());
+    panel
+}
+
+//TESTS
+
+#[cfg(test)]
+fn test_frame(lit: &[(usize, usize)]) -> Vec<u8> {
+    let mut frame = vec![0u8; mem::SCREEN_WIDTH*mem::SCREEN_HEIGHT];
+    for &(x, y) in lit {
+        frame[y*mem::SCREEN_WIDTH + x] = 255;
+    }
+    frame
+}
+
+#[test]
+fn test_render_half_blocks(){
+    let palette: Palette = Default::default();
+    let lines = render_half_blocks(&test_frame(&[(0, 0), (1, 1)]), &palette);
+    if lines.len() != mem::SCREEN_HEIGHT/2 {
+        panic!("Expected {} lines, got {}", mem::SCREEN_HEIGHT/2, lines.len());
+    }
+    let expected_start = format!("{}{}\u{2580}{}{}\u{2580}{}{}\u{2580}",
+                                 fg(Rgb(255, 255, 255)), bg(Rgb(0, 0, 0)),
+                                 fg(Rgb(0, 0, 0)), bg(Rgb(255, 255, 255)),
+                                 fg(Rgb(0, 0, 0)), bg(Rgb(0, 0, 0)));
+    if !lines[0].starts_with(&expected_start) {
+        panic!("First line rendered incorrectly: {:?}", lines[0]);
+    }
+    if lines[0].chars().filter(|&c| c == '\u{2580}').count() != mem::SCREEN_WIDTH {
+        panic!("Each line should have one half block per column");
+    }
+}
+
+#[test]
+fn test_render_braille(){
+    let palette: Palette = Default::default();
+    let lines = render_braille(&test_frame(&[(0, 0), (1, 3), (3, 1)]), &palette);
+    if lines.len() != mem::SCREEN_HEIGHT/4 {
+        panic!("Expected {} lines, got {}", mem::SCREEN_HEIGHT/4, lines.len());
+    }
+    let cells: Vec<char> = lines[0].chars().filter(|c| ('\u{2800}'..='\u{28ff}').contains(c)).collect();
+    if cells.len() != mem::SCREEN_WIDTH/2 {
+        panic!("Expected {} braille cells per line, got {}", mem::SCREEN_WIDTH/2, cells.len());
+    }
+    if cells[0] != '\u{2881}' || cells[1] != '\u{2810}' || cells[2] != '\u{2800}' {
+        panic!("Braille dots placed incorrectly: {:?}", &cells[..3]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Exports for running chippy in a web page. Build with
+//! `cargo build --lib --release --target wasm32-unknown-unknown --no-default-features --features wasm`
+//! and load the module through `web/chippy.js`.
+//!
+//! There is a single emulator per module instance. ROMs are passed in by
+//! asking for a buffer with `rom_buffer`, filling it from JS, then calling
+//! `load_rom`.
+
+use std::sync::Mutex;
+use cpu::Cpu;
+use flicker::{FlickerFilter, FlickerMode};
+use frontend::FRAMES_PER_SECOND;
+use mem;
+use palette::Palette;
+use postfx;
+use postfx::Effects;
+
+struct Emulator {
+    cpu: Cpu,
+    rom: Vec<u8>,
+    palette: Palette,
+    flicker: FlickerFilter,
+    rgba: Vec<u8>,
+}
+
+static EMULATOR: Mutex<Option<Emulator>> = Mutex::new(None);
+
+fn with_emulator<T, F: FnOnce(&mut Emulator) -> T>(f: F) -> T {
+    let mut emulator = EMULATOR.lock().unwrap();
+    f(emulator.get_or_insert_with(|| Emulator {
+        cpu: Cpu::new(),
+        rom: Vec::new(),
+        palette: Default::default(),
+        flicker: FlickerFilter::new(FlickerMode::Off),
+        rgba: vec![0u8; mem::SCREEN_WIDTH*mem::SCREEN_HEIGHT*4],
+    }))
+}
+
+/// Returns a buffer of `len` bytes for JS to copy a ROM into.
+#[no_mangle]
+pub extern "C" fn rom_buffer(len: usize) -> *mut u8 {
+    with_emulator(|emulator| {
+        emulator.rom = vec![0u8; len];
+        emulator.rom.as_mut_ptr()
+    })
+}
+
+/// Resets the machine and loads the ROM from `rom_buffer`. Returns false if
+/// the ROM is too large.
+#[no_mangle]
+pub extern "C" fn load_rom() -> bool {
+    with_emulator(|emulator| {
+        emulator.cpu.reset();
+        emulator.cpu.memory.load_rom(&emulator.rom).is_ok()
+    })
+}
+
+/// Runs one 60th of a second worth of instructions and ticks the timers.
+#[no_mangle]
+pub extern "C" fn run_frame() {
+    with_emulator(|emulator| {
+        for _ in 0..emulator.cpu.get_ips()/FRAMES_PER_SECOND as u64 {
+            if emulator.cpu.exec_instruction().is_err() {
+                break;
+            }
+        }
+        emulator.cpu.decr_dt();
+        emulator.cpu.decr_st();
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn set_key(key: u8, down: bool) {
+    with_emulator(|emulator| {
+        if key < 16 {
+            if down {
+                emulator.cpu.keydown(key);
+            } else {
+                emulator.cpu.keyup(key);
+            }
+        }
+    })
+}
+
+/// Renders the screen and returns a pointer to it as RGBA, `screen_width()`
+/// by `screen_height()` pixels. It stays valid until the next call.
+#[no_mangle]
+pub extern "C" fn framebuffer() -> *const u8 {
+    with_emulator(|emulator| {
+        let image = postfx::render_frame(&emulator.cpu, &emulator.palette, &mut emulator.flicker, &Effects::default());
+        for (out, pixel) in emulator.rgba.chunks_mut(4).zip(image.data.chunks(3)) {
+            out.copy_from_slice(&[pixel[0], pixel[1], pixel[2], 255]);
+        }
+        emulator.rgba.as_ptr()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn screen_width() -> usize {
+    mem::SCREEN_WIDTH
+}
+
+#[no_mangle]
+pub extern "C" fn screen_height() -> usize {
+    mem::SCREEN_HEIGHT
+}
+
+/// Whether the buzzer should be sounding.
+#[no_mangle]
+pub extern "C" fn sound_active() -> bool {
+    with_emulator(|emulator| emulator.cpu.get_st_active())
+}
+
+#[no_mangle]
+pub extern "C" fn set_ips(ips: u64) {
+    with_emulator(|emulator| emulator.cpu.set_ips(ips))
+}
+
+#[no_mangle]
+pub extern "C" fn set_seed(seed: u32) {
+    with_emulator(|emulator| emulator.cpu.set_seed(seed))
+}
+
+/// Switches to the next built in palette.
+#[no_mangle]
+pub extern "C" fn next_palette() {
+    with_emulator(|emulator| emulator.palette = emulator.palette.next())
+}
+
+//TESTS
+
+#[test]
+fn test_wasm_exports(){
+    use std::slice;
+    // Draws the "0" sprite at (0, 0), then waits for key 5 before starting
+    // the sound timer
+    let rom = [0xA0, 0x00, 0xD0, 0x15, 0xF0, 0x0A, 0xF0, 0x18, 0x12, 0x08];
+    let buffer = rom_buffer(rom.len());
+    unsafe { slice::from_raw_parts_mut(buffer, rom.len()) }.copy_from_slice(&rom);
+    if !load_rom() {
+        panic!("Rom should load");
+    }
+    run_frame();
+    let frame = unsafe { slice::from_raw_parts(framebuffer(), screen_width()*screen_height()*4) };
+    let background = &frame[frame.len()-4..];
+    if frame[..4] == *background || frame[16..20] != *background || frame[3] != 255 {
+        panic!("Sprite was not drawn: {:?}", &frame[..20]);
+    }
+    if sound_active() {
+        panic!("Sound should wait for a key press");
+    }
+    set_key(5, true);
+    run_frame();
+    if !sound_active() {
+        panic!("Key press should start the sound timer");
+    }
+
+    let huge = vec![0u8; mem::MEM_SIZE];
+    let buffer = rom_buffer(huge.len());
+    unsafe { slice::from_raw_parts_mut(buffer, huge.len()) }.copy_from_slice(&huge);
+    if load_rom() {
+        panic!("Oversized rom should be rejected");
+    }
+}
@@ -0,0 +1,106 @@
+// Runs the wasm build of chippy on a canvas.
+//
+//     const chippy = await Chippy.load("chippy.wasm", canvas);
+//     chippy.loadRom(new Uint8Array(await (await fetch("pong.ch8")).arrayBuffer()));
+//     chippy.start();
+
+// Same layout as the desktop version: 4567 / rtyu / fghj / vbnm
+const KEYS = "4567rtyufghjvbnm";
+// run_frame runs a 60th of a second, whatever the display's refresh rate
+const FRAME_MS = 1000 / 60;
+// Frames caught up on at most, e.g. after the tab was in the background
+const MAX_CATCH_UP = 4;
+
+class Chippy {
+    static async load(url, canvas) {
+        const { instance } = await WebAssembly.instantiateStreaming(fetch(url));
+        return new Chippy(instance.exports, canvas);
+    }
+
+    constructor(exports, canvas) {
+        this.exports = exports;
+        this.canvas = canvas;
+        this.context = canvas.getContext("2d");
+        this.width = exports.screen_width();
+        this.height = exports.screen_height();
+        this.running = false;
+        this.audio = null;
+        canvas.tabIndex = 0;
+        canvas.addEventListener("keydown", e => this.onKey(e, true));
+        canvas.addEventListener("keyup", e => this.onKey(e, false));
+    }
+
+    loadRom(bytes) {
+        const buffer = this.exports.rom_buffer(bytes.length);
+        new Uint8Array(this.exports.memory.buffer, buffer, bytes.length).set(bytes);
+        if (!this.exports.load_rom()) {
+            throw new Error("ROM is too large");
+        }
+    }
+
+    setKey(key, down) {
+        this.exports.set_key(key, down);
+    }
+
+    start() {
+        this.running = true;
+        let last = null;
+        // Starting half a frame in keeps frame boundaries away from the
+        // callbacks on a 60Hz display, so timing jitter doesn't matter
+        let pending = FRAME_MS / 2;
+        const frame = time => {
+            if (!this.running) {
+                return;
+            }
+            if (last !== null) {
+                pending = Math.min(pending + time - last, MAX_CATCH_UP * FRAME_MS);
+            }
+            last = time;
+            if (pending >= FRAME_MS) {
+                for (; pending >= FRAME_MS; pending -= FRAME_MS) {
+                    this.exports.run_frame();
+                }
+                this.draw();
+                this.beep(this.exports.sound_active());
+            }
+            requestAnimationFrame(frame);
+        };
+        requestAnimationFrame(frame);
+    }
+
+    stop() {
+        this.running = false;
+        this.beep(false);
+    }
+
+    draw() {
+        const pointer = this.exports.framebuffer();
+        const pixels = new Uint8ClampedArray(this.exports.memory.buffer, pointer, this.width*this.height*4);
+        this.context.putImageData(new ImageData(pixels, this.width, this.height), 0, 0);
+    }
+
+    beep(on) {
+        if (on && !this.audio) {
+            const context = new AudioContext();
+            const oscillator = context.createOscillator();
+            const gain = context.createGain();
+            oscillator.type = "square";
+            oscillator.frequency.value = 440;
+            gain.gain.value = 0.25;
+            oscillator.connect(gain).connect(context.destination);
+            oscillator.start();
+            this.audio = context;
+        } else if (!on && this.audio) {
+            this.audio.close();
+            this.audio = null;
+        }
+    }
+
+    onKey(event, down) {
+        const key = KEYS.indexOf(event.key.toLowerCase());
+        if (key >= 0) {
+            this.setKey(key, down);
+            event.preventDefault();
+        }
+    }
+}